@@ -0,0 +1,267 @@
+use std::mem;
+
+use gameboy::Gameboy;
+use gameboy::serial::{Connection, Peer};
+use link::{cable, LocalPeer};
+
+
+const PLAYERS: usize = 4;
+const MAX_PACKET_SIZE: usize = 4;
+
+// Sent at the start of each ping packet, before three status bytes.
+const PING: u8 = 0xFE;
+// Players answer a ping with two of these, then their rate and size.
+const ACK: u8 = 0x88;
+// Player 1 answers a whole ping packet with these to start playing.
+const START: u8 = 0xAA;
+// Sent four times before the first transmission.
+const STARTING: u8 = 0xCC;
+
+// Bits are shifted out at 8192Hz like the internal clock, so a byte
+// takes 4096 cycles. Player 1's rate stretches the gap between bytes
+// once transmission starts.
+const BYTE_CYCLES: u32 = 4096;
+const RATE_STEP_CYCLES: u32 = 1024;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Ping,
+    Starting,
+    Transmitting,
+}
+
+// A DMG-07 Four Player Adapter, plugged into player 1's link port. The
+// adapter clocks every transfer itself, so games wait on an external
+// clock and time passes with player 1's machine.
+//
+// Until player 1 starts things, it pings each port with 0xFE and three
+// status bytes giving the port's player number and which players
+// answered the last ping. After that, every round it sends all players
+// the packets each sent it in the round before, four packets in player
+// order, gathering new packets from the start of what each sends back.
+pub struct Adapter {
+    // Player 1's end of the cable to the first port
+    first: LocalPeer,
+    ports: Vec<Box<Peer>>,
+    phase: Phase,
+    timer: u32,
+    // Byte of the current packet or round
+    position: usize,
+    // Players answering the last ping and the one going out, as bits 4-7
+    // of the status byte
+    connected: u8,
+    acks: u8,
+    // Player 1's answers to the current ping
+    replies: [u8; 4],
+    rate: u8,
+    size: usize,
+    outgoing: Vec<u8>,
+    incoming: Vec<u8>,
+}
+
+impl Adapter {
+    pub fn new() -> Adapter {
+        let (port, first) = cable();
+        Adapter {
+            first: first,
+            ports: vec![Box::new(port)],
+            phase: Phase::Ping,
+            timer: 0,
+            position: 0,
+            connected: 0,
+            acks: 0,
+            replies: [0; 4],
+            rate: 0,
+            size: 1,
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+        }
+    }
+    // Plug another player into the next free port.
+    pub fn plug(&mut self, port: Box<Peer>) {
+        assert!(self.ports.len() < PLAYERS, "The adapter only has four ports");
+        self.ports.push(port);
+    }
+    // Plug in a machine running in the same process.
+    pub fn plug_local(&mut self, gameboy: &mut Gameboy) {
+        let (port, end) = cable();
+        gameboy.mmu.serial.connect(Connection::Peer(Box::new(end)));
+        self.plug(Box::new(port));
+    }
+    fn step(&mut self, cycles: u32) {
+        for port in &mut self.ports {
+            port.poll(cycles, None);
+        }
+        self.timer += cycles;
+        while self.timer >= self.byte_cycles() {
+            self.timer -= self.byte_cycles();
+            match self.phase {
+                Phase::Ping => self.ping(),
+                Phase::Starting => self.start(),
+                Phase::Transmitting => self.transmit(),
+            }
+        }
+    }
+    fn byte_cycles(&self) -> u32 {
+        match self.phase {
+            Phase::Transmitting => BYTE_CYCLES + (self.rate & 0x0F) as u32 * RATE_STEP_CYCLES,
+            _ => BYTE_CYCLES,
+        }
+    }
+    fn ping(&mut self) {
+        for (i, port) in self.ports.iter_mut().enumerate() {
+            let byte = match self.position {
+                0 => PING,
+                _ => self.connected | (i as u8 + 1),
+            };
+            let reply = port.transfer(byte);
+            if self.position == 0 && (reply == ACK || reply == START) {
+                self.acks |= 0x10 << i;
+            }
+            if i == 0 {
+                self.replies[self.position] = reply;
+            }
+        }
+        self.position += 1;
+        if self.position < 4 {
+            return;
+        }
+        self.position = 0;
+        self.connected = mem::replace(&mut self.acks, 0);
+        match self.replies {
+            [START, START, START, START] => self.phase = Phase::Starting,
+            // Player 1 may have started answering with START part way
+            // through, which is no size.
+            [ACK, ACK, rate, size] if size as usize <= MAX_PACKET_SIZE => {
+                self.rate = rate;
+                self.size = (size as usize).max(1);
+            }
+            _ => {}
+        }
+    }
+    fn start(&mut self) {
+        for port in &mut self.ports {
+            port.transfer(STARTING);
+        }
+        self.position += 1;
+        if self.position == 4 {
+            self.position = 0;
+            self.outgoing = vec![0; PLAYERS * self.size];
+            self.incoming = vec![0; PLAYERS * self.size];
+            self.phase = Phase::Transmitting;
+        }
+    }
+    fn transmit(&mut self) {
+        let byte = self.outgoing[self.position];
+        for (i, port) in self.ports.iter_mut().enumerate() {
+            let reply = port.transfer(byte);
+            if self.position < self.size {
+                self.incoming[i * self.size + self.position] = reply;
+            }
+        }
+        self.position += 1;
+        if self.position == self.outgoing.len() {
+            self.position = 0;
+            let next = vec![0; PLAYERS * self.size];
+            self.outgoing = mem::replace(&mut self.incoming, next);
+        }
+    }
+}
+
+impl Peer for Adapter {
+    // Games only use the external clock with the adapter.
+    fn transfer(&mut self, _: u8) -> u8 {
+        0xFF
+    }
+    fn poll(&mut self, cycles: u32, ready: Option<u8>) -> Option<u8> {
+        self.first.poll(0, ready);
+        self.step(cycles);
+        self.first.poll(0, ready)
+    }
+}
+
+
+#[test]
+fn test_four_players() {
+    use gameboy::model::Model;
+    use gameboy::rom::Rom;
+    use link::LocalLink;
+    use script::Script;
+
+    // Answer pings with a rate of 0 and packets of 1 byte, with player 1
+    // starting once all four are connected. Then send the A, B, Select
+    // and Start buttons as the packet and store each round at C000.
+    fn machine() -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        let code = [
+            0x06, 0x88, 0x0E, 0x00, 0x16, 0x00, // LD B,ACK; LD C,0; LD D,0
+            0x78, 0xE0, 0x01, // LD A,B; LDH (01),A
+            // next:
+            0x3E, 0x80, 0xE0, 0x02, // LD A,0x80; LDH (02),A
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, // LDH A,(02); BIT 7,A; JR NZ
+            0xF0, 0x01, 0x5F, // LDH A,(01); LD E,A
+            0x7A, 0xFE, 0x05, 0x28, 0x39, // LD A,D; CP 5; JR Z,transmit
+            0x7B, 0xFE, 0xCC, 0x28, 0x25, // LD A,E; CP STARTING; JR Z,starting
+            0xFE, 0xFE, 0x20, 0x02, // CP PING; JR NZ,status
+            0x0E, 0x00, // LD C,0
+            // status:
+            0x79, 0xFE, 0x01, 0x20, 0x07, // LD A,C; CP 1; JR NZ,reply
+            0x7B, 0xFE, 0xF1, 0x20, 0x02, // LD A,E; CP 0xF1; JR NZ,reply
+            0x06, 0xAA, // LD B,START
+            // reply:
+            0x0C, 0x78, 0xFE, 0xAA, 0x28, 0x09, // INC C; LD A,B; CP START; JR Z,send
+            0x79, 0xE6, 0x03, 0xC6, 0xBA, // LD A,C; AND 3; ADD A,answers
+            0x6F, 0x26, 0x01, 0x7E, // LD L,A; LD H,01; LD A,(HL)
+            // send:
+            0xE0, 0x01, 0x18, 0xC4, // LDH (01),A; JR next
+            // starting:
+            0x14, 0x7A, 0xFE, 0x04, 0x20, 0x06, // INC D; LD A,D; CP 4; JR NZ,zero
+            0x16, 0x05, 0x0E, 0x00, 0x18, 0x0E, // LD D,5; LD C,0; JR buttons
+            // zero:
+            0xAF, 0x18, 0xED, // XOR A; JR send
+            // transmit:
+            0x26, 0xC0, 0x69, 0x73, // LD H,C0; LD L,C; LD (HL),E
+            0x79, 0x3C, 0xE6, 0x03, 0x4F, 0x20, 0xF2, // LD A,C; INC A; AND 3; LD C,A; JR NZ,zero
+            // buttons:
+            0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, // LD A,0x10; LDH (00),A; LDH A,(00)
+            0x2F, 0xE6, 0x0F, 0x18, 0xD7, // CPL; AND 0x0F; JR send
+            // answers, at 01BA:
+            0x88, 0x88, 0x00, 0x01,
+        ];
+        rom[0x150..0x150 + code.len()].copy_from_slice(&code);
+        let mut gameboy = Gameboy::new(Rom::from_bytes(rom, "adapter").unwrap(), Model::Dmg);
+        gameboy.skip_boot_rom();
+        gameboy
+    }
+
+    let mut scripts: Vec<Script> = ["A", "B", "START", "SELECT"]
+        .iter()
+        .map(|button| Script::parse(&format!("hold {} 1\nwait 60", button)).unwrap())
+        .collect();
+    let mut first = machine();
+    let mut adapter = Adapter::new();
+    let others = (1..PLAYERS)
+        .map(|_| {
+            let mut other = machine();
+            adapter.plug_local(&mut other);
+            other
+        })
+        .collect();
+    first.mmu.serial.connect(Connection::Peer(Box::new(adapter)));
+    let mut link = LocalLink::new(others);
+    while let Some(buttons) = scripts[0].frame(&first) {
+        first.set_buttons(buttons);
+        for (script, other) in scripts[1..].iter_mut().zip(link.others.iter_mut()) {
+            let buttons = script.frame(other).unwrap();
+            other.set_buttons(buttons);
+        }
+        link.run_frame(&mut first);
+    }
+
+    for gameboy in Some(&first).into_iter().chain(link.others.iter()) {
+        let round: Vec<u8> = (0xC000..0xC004).map(|address| gameboy.mmu.read(address)).collect();
+        assert_eq!(vec![0x01, 0x02, 0x08, 0x04], round);
+    }
+}
@@ -0,0 +1,97 @@
+pub mod resampler;
+pub mod vgm;
+pub mod wav;
+
+use sdl2::AudioSubsystem;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use std::thread::sleep;
+use std::time::Duration;
+
+use gameboy::apu::SAMPLE_RATE;
+use self::resampler::Resampler;
+
+
+// Audio sync keeps about this much sound queued, in seconds.
+const LATENCY: f64 = 0.05;
+// Past this much queued sound, new samples are dropped rather than
+// letting the delay grow.
+const MAX_LATENCY: f64 = 0.2;
+
+
+// Plays the APU's samples through an SDL audio queue.
+pub struct Audio {
+    queue: AudioQueue<f32>,
+    resampler: Resampler,
+    rate: u32,
+    volume: u32,
+    muted: bool,
+    // Pace emulation by the queue instead of the wall clock
+    sync: bool,
+    buffer: Vec<f32>,
+}
+
+impl Audio {
+    pub fn new(subsystem: &AudioSubsystem, rate: u32) -> Result<Audio, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(rate as i32),
+            channels: Some(2),
+            samples: Some(1024),
+        };
+        let queue: AudioQueue<f32> = subsystem.open_queue(None, &desired)?;
+        let rate = queue.spec().freq as u32;
+        queue.resume();
+        Ok(Audio {
+            queue: queue,
+            resampler: Resampler::new(2, SAMPLE_RATE, rate),
+            rate: rate,
+            volume: 100,
+            muted: false,
+            sync: false,
+            buffer: Vec::new(),
+        })
+    }
+    pub fn set_volume(&mut self, volume: u32) {
+        self.volume = volume.min(100);
+    }
+    pub fn volume(&self) -> u32 {
+        self.volume
+    }
+    // Returns whether sound is now muted.
+    pub fn toggle_mute(&mut self) -> bool {
+        self.muted = !self.muted;
+        self.muted
+    }
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+    pub fn sync(&self) -> bool {
+        self.sync
+    }
+    // Queue samples at the APU's rate. While muted silence is queued
+    // instead, so audio sync still has something to pace by.
+    pub fn push(&mut self, samples: &[f32]) {
+        self.buffer.clear();
+        self.resampler.process(samples, &mut self.buffer);
+        if self.queued_seconds() > MAX_LATENCY {
+            return;
+        }
+        let gain = if self.muted { 0.0 } else { self.volume as f32 / 100.0 };
+        for sample in &mut self.buffer {
+            *sample *= gain;
+        }
+        self.queue.queue(&self.buffer);
+    }
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+    // Block until the queue has drained down to the target latency.
+    pub fn wait(&self) {
+        while self.queued_seconds() > LATENCY {
+            sleep(Duration::from_millis(1));
+        }
+    }
+    fn queued_seconds(&self) -> f64 {
+        let frames = self.queue.size() as usize / (2 * 4);
+        frames as f64 / self.rate as f64
+    }
+}
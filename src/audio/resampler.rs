@@ -0,0 +1,86 @@
+use std::f64::consts::PI;
+
+
+// Filter taps on either side of each output sample.
+const TAPS: usize = 16;
+
+
+// Converts interleaved samples between rates with a windowed sinc
+// filter, cutting off just below the output's Nyquist frequency so the
+// square waves don't alias.
+pub struct Resampler {
+    channels: usize,
+    step: f64,
+    cutoff: f64,
+    // Input samples not yet consumed, including TAPS frames of history
+    input: Vec<f32>,
+    // Position of the next output frame within `input`
+    position: f64,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, input_rate: u32, output_rate: u32) -> Resampler {
+        let step = input_rate as f64 / output_rate as f64;
+        Resampler {
+            channels: channels,
+            step: step,
+            cutoff: 0.45 / step.max(1.0),
+            input: vec![0.0; TAPS * channels],
+            position: TAPS as f64,
+        }
+    }
+    pub fn process(&mut self, samples: &[f32], output: &mut Vec<f32>) {
+        self.input.extend_from_slice(samples);
+        let channels = self.channels;
+        let mut weights = [0.0; TAPS * 2];
+        while self.position as usize + TAPS < self.input.len() / channels {
+            let base = self.position as usize + 1 - TAPS;
+            let fraction = self.position - self.position.floor();
+            let mut total = 0.0;
+            for (i, weight) in weights.iter_mut().enumerate() {
+                let x = i as f64 - (TAPS - 1) as f64 - fraction;
+                *weight = self.kernel(x);
+                total += *weight;
+            }
+            for channel in 0..channels {
+                let mut sum = 0.0;
+                for (i, weight) in weights.iter().enumerate() {
+                    sum += weight / total * self.input[(base + i) * channels + channel] as f64;
+                }
+                output.push(sum as f32);
+            }
+            self.position += self.step;
+        }
+        // Drop what no future output frame can reach.
+        let consumed = (self.position as usize + 1 - TAPS).min(self.input.len() / channels);
+        self.input.drain(..consumed * channels);
+        self.position -= consumed as f64;
+    }
+    // Low-pass sinc at `cutoff` cycles per input sample under a
+    // Blackman window spanning the taps.
+    fn kernel(&self, x: f64) -> f64 {
+        let sinc = if x == 0.0 {
+            2.0 * self.cutoff
+        } else {
+            (2.0 * PI * self.cutoff * x).sin() / (PI * x)
+        };
+        let t = (x / TAPS as f64 + 1.0) / 2.0;
+        let window = 0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos();
+        sinc * window.max(0.0)
+    }
+}
+
+
+#[test]
+fn test_resampler_rate_and_gain() {
+    let mut resampler = Resampler::new(2, 131072, 48000);
+    let mut output = Vec::new();
+    let input = vec![0.5; 131072 * 2];
+    resampler.process(&input[..65536], &mut output);
+    resampler.process(&input[65536..], &mut output);
+    let frames = output.len() / 2;
+    assert!(frames > 47990 && frames <= 48000);
+    // A constant level comes through unchanged once the history of
+    // silence has passed.
+    assert!(output[100..].iter().all(|x| (x - 0.5).abs() < 1e-4));
+}
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use gameboy::CLOCK_SPEED;
+use gameboy::apu::{Apu, RegisterWrite};
+
+
+// VGM 1.61 is the first version with a Game Boy DMG clock field. Its
+// header is padded out to 0x100 bytes with the data straight after.
+const VERSION: u32 = 0x161;
+const HEADER_SIZE: u32 = 0x100;
+// VGM timestamps are in samples at 44100 Hz.
+const VGM_RATE: u64 = 44_100;
+
+const WRITE_DMG: u8 = 0xB3;
+const WAIT: u8 = 0x61;
+const WAIT_NTSC_FRAME: u8 = 0x62;
+const WAIT_PAL_FRAME: u8 = 0x63;
+const WAIT_SHORT: u8 = 0x70;
+const END: u8 = 0x66;
+
+
+// Logs the APU's register writes to a VGM file for playback or ripping
+// elsewhere. The header is filled in by `finish`.
+pub struct VgmWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    // Bytes of commands written after the header
+    length: u32,
+    cycles: u64,
+    samples: u64,
+    // Data offset and sample count where the loop starts
+    loop_start: Option<(u32, u64)>,
+}
+
+impl VgmWriter {
+    pub fn start(apu: &mut Apu, path: &Path) -> io::Result<VgmWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&[0; HEADER_SIZE as usize])?;
+        apu.start_log();
+        Ok(VgmWriter {
+            path: path.to_path_buf(),
+            file: file,
+            length: 0,
+            cycles: 0,
+            samples: 0,
+            loop_start: None,
+        })
+    }
+    // Write out the writes taken from the APU, along with the waits
+    // between them.
+    pub fn write(&mut self, writes: &[RegisterWrite], cycles: u32) -> io::Result<()> {
+        let start = self.cycles;
+        for write in writes {
+            self.wait_until(start + write.cycle as u64)?;
+            self.command(&[WRITE_DMG, write.register, write.value])?;
+        }
+        self.wait_until(start + cycles as u64)
+    }
+    // Loop playback back to this point once the log ends.
+    pub fn mark_loop(&mut self) {
+        self.loop_start = Some((self.length, self.samples));
+    }
+    pub fn finish(mut self, apu: &mut Apu) {
+        apu.stop_log();
+        match self.write_header() {
+            Ok(()) => println!("Logged sound registers to {}", self.path.display()),
+            Err(err) => eprintln!("Could not finish {}: {}", self.path.display(), err),
+        }
+    }
+    fn wait_until(&mut self, cycles: u64) -> io::Result<()> {
+        self.cycles = cycles;
+        let target = cycles * VGM_RATE / CLOCK_SPEED as u64;
+        while self.samples < target {
+            let wait = (target - self.samples).min(0xFFFF);
+            match wait {
+                735 => self.command(&[WAIT_NTSC_FRAME])?,
+                882 => self.command(&[WAIT_PAL_FRAME])?,
+                1...16 => self.command(&[WAIT_SHORT + wait as u8 - 1])?,
+                _ => self.command(&[WAIT, wait as u8, (wait >> 8) as u8])?,
+            }
+            self.samples += wait;
+        }
+        Ok(())
+    }
+    fn command(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.length += bytes.len() as u32;
+        self.file.write_all(bytes)
+    }
+    fn write_header(&mut self) -> io::Result<()> {
+        self.file.write_all(&[END])?;
+        let mut header = [0; HEADER_SIZE as usize];
+        header[0x00..0x04].copy_from_slice(b"Vgm ");
+        put_u32(&mut header, 0x04, HEADER_SIZE + self.length + 1 - 0x04);
+        put_u32(&mut header, 0x08, VERSION);
+        put_u32(&mut header, 0x18, self.samples as u32);
+        if let Some((offset, samples)) = self.loop_start {
+            put_u32(&mut header, 0x1C, HEADER_SIZE + offset - 0x1C);
+            put_u32(&mut header, 0x20, (self.samples - samples) as u32);
+        }
+        put_u32(&mut header, 0x34, HEADER_SIZE - 0x34);
+        put_u32(&mut header, 0x80, CLOCK_SPEED);
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.flush()
+    }
+}
+
+fn put_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+
+#[test]
+fn test_vgm_log() {
+    use std::env;
+    use std::fs;
+    use gameboy::model::Model;
+
+    let path = env::temp_dir().join("bitromney_test_vgm_log.vgm");
+    let mut apu = Apu::new(Model::Dmg);
+    let mut vgm = VgmWriter::start(&mut apu, &path).unwrap();
+    let (writes, _) = apu.take_writes();
+    vgm.write(&writes, 0).unwrap();
+    apu.step(4);
+    apu.write_u8(0xFF26, 0x80);
+    apu.step(CLOCK_SPEED - 4);
+    let (writes, cycles) = apu.take_writes();
+    assert_eq!(RegisterWrite { cycle: 4, register: 0x16, value: 0x80 }, writes[0]);
+    vgm.write(&writes, cycles).unwrap();
+    vgm.mark_loop();
+    apu.write_u8(0xFF12, 0xF0);
+    let (writes, cycles) = apu.take_writes();
+    vgm.write(&writes, cycles).unwrap();
+    vgm.finish(&mut apu);
+
+    let data = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let commands = &data[HEADER_SIZE as usize..];
+    // NR52, 21 registers less FF15, then wave RAM
+    let initial = 3 * (1 + 21 + 16);
+    assert_eq!(&[WRITE_DMG, 0x16, 0x80, WAIT, 0x44, 0xAC], &commands[initial..initial + 6]);
+    assert_eq!(&[WRITE_DMG, 0x02, 0xF0, END], &commands[initial + 6..]);
+    assert_eq!(&[0x44, 0xAC, 0, 0], &data[0x18..0x1C]);
+    let loop_offset = (HEADER_SIZE as usize + initial + 6 - 0x1C) as u32;
+    assert_eq!(&loop_offset.to_le_bytes(), &data[0x1C..0x20]);
+    assert_eq!(&[0, 0, 0, 0], &data[0x20..0x24]);
+}
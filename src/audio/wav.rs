@@ -0,0 +1,176 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use gameboy::apu::{Apu, SAMPLE_RATE};
+use super::resampler::Resampler;
+
+
+const HEADER_SIZE: u32 = 44;
+// The RIFF size field counts everything after itself in a u32
+const MAX_DATA_SIZE: u64 = 0xFFFF_FFFF - (HEADER_SIZE as u64 - 8);
+
+
+// Writes 16-bit PCM samples to a WAV file. The header's sizes are
+// filled in by `finish`. Writes that would take the file past the 4 GiB
+// the header can describe fail without writing anything.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, channels: u16, rate: u32) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&rate.to_le_bytes())?;
+        file.write_all(&(rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            file: file,
+            samples: 0,
+        })
+    }
+    // Write interleaved samples from -1.0 to 1.0.
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        if (self.samples as u64 + samples.len() as u64) * 2 > MAX_DATA_SIZE {
+            return Err(io::Error::new(io::ErrorKind::Other, "the WAV file is full"));
+        }
+        for sample in samples {
+            let value = (sample.max(-1.0).min(1.0) * i16::max_value() as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+    pub fn finish(mut self) -> io::Result<()> {
+        let data_size = self.samples * 2;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+struct ChannelFiles {
+    resampler: Resampler,
+    files: Vec<WavWriter>,
+}
+
+// Records the APU's mixed stereo output to a WAV file, and optionally
+// each channel on its own to `<name>.ch1.wav` to `<name>.ch4.wav`.
+pub struct AudioRecorder {
+    path: PathBuf,
+    resampler: Resampler,
+    mixed: WavWriter,
+    channels: Option<ChannelFiles>,
+    buffer: Vec<f32>,
+}
+
+impl AudioRecorder {
+    pub fn start(
+        apu: &mut Apu,
+        path: &Path,
+        rate: u32,
+        per_channel: bool,
+    ) -> io::Result<AudioRecorder> {
+        let mixed = WavWriter::create(path, 2, rate)?;
+        let channels = match per_channel {
+            true => {
+                let mut files = Vec::new();
+                for channel in 1..5 {
+                    let channel_path = path.with_extension(format!("ch{}.wav", channel));
+                    files.push(WavWriter::create(&channel_path, 1, rate)?);
+                }
+                Some(ChannelFiles {
+                    resampler: Resampler::new(4, SAMPLE_RATE, rate),
+                    files: files,
+                })
+            }
+            false => None,
+        };
+        apu.enable_samples(per_channel);
+        Ok(AudioRecorder {
+            path: path.to_path_buf(),
+            resampler: Resampler::new(2, SAMPLE_RATE, rate),
+            mixed: mixed,
+            channels: channels,
+            buffer: Vec::new(),
+        })
+    }
+    // Write out samples taken from the APU.
+    pub fn write(&mut self, samples: &[f32], channel_samples: &[f32]) -> io::Result<()> {
+        self.buffer.clear();
+        self.resampler.process(samples, &mut self.buffer);
+        self.mixed.write(&self.buffer)?;
+        if let Some(ref mut channels) = self.channels {
+            self.buffer.clear();
+            channels.resampler.process(channel_samples, &mut self.buffer);
+            for (i, file) in channels.files.iter_mut().enumerate() {
+                let samples: Vec<f32> = self.buffer.iter().skip(i).step_by(4).cloned().collect();
+                file.write(&samples)?;
+            }
+        }
+        Ok(())
+    }
+    pub fn finish(self) {
+        let mut result = self.mixed.finish();
+        if let Some(channels) = self.channels {
+            for file in channels.files {
+                result = result.and(file.finish());
+            }
+        }
+        match result {
+            Ok(()) => println!("Recorded audio to {}", self.path.display()),
+            Err(err) => eprintln!("Could not finish {}: {}", self.path.display(), err),
+        }
+    }
+}
+
+
+#[test]
+fn test_wav_header() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join("bitromney_test_wav_header.wav");
+    let mut wav = WavWriter::create(&path, 2, 48000).unwrap();
+    wav.write(&[0.0, 1.0, -1.0, 0.5]).unwrap();
+    wav.finish().unwrap();
+    let data = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(HEADER_SIZE as usize + 8, data.len());
+    assert_eq!(&b"RIFF"[..], &data[0..4]);
+    assert_eq!(&[44, 0, 0, 0], &data[4..8]);
+    assert_eq!(&[8, 0, 0, 0], &data[40..44]);
+    assert_eq!(&[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80], &data[44..50]);
+}
+
+#[test]
+fn test_wav_size_limit() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join("bitromney_test_wav_limit.wav");
+    let mut wav = WavWriter::create(&path, 1, 48000).unwrap();
+    wav.samples = (MAX_DATA_SIZE / 2) as u32 - 2;
+    assert!(wav.write(&[0.0, 0.0, 0.0]).is_err());
+    wav.write(&[0.0, 0.0]).unwrap();
+    assert!(wav.write(&[0.0]).is_err());
+    wav.finish().unwrap();
+    let data = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(&[0xFE, 0xFF, 0xFF, 0xFF], &data[4..8]);
+    assert_eq!(&[0xDA, 0xFF, 0xFF, 0xFF], &data[40..44]);
+}
@@ -78,3 +78,22 @@ impl BitFlags for u8 {
         *self ^= pushed;
     }
 }
+
+
+// CRC-32 (IEEE 802.3), as used by zip and PNG.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFF_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(0xCBF43926, crc32(b"123456789"));
+}
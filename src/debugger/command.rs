@@ -1,4 +1,5 @@
 use debugger::str_to_u16;
+use throttle::Speed;
 
 
 #[derive(Debug)]
@@ -15,7 +16,29 @@ pub enum SetType {
     Memory(usize, u8),
     Breakpoint(usize),
     // Register,
-    // Speed,
+    Speed(Speed),
+    Volume(u32),
+}
+
+#[derive(Debug)]
+pub enum VgmType {
+    Start(String),
+    Stop,
+    Loop,
+}
+
+#[derive(Debug)]
+pub enum WavType {
+    Start(String),
+    Stop,
+}
+
+#[derive(Debug)]
+pub enum CheatType {
+    List,
+    Add(String, String),
+    Enable(usize, bool),
+    Remove(usize),
 }
 
 #[derive(Debug)]
@@ -23,6 +46,14 @@ pub enum Command {
     Show(ShowType),
     Set(SetType),
     Step(u32),
+    SaveState(String),
+    LoadState(String),
+    Record(String),
+    Play(String),
+    Stop,
+    Vgm(VgmType),
+    Wav(WavType),
+    Cheat(CheatType),
     Restart,
     Resume,
     Quit,
@@ -37,6 +68,74 @@ pub fn build_step(parts: &Vec<&str>) -> Result<Command, &'static str> {
     }
 }
 
+pub fn build_save(parts: &Vec<&str>) -> Result<Command, &'static str> {
+    match parts.get(0) {
+        Some(target) if !target.is_empty() => Ok(Command::SaveState(target.to_string())),
+        _ => Err("Save requires a slot number or file path."),
+    }
+}
+
+pub fn build_load(parts: &Vec<&str>) -> Result<Command, &'static str> {
+    match parts.get(0) {
+        Some(target) if !target.is_empty() => Ok(Command::LoadState(target.to_string())),
+        _ => Err("Load requires a slot number or file path."),
+    }
+}
+
+pub fn build_record(parts: &Vec<&str>) -> Result<Command, &'static str> {
+    match parts.get(0) {
+        Some(path) if !path.is_empty() => Ok(Command::Record(path.to_string())),
+        _ => Err("Record requires a movie file path."),
+    }
+}
+
+pub fn build_play(parts: &Vec<&str>) -> Result<Command, &'static str> {
+    match parts.get(0) {
+        Some(path) if !path.is_empty() => Ok(Command::Play(path.to_string())),
+        _ => Err("Play requires a movie file path."),
+    }
+}
+
+pub fn build_vgm(parts: &Vec<&str>) -> Result<Command, &'static str> {
+    let vgmtype = match (parts[0], parts.get(1)) {
+        ("start", Some(path)) if !path.is_empty() => VgmType::Start(path.to_string()),
+        ("start", _) => return Err("Vgm start requires a file path."),
+        ("stop", _) => VgmType::Stop,
+        ("loop", _) => VgmType::Loop,
+        _ => return Err("Vgm takes 'start <file>', 'stop' or 'loop'."),
+    };
+    Ok(Command::Vgm(vgmtype))
+}
+
+pub fn build_wav(parts: &Vec<&str>) -> Result<Command, &'static str> {
+    let wavtype = match (parts[0], parts.get(1)) {
+        ("start", Some(path)) if !path.is_empty() => WavType::Start(path.to_string()),
+        ("start", _) => return Err("Wav start requires a file path."),
+        ("stop", _) => WavType::Stop,
+        _ => return Err("Wav takes 'start <file>' or 'stop'."),
+    };
+    Ok(Command::Wav(wavtype))
+}
+
+pub fn build_cheat(parts: &Vec<&str>) -> Result<Command, &'static str> {
+    let index = || match parts.get(1).map(|x| x.parse::<usize>()) {
+        Some(Ok(val)) => Ok(val),
+        _ => Err("Cheat on, off and remove take a cheat number."),
+    };
+    let cheattype = match (parts.get(0).map_or("list", |x| *x), parts.get(1)) {
+        ("list", _) => CheatType::List,
+        ("add", Some(code)) if !code.is_empty() => {
+            CheatType::Add(code.to_string(), parts[2..].join(" "))
+        }
+        ("add", _) => return Err("Cheat add requires a code."),
+        ("on", _) => CheatType::Enable(index()?, true),
+        ("off", _) => CheatType::Enable(index()?, false),
+        ("remove", _) => CheatType::Remove(index()?),
+        _ => return Err("Cheat takes 'list', 'add <code> [name]', 'on', 'off' or 'remove'."),
+    };
+    Ok(Command::Cheat(cheattype))
+}
+
 pub fn build_show(parts: &Vec<&str>) -> Result<Command, &'static str> {
     let st = parts[0];
     let showtype = match st {
@@ -67,6 +166,18 @@ pub fn build_set(parts: &Vec<&str>) -> Result<Command, &'static str> {
                 Err(err) => return Err(err),
             }
         }
+        "speed" => {
+            match _build_speed(&parts) {
+                Ok(settype) => settype,
+                Err(err) => return Err(err),
+            }
+        }
+        "volume" | "vol" => {
+            match _build_volume(&parts) {
+                Ok(settype) => settype,
+                Err(err) => return Err(err),
+            }
+        }
         _ => return Err("Invalid argument for 'set'."),
 
     };
@@ -98,6 +209,22 @@ fn _build_breakpoint(parts: &Vec<&str>) -> Result<SetType, &'static str> {
         Err(_) => return Err("Invalid argument for set type."),
     }
 }
+fn _build_speed(parts: &Vec<&str>) -> Result<SetType, &'static str> {
+    if parts.len() < 2 {
+        return Err("Speed requires a multiplier or 'max'.");
+    }
+    match Speed::from_str(parts[1]) {
+        Some(speed) => Ok(SetType::Speed(speed)),
+        None => Err("Invalid argument for speed."),
+    }
+}
+fn _build_volume(parts: &Vec<&str>) -> Result<SetType, &'static str> {
+    match parts.get(1).map(|x| x.parse::<u32>()) {
+        Some(Ok(val)) if val <= 100 => Ok(SetType::Volume(val)),
+        Some(_) => Err("Volume must be a percentage from 0 to 100."),
+        None => Err("Volume requires a percentage."),
+    }
+}
 fn _build_memory_type(parts: &Vec<&str>) -> Result<ShowType, &'static str> {
     let loc1 = match str_to_u16(parts[0]) {
         Ok(val) => val,
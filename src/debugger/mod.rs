@@ -2,17 +2,35 @@ mod command;
 pub mod dis;
 
 use sdl2;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use std::process;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, LALTMOD, LSHIFTMOD, RALTMOD, RSHIFTMOD};
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::io::{stdout, stdin, Write};
 
+use audio::Audio;
+use audio::vgm::VgmWriter;
+use audio::wav::AudioRecorder;
 use gameboy::Gameboy;
+use gameboy::joypad::Button;
 use gameboy::operations::get_operation;
-use self::command::{Command, build_step, build_show, build_set, ShowType, SetType};
+use graphics::display::Display;
+use graphics::scope::Scope;
+use movie::{Movie, Player, Recorder, Session, Start};
+use rewind::Rewind;
+use savestate;
+use savestate::SaveSlots;
+use throttle::{Speed, Throttle};
+use self::command::{Command, build_step, build_show, build_set, build_save, build_load,
+                    build_record, build_play, build_vgm, build_wav, build_cheat, CheatType,
+                    ShowType, SetType, VgmType, WavType};
 
 
 const MEM_DISPLAY_WIDTH: u16 = 16;
+// Snapshot every third of a second and keep about two minutes of them.
+const REWIND_INTERVAL: u64 = 20;
+const REWIND_SNAPSHOTS: usize = 360;
 
 
 enum DebugMode {
@@ -29,6 +47,27 @@ pub struct Debugger {
     mode: DebugMode,
     gameboy: Gameboy,
     step_distance: u32,
+    frame_limit: Option<u64>,
+    throttle: Throttle,
+    // Speed to return to when the fast-forward key is released
+    held_speed: Option<Speed>,
+    display: Option<Rc<RefCell<Display>>>,
+    audio: Option<Audio>,
+    audio_recorder: Option<AudioRecorder>,
+    // Sample rate and per-channel files for `wav start`
+    record_rate: u32,
+    record_channels: bool,
+    vgm: Option<VgmWriter>,
+    scope: Option<Scope>,
+    save_slots: Option<SaveSlots>,
+    // Where changes to the cheats are saved
+    cheat_path: Option<PathBuf>,
+    rewind: Rewind,
+    rewinding: bool,
+    // Buttons held on the keyboard; handed to the machine as each
+    // frame begins so input stays reproducible.
+    buttons: u8,
+    movie: Option<Session>,
     events: sdl2::EventPump,
 }
 
@@ -40,27 +79,188 @@ impl Debugger {
             gameboy: gameboy,
             mode: DebugMode::Repl,
             step_distance: 10,
+            frame_limit: None,
+            throttle: Throttle::new(),
+            held_speed: None,
+            display: None,
+            audio: None,
+            audio_recorder: None,
+            record_rate: 48000,
+            record_channels: false,
+            vgm: None,
+            scope: None,
+            save_slots: None,
+            cheat_path: None,
+            rewind: Rewind::new(REWIND_INTERVAL, REWIND_SNAPSHOTS),
+            rewinding: false,
+            buttons: 0,
+            movie: None,
             events: event_pump,
         }
     }
+    pub fn gameboy(&self) -> &Gameboy {
+        &self.gameboy
+    }
+    pub fn resume(&mut self) {
+        self.mode = DebugMode::Running;
+    }
+    pub fn set_display(&mut self, display: Rc<RefCell<Display>>) {
+        self.display = Some(display);
+    }
+    pub fn set_audio(&mut self, audio: Audio) {
+        self.gameboy.mmu.apu.enable_samples(false);
+        self.audio = Some(audio);
+    }
+    pub fn set_audio_recorder(&mut self, recorder: AudioRecorder) {
+        self.audio_recorder = Some(recorder);
+    }
+    pub fn set_record_format(&mut self, rate: u32, per_channel: bool) {
+        self.record_rate = rate;
+        self.record_channels = per_channel;
+    }
+    pub fn set_vgm(&mut self, writer: VgmWriter) {
+        self.vgm = Some(writer);
+    }
+    pub fn set_scope(&mut self, scope: Scope) {
+        self.gameboy.mmu.apu.enable_samples(true);
+        self.scope = Some(scope);
+    }
+    pub fn set_save_slots(&mut self, slots: SaveSlots) {
+        self.save_slots = Some(slots);
+    }
+    pub fn set_cheat_path(&mut self, path: PathBuf) {
+        self.cheat_path = Some(path);
+    }
+    pub fn set_movie(&mut self, session: Session) {
+        self.movie = Some(session);
+        self.begin_frame();
+    }
+    pub fn set_frame_limit(&mut self, limit: Option<u64>) {
+        self.frame_limit = limit;
+    }
     fn cycle(&mut self) {
         self.handle_events();
+        let frame = self.gameboy.frames();
         self.gameboy.step();
+        if self.gameboy.frames() != frame {
+            self.rewind.record(&self.gameboy);
+            self.end_frame();
+            self.begin_frame();
+        }
         self.log();
         self.check_breakpoints();
+        self.check_frame_limit();
     }
 
     fn handle_events(&mut self) {
-        for event in self.events.poll_iter() {
+        let events: Vec<Event> = self.events.poll_iter().collect();
+        for event in events {
             match event {
+                Event::Window { window_id, win_event: WindowEvent::Close, .. }
+                    if self.scope.as_ref().map(|x| x.window_id()) == Some(window_id) => {
+                    self.scope = None
+                }
                 Event::Quit { .. } |
+                Event::Window { win_event: WindowEvent::Close, .. } |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     self.mode = DebugMode::Repl
                 }
+                Event::KeyDown { keycode: Some(Keycode::Tab), repeat: false, .. } => {
+                    self.held_speed = Some(self.throttle.speed());
+                    self.throttle.set_speed(Speed::Uncapped);
+                }
+                Event::KeyUp { keycode: Some(Keycode::Tab), .. } => {
+                    if let Some(speed) = self.held_speed.take() {
+                        self.throttle.set_speed(speed);
+                    }
+                }
+                Event::KeyDown { keycode: Some(Keycode::Equals), .. } => {
+                    self.throttle.faster();
+                    println!("Speed: {}", self.throttle.speed());
+                }
+                Event::KeyDown { keycode: Some(Keycode::Minus), .. } => {
+                    self.throttle.slower();
+                    println!("Speed: {}", self.throttle.speed());
+                }
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => {
+                    self.throttle.set_speed(Speed::normal());
+                    println!("Speed: {}", self.throttle.speed());
+                }
+                Event::KeyDown { keycode: Some(Keycode::R), .. } => {
+                    // Going back in time would break a movie's input
+                    // sequence.
+                    if self.movie.is_none() {
+                        self.rewinding = true;
+                    }
+                }
+                Event::KeyUp { keycode: Some(Keycode::R), .. } => self.rewinding = false,
+                Event::KeyDown { keycode: Some(Keycode::F11), .. } => self.toggle_fullscreen(),
+                Event::KeyDown { keycode: Some(Keycode::M), repeat: false, .. } => {
+                    self.toggle_mute()
+                }
+                Event::KeyDown { keycode: Some(Keycode::Return), keymod, .. }
+                    if keymod.intersects(LALTMOD | RALTMOD) => self.toggle_fullscreen(),
+                Event::KeyDown { keycode: Some(key), keymod, repeat: false, .. } => {
+                    if let Some(slot) = state_slot(key) {
+                        let target = format!("{}", slot);
+                        if keymod.intersects(LSHIFTMOD | RSHIFTMOD) {
+                            self.save_state(&target);
+                        } else {
+                            self.load_state(&target);
+                        }
+                    } else if let Some(channel) = sound_channel(key) {
+                        let shift = keymod.intersects(LSHIFTMOD | RSHIFTMOD);
+                        self.toggle_channel(channel, shift);
+                    } else if let Some(button) = joypad_button(key) {
+                        self.buttons |= button.mask();
+                    }
+                }
+                Event::KeyUp { keycode: Some(key), .. } => {
+                    if let Some(button) = joypad_button(key) {
+                        self.buttons &= !button.mask();
+                    }
+                }
+                Event::Window { win_event: WindowEvent::SizeChanged(..), .. } => {
+                    if let Some(ref display) = self.display {
+                        display.borrow_mut().present();
+                    }
+                }
                 _ => {}
             }
         }
     }
+    fn save_state(&self, target: &str) {
+        let path = match self.save_slots {
+            Some(ref slots) => slots.resolve(target),
+            None => target.into(),
+        };
+        match savestate::save(&self.gameboy, &path) {
+            Ok(()) => println!("Saved state to {}", path.display()),
+            Err(err) => println!("Could not save state to {}: {}", path.display(), err),
+        }
+    }
+    fn load_state(&mut self, target: &str) {
+        let path = match self.save_slots {
+            Some(ref slots) => slots.resolve(target),
+            None => target.into(),
+        };
+        match savestate::load(&mut self.gameboy, &path) {
+            Ok(()) => {
+                if let Some(session) = self.movie.take() {
+                    session.finish(&mut self.gameboy);
+                }
+                self.throttle.reset();
+                self.rewind.clear();
+                println!("Loaded state from {}", path.display());
+            }
+            Err(err) => println!("Could not load state from {}: {}", path.display(), err),
+        }
+    }
+    fn toggle_fullscreen(&mut self) {
+        if let Some(ref display) = self.display {
+            display.borrow_mut().toggle_fullscreen();
+        }
+    }
     fn log(&mut self) {
         let first = self.gameboy.mmu.read(self.gameboy.cpu.regs.pc) as u16;
         let code = match self.gameboy.mmu.read(self.gameboy.cpu.regs.pc) {
@@ -84,9 +284,130 @@ impl Debugger {
             self.mode = DebugMode::Repl;
         }
     }
+    // Hand the frame's sound to the audio queue and wait until the next
+    // frame is due.
+    fn end_frame(&mut self) {
+        let samples = self.gameboy.mmu.apu.take_samples();
+        let channel_samples = self.gameboy.mmu.apu.take_channel_samples();
+        if let Some(ref mut scope) = self.scope {
+            let apu = &self.gameboy.mmu.apu;
+            let channels: Vec<_> = (0..4).map(|i| apu.channel_info(i)).collect();
+            scope.draw(&channel_samples, &channels);
+        }
+        if let Some(mut recorder) = self.audio_recorder.take() {
+            match recorder.write(&samples, &channel_samples) {
+                Ok(()) => self.audio_recorder = Some(recorder),
+                Err(err) => {
+                    println!("Stopped recording audio: {}", err);
+                    recorder.finish();
+                }
+            }
+        }
+        if let Some(mut writer) = self.vgm.take() {
+            let (writes, cycles) = self.gameboy.mmu.apu.take_writes();
+            match writer.write(&writes, cycles) {
+                Ok(()) => self.vgm = Some(writer),
+                Err(err) => {
+                    println!("Stopped logging sound registers: {}", err);
+                    self.gameboy.mmu.apu.stop_log();
+                }
+            }
+        }
+        if let Some(ref mut audio) = self.audio {
+            // Other speeds would starve or flood the queue, so they are
+            // silent.
+            if self.throttle.speed() != Speed::normal() {
+                audio.clear();
+            } else if audio.sync() {
+                audio.push(&samples);
+                audio.wait();
+                return;
+            } else {
+                audio.push(&samples);
+            }
+        }
+        self.throttle.frame();
+    }
+    fn toggle_mute(&mut self) {
+        if let Some(ref mut audio) = self.audio {
+            match audio.toggle_mute() {
+                true => println!("Sound muted"),
+                false => println!("Sound unmuted"),
+            }
+        }
+    }
+    fn toggle_channel(&mut self, channel: usize, solo: bool) {
+        let apu = &mut self.gameboy.mmu.apu;
+        match solo {
+            true if apu.toggle_solo(channel) => println!("Channel {} solo", channel + 1),
+            true => println!("All channels playing"),
+            false if apu.toggle_mute(channel) => println!("Channel {} muted", channel + 1),
+            false => println!("Channel {} playing", channel + 1),
+        }
+    }
+    fn begin_frame(&mut self) {
+        match self.movie.take() {
+            Some(mut session) => {
+                if session.frame(&mut self.gameboy, self.buttons) {
+                    self.movie = Some(session);
+                } else {
+                    session.finish(&mut self.gameboy);
+                }
+            }
+            None => self.gameboy.set_buttons(self.buttons),
+        }
+    }
+    fn stop_movie(&mut self) {
+        match self.movie.take() {
+            Some(session) => session.finish(&mut self.gameboy),
+            None => println!("No movie is recording or playing."),
+        }
+    }
+    fn record_movie(&mut self, path: &str) {
+        if let Some(session) = self.movie.take() {
+            session.finish(&mut self.gameboy);
+        }
+        let recorder = Recorder::start(&self.gameboy, Start::SaveState, path.as_ref());
+        self.set_movie(Session::Recording(recorder));
+    }
+    fn play_movie(&mut self, path: &str) {
+        if let Some(session) = self.movie.take() {
+            session.finish(&mut self.gameboy);
+        }
+        let player = Movie::load(path.as_ref()).and_then(|movie| {
+            Player::start(&mut self.gameboy, movie)
+        });
+        match player {
+            Ok(player) => {
+                self.rewind.clear();
+                self.set_movie(Session::Playing(player));
+            }
+            Err(err) => println!("Could not play movie {}: {}", path, err),
+        }
+    }
+    // Run backwards one frame per throttled frame while the rewind key
+    // is held.
+    fn rewind_frame(&mut self) {
+        self.handle_events();
+        if self.rewind.step_back(&mut self.gameboy) {
+            self.throttle.frame();
+        } else {
+            self.rewinding = false;
+        }
+    }
+    fn check_frame_limit(&mut self) {
+        if let Some(limit) = self.frame_limit {
+            if self.gameboy.frames() >= limit {
+                self.mode = DebugMode::Quitting;
+            }
+        }
+    }
     fn step(&mut self) {
         for _ in 0..self.step_distance {
-            self.cycle()
+            self.cycle();
+            if let DebugMode::Quitting = self.mode {
+                return;
+            }
         }
         self.mode = DebugMode::Repl;
     }
@@ -103,7 +424,19 @@ impl Debugger {
             match self.mode {
                 DebugMode::Repl => self.repl(),
                 DebugMode::Restarting => {}
-                DebugMode::Quitting => process::exit(1),
+                DebugMode::Quitting => {
+                    if let Some(session) = self.movie.take() {
+                        session.finish(&mut self.gameboy);
+                    }
+                    if let Some(recorder) = self.audio_recorder.take() {
+                        recorder.finish();
+                    }
+                    if let Some(writer) = self.vgm.take() {
+                        writer.finish(&mut self.gameboy.mmu.apu);
+                    }
+                    return;
+                }
+                DebugMode::Running if self.rewinding => self.rewind_frame(),
                 DebugMode::Running => self.cycle(),
                 DebugMode::Stepping => self.step(),
             };
@@ -122,6 +455,7 @@ impl Debugger {
                 Err(error) => println!("{}", error),
             };
         }
+        self.throttle.reset();
     }
     fn handle_command(&mut self, command: Command) {
         match command {
@@ -129,6 +463,14 @@ impl Debugger {
                 self.step_distance = dist;
                 self.mode = DebugMode::Stepping
             }
+            Command::Record(path) => self.record_movie(&path),
+            Command::Play(path) => self.play_movie(&path),
+            Command::Stop => self.stop_movie(),
+            Command::Vgm(vgmtype) => self.vgm(vgmtype),
+            Command::Wav(wavtype) => self.wav(wavtype),
+            Command::Cheat(cheattype) => self.cheat(cheattype),
+            Command::SaveState(target) => self.save_state(&target),
+            Command::LoadState(target) => self.load_state(&target),
             Command::Restart => self.mode = DebugMode::Restarting,
             Command::Resume => self.mode = DebugMode::Running,
             Command::Quit => self.mode = DebugMode::Quitting,
@@ -141,6 +483,110 @@ impl Debugger {
         match settype {
             SetType::Breakpoint(val) => self.breakpoints.push(val),
             SetType::Memory(loc, val) => self.set_memory(loc, val),
+            SetType::Speed(speed) => {
+                self.throttle.set_speed(speed);
+                println!("Speed: {}", speed);
+            }
+            SetType::Volume(volume) => {
+                match self.audio {
+                    Some(ref mut audio) => {
+                        audio.set_volume(volume);
+                        println!("Volume: {}%", audio.volume());
+                    }
+                    None => println!("Sound is not enabled."),
+                }
+            }
+        }
+    }
+    fn vgm(&mut self, vgmtype: VgmType) {
+        match vgmtype {
+            VgmType::Start(path) => {
+                if let Some(writer) = self.vgm.take() {
+                    writer.finish(&mut self.gameboy.mmu.apu);
+                }
+                match VgmWriter::start(&mut self.gameboy.mmu.apu, path.as_ref()) {
+                    Ok(writer) => {
+                        println!("Logging sound registers to {}", path);
+                        self.vgm = Some(writer);
+                    }
+                    Err(err) => println!("Could not log to {}: {}", path, err),
+                }
+            }
+            VgmType::Stop => {
+                match self.vgm.take() {
+                    Some(writer) => writer.finish(&mut self.gameboy.mmu.apu),
+                    None => println!("Sound registers are not being logged."),
+                }
+            }
+            VgmType::Loop => {
+                match self.vgm {
+                    Some(ref mut writer) => {
+                        writer.mark_loop();
+                        println!("Loop point set");
+                    }
+                    None => println!("Sound registers are not being logged."),
+                }
+            }
+        }
+    }
+    fn wav(&mut self, wavtype: WavType) {
+        if let Some(recorder) = self.audio_recorder.take() {
+            recorder.finish();
+            // Only the scope still needs each channel's samples
+            self.gameboy.mmu.apu.enable_samples(self.scope.is_some());
+        } else if let WavType::Stop = wavtype {
+            println!("Audio is not being recorded.");
+        }
+        if let WavType::Start(path) = wavtype {
+            let apu = &mut self.gameboy.mmu.apu;
+            match AudioRecorder::start(apu, path.as_ref(), self.record_rate, self.record_channels) {
+                Ok(recorder) => {
+                    println!("Recording audio to {}", path);
+                    self.audio_recorder = Some(recorder);
+                }
+                Err(err) => println!("Could not record audio to {}: {}", path, err),
+            }
+        }
+    }
+    fn cheat(&mut self, cheattype: CheatType) {
+        let (index, found) = match cheattype {
+            CheatType::List => {
+                for (i, cheat) in self.gameboy.mmu.cheats.list().iter().enumerate() {
+                    let state = if cheat.enabled { "on" } else { "off" };
+                    println!("{:2} {:3} {:11} {}", i, state, cheat.text, cheat.name);
+                }
+                return;
+            }
+            // Movies replay with the codes they were recorded with
+            _ if self.movie.is_some() => {
+                println!("Cheats can't change while a movie is recording or playing.");
+                return;
+            }
+            CheatType::Add(code, name) => {
+                match self.gameboy.mmu.cheats.add(&code, &name) {
+                    Some(i) => {
+                        println!("Added cheat {}", i);
+                        (i, true)
+                    }
+                    None => {
+                        println!("'{}' is not a Game Genie or GameShark code.", code);
+                        return;
+                    }
+                }
+            }
+            CheatType::Enable(i, enabled) => (i, self.gameboy.mmu.cheats.set_enabled(i, enabled)),
+            CheatType::Remove(i) => (i, self.gameboy.mmu.cheats.remove(i).is_some()),
+        };
+        match found {
+            true => self.save_cheats(),
+            false => println!("There is no cheat {}.", index),
+        }
+    }
+    fn save_cheats(&self) {
+        if let Some(ref path) = self.cheat_path {
+            if let Err(err) = self.gameboy.mmu.cheats.save(path) {
+                println!("Could not save cheats to {}", err);
+            }
         }
     }
     fn set_memory(&mut self, loc: usize, val: u8) {
@@ -160,15 +606,37 @@ impl Debugger {
         Restart\t(restart|r) - Hard restart (clear breaks and traces)\n\
         Resume\t(go|start|resume) - Continue running to next break\n\
         Quit\t(quit|exit) - Quit emulation\n\
+        Save\t(save <slot|file>) - Save the machine state\n\
+        Load\t(load <slot|file>) - Restore a saved machine state\n\
+        Record\t(record <file>) - Record joypad input to a movie from here\n\
+        Play\t(play <file>) - Play back a movie\n\
+        Stop\t(stop) - Stop recording or playing a movie\n\
+        Vgm\t(vgm <start <file>|stop|loop>) - Log sound registers to a VGM file\n\
+        \t- vgm loop - Mark where the log loops back to\n\
+        Wav\t(wav <start <file>|stop>) - Record the sound to a WAV file\n\
+        Cheat\t(cheat [list]) - List the Game Genie and GameShark codes\n\
+        \t- cheat add <code> [name] - Add a code, saved next to the ROM\n\
+        \t- cheat <on|off|remove> n - Switch a code on or off, or drop it\n\
         Set\t(set <set type> arg\n\
         \t- set breakpoint 0x****\n\
         \t- set tracepoint 0x****\n\
-        \t- set speed <instructions per second>\n\
+        \t- set speed <multiplier|max>\n\
+        \t- set volume <0-100>\n\
         Show\t(show|print <show type> arg1 [arg2])\n\
         \t- show breakpoints\n\
         \t- show tracepooints\n\
         \t- show (mem|memory) n [n] (low [high])\n\
-        \t= show (regs | registers)
+        \t= show (regs | registers)\n\
+        Hotkeys (while running)\n\
+        \t- Tab (hold) - Fast-forward uncapped\n\
+        \t- = / - - Step speed up or down (0.25x to uncapped)\n\
+        \t- Backspace - Normal speed\n\
+        \t- F11 or Alt+Enter - Toggle fullscreen\n\
+        \t- F1-F10 - Load state slot 0-9 (Shift to save)\n\
+        \t- R (hold) - Rewind\n\
+        \t- M - Mute or unmute sound\n\
+        \t- 1-4 - Mute or unmute a sound channel (Shift to solo)\n\
+        \t- Arrows, X (A), Z (B), Enter (Start), Right Shift (Select) - Joypad
         ";
         println!("{}", help_string);
     }
@@ -233,6 +701,43 @@ impl Debugger {
 }
 
 
+// F1 through F10 map to save state slots 0 through 9.
+fn state_slot(key: Keycode) -> Option<usize> {
+    let keys = [
+        Keycode::F1,
+        Keycode::F2,
+        Keycode::F3,
+        Keycode::F4,
+        Keycode::F5,
+        Keycode::F6,
+        Keycode::F7,
+        Keycode::F8,
+        Keycode::F9,
+        Keycode::F10,
+    ];
+    keys.iter().position(|x| *x == key)
+}
+
+fn sound_channel(key: Keycode) -> Option<usize> {
+    [Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4]
+        .iter()
+        .position(|x| *x == key)
+}
+
+fn joypad_button(key: Keycode) -> Option<Button> {
+    match key {
+        Keycode::Right => Some(Button::Right),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::X => Some(Button::A),
+        Keycode::Z => Some(Button::B),
+        Keycode::RShift => Some(Button::Select),
+        Keycode::Return => Some(Button::Start),
+        _ => None,
+    }
+}
+
 fn parse_input(text: &str) -> Result<Command, &str> {
     let parts: Vec<&str> = text.split(" ").collect();
     let next_parts = &parts[1..].to_vec();
@@ -240,6 +745,14 @@ fn parse_input(text: &str) -> Result<Command, &str> {
         "show" | "print" => build_show(next_parts),
         "step" => build_step(next_parts),
         "set" => build_set(next_parts),
+        "save" => build_save(next_parts),
+        "load" => build_load(next_parts),
+        "record" => build_record(next_parts),
+        "play" => build_play(next_parts),
+        "vgm" => build_vgm(next_parts),
+        "wav" => build_wav(next_parts),
+        "cheat" | "cheats" => build_cheat(next_parts),
+        "stop" => Ok(Command::Stop),
         "restart" | "r" => Ok(Command::Restart),
         "go" | "resume" | "start" => Ok(Command::Resume),
         "exit" | "quit" | "q" => Ok(Command::Quit),
@@ -0,0 +1,994 @@
+use std::mem;
+
+use gameboy::CLOCK_SPEED;
+use gameboy::model::Model;
+use gameboy::state::{StateError, StateReader, StateWriter};
+
+
+// The frame sequencer runs at 512 Hz, clocking length counters on even
+// steps, the sweep on steps 2 and 6 and envelopes on step 7.
+const SEQUENCER_CYCLES: u32 = 8192;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+// Output is averaged over this many cycles, giving 131072 samples per
+// second for the frontend to resample.
+pub const SAMPLE_CYCLES: u32 = 32;
+pub const SAMPLE_RATE: u32 = CLOCK_SPEED / SAMPLE_CYCLES;
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits that always read back as 1 for each register from FF10 to FF2F.
+// Write-only and unused bits read high.
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+
+// Counts down while enabled and silences its channel on reaching zero.
+struct Length {
+    counter: u16,
+    enabled: bool,
+    max: u16,
+}
+
+impl Length {
+    fn new(max: u16) -> Length {
+        Length {
+            counter: 0,
+            enabled: false,
+            max: max,
+        }
+    }
+    fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+    // Returns false once the channel should be turned off.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_bool(self.enabled);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// Volume envelope shared by the square and noise channels (NRx2).
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            initial: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+    fn write(&mut self, value: u8) {
+        self.initial = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+    // With the top five bits of NRx2 clear the channel's DAC is off.
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.volume);
+        state.write_u8(self.timer);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.volume = state.read_u8()?;
+        self.timer = state.read_u8()?;
+        Ok(())
+    }
+}
+
+// Channels 1 and 2. Only channel 1 has a frequency sweep.
+struct Square {
+    enabled: bool,
+    duty: usize,
+    step: usize,
+    frequency: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow_frequency: u16,
+}
+
+impl Square {
+    fn new() -> Square {
+        Square {
+            enabled: false,
+            duty: 0,
+            step: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow_frequency: 0,
+        }
+    }
+    fn write(&mut self, register: usize, value: u8) {
+        match register {
+            0 => {
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x07;
+            }
+            1 => {
+                self.duty = (value >> 6) as usize;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.shadow_frequency = self.frequency;
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+        if self.sweep_shift != 0 {
+            self.sweep_frequency();
+        }
+    }
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+    fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.step = (self.step + 1) % 8;
+        }
+    }
+    // Work out the next swept frequency, silencing the channel if it
+    // would overflow.
+    fn sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift;
+        let frequency = match self.sweep_negate {
+            true => self.shadow_frequency.wrapping_sub(delta),
+            false => self.shadow_frequency + delta,
+        };
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        frequency
+    }
+    fn clock_sweep(&mut self) {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer > 0 {
+            return;
+        }
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let frequency = self.sweep_frequency();
+            if frequency <= 2047 && self.sweep_shift != 0 {
+                self.frequency = frequency;
+                self.shadow_frequency = frequency;
+                self.sweep_frequency();
+            }
+        }
+    }
+    fn output(&self) -> u8 {
+        match self.enabled {
+            true => DUTY_PATTERNS[self.duty][self.step] * self.envelope.volume,
+            false => 0,
+        }
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.step as u8);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer as u32);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write_u8(self.sweep_timer);
+        state.write_bool(self.sweep_enabled);
+        state.write_u16(self.shadow_frequency);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.step = state.read_u8()? as usize % 8;
+        self.frequency = state.read_u16()? & 0x7FF;
+        self.timer = state.read_u32()? as i32;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.sweep_timer = state.read_u8()?;
+        self.sweep_enabled = state.read_bool()?;
+        self.shadow_frequency = state.read_u16()?;
+        Ok(())
+    }
+}
+
+// Channel 3 plays back 32 four-bit samples from wave RAM.
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_shift: u8,
+    frequency: u16,
+    timer: i32,
+    position: usize,
+    length: Length,
+    ram: [u8; 0x10],
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            volume_shift: 4,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            length: Length::new(256),
+            ram: [0; 0x10],
+        }
+    }
+    fn write(&mut self, register: usize, value: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            // Volume codes 0-3 are mute, 100%, 50% and 25%.
+            2 => self.volume_shift = [4, 0, 1, 2][((value >> 5) & 0x03) as usize],
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            }
+        }
+    }
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+    fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) % 32;
+        }
+    }
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = self.ram[self.position / 2];
+        let sample = if self.position % 2 == 0 { byte >> 4 } else { byte & 0x0F };
+        sample >> self.volume_shift
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer as u32);
+        state.write_u8(self.position as u8);
+        self.length.save_state(state);
+        state.write_bytes(&self.ram);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.frequency = state.read_u16()? & 0x7FF;
+        self.timer = state.read_u32()? as i32;
+        self.position = state.read_u8()? as usize % 32;
+        self.length.load_state(state)?;
+        state.read_into(&mut self.ram)
+    }
+}
+
+// Channel 4 outputs the low bit of a 15-bit linear feedback shift
+// register, optionally shortened to 7 bits.
+struct Noise {
+    enabled: bool,
+    shift: u8,
+    short_mode: bool,
+    divisor: usize,
+    lfsr: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            shift: 0,
+            short_mode: false,
+            divisor: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+    fn write(&mut self, register: usize, value: u8) {
+        match register {
+            0 => {}
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = value >> 4;
+                self.short_mode = value & 0x08 != 0;
+                self.divisor = (value & 0x07) as usize;
+            }
+            _ => {
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger();
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7FFF;
+                }
+            }
+        }
+    }
+    fn period(&self) -> i32 {
+        (NOISE_DIVISORS[self.divisor] << self.shift) as i32
+    }
+    fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+    }
+    fn output(&self) -> u8 {
+        match self.enabled {
+            true => (!self.lfsr & 1) as u8 * self.envelope.volume,
+            false => 0,
+        }
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u16(self.lfsr);
+        state.write_u32(self.timer as u32);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.lfsr = state.read_u16()? & 0x7FFF;
+        self.timer = state.read_u32()? as i32;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+// What a channel is currently playing, for display.
+pub struct ChannelInfo {
+    pub enabled: bool,
+    pub muted: bool,
+    // In Hz; for noise, the rate the LFSR is clocked at
+    pub frequency: f32,
+    pub volume: u8,
+    // High percentage of the square channels' waveform
+    pub duty: Option<f32>,
+}
+
+// Collects interleaved stereo samples at SAMPLE_RATE while enabled,
+// and optionally each channel's own output as four interleaved samples.
+// This isn't part of the machine state.
+struct Sampler {
+    enabled: bool,
+    channels: bool,
+    cycles: u32,
+    // Running totals for left, right, then channels 1-4
+    sums: [f32; 6],
+    buffer: Vec<f32>,
+    channel_buffer: Vec<f32>,
+}
+
+impl Sampler {
+    fn new() -> Sampler {
+        Sampler {
+            enabled: false,
+            channels: false,
+            cycles: 0,
+            sums: [0.0; 6],
+            buffer: Vec::new(),
+            channel_buffer: Vec::new(),
+        }
+    }
+    fn add(&mut self, levels: [f32; 6], mut cycles: u32) {
+        while cycles > 0 {
+            let n = cycles.min(SAMPLE_CYCLES - self.cycles);
+            for (sum, level) in self.sums.iter_mut().zip(&levels) {
+                *sum += level * n as f32;
+            }
+            self.cycles += n;
+            cycles -= n;
+            if self.cycles == SAMPLE_CYCLES {
+                let mut averages = [0.0; 6];
+                for (average, sum) in averages.iter_mut().zip(&self.sums) {
+                    *average = sum / SAMPLE_CYCLES as f32;
+                }
+                self.buffer.extend_from_slice(&averages[..2]);
+                if self.channels {
+                    self.channel_buffer.extend_from_slice(&averages[2..]);
+                }
+                self.cycles = 0;
+                self.sums = [0.0; 6];
+            }
+        }
+    }
+}
+
+// A write to a sound register, for logging. Registers count from FF10,
+// and `cycle` is the time since the log was last taken.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegisterWrite {
+    pub cycle: u32,
+    pub register: u8,
+    pub value: u8,
+}
+
+struct WriteLog {
+    cycles: u32,
+    writes: Vec<RegisterWrite>,
+    // Set while the machine is re-simulated, as when rewinding
+    paused: bool,
+}
+
+impl WriteLog {
+    fn new() -> WriteLog {
+        WriteLog {
+            cycles: 0,
+            writes: Vec::new(),
+            paused: false,
+        }
+    }
+    fn add(&mut self, address: usize, value: u8) {
+        if self.paused {
+            return;
+        }
+        self.writes.push(RegisterWrite {
+            cycle: self.cycles,
+            register: (address - 0xFF10) as u8,
+            value: value,
+        });
+    }
+}
+
+pub struct Apu {
+    square1: Square, // FF10-FF14
+    square2: Square, // FF15-FF19
+    wave: Wave, // FF1A-FF1E, wave RAM at FF30-FF3F
+    noise: Noise, // FF1F-FF23
+
+    // Last value written to each register, for reading back
+    registers: [u8; 0x20],
+
+    // Master volume and panning (NR50, NR51) and power (NR52 bit 7)
+    volume: u8, // FF24
+    panning: u8, // FF25
+    powered: bool, // FF26
+    // The DMG keeps its length counters through a power cycle.
+    model: Model,
+
+    sequencer_cycles: u32,
+    sequencer_step: u8,
+
+    sampler: Sampler,
+    // Channels left out of the mix, one bit each. Like the sampler this
+    // is a frontend setting rather than machine state.
+    muted: u8,
+    log: Option<WriteLog>,
+}
+
+impl Apu {
+    pub fn new(model: Model) -> Apu {
+        Apu {
+            square1: Square::new(),
+            square2: Square::new(),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            registers: [0; 0x20],
+            volume: 0,
+            panning: 0,
+            powered: false,
+            model: model,
+            sequencer_cycles: 0,
+            sequencer_step: 0,
+            sampler: Sampler::new(),
+            muted: 0,
+            log: None,
+        }
+    }
+    pub fn read_u8(&self, address: usize) -> u8 {
+        match address {
+            0xFF26 => {
+                let status = self.square1.enabled as u8 | (self.square2.enabled as u8) << 1 |
+                    (self.wave.enabled as u8) << 2 |
+                    (self.noise.enabled as u8) << 3;
+                (self.powered as u8) << 7 | READ_MASKS[0x16] | status
+            }
+            0xFF10...0xFF2F => {
+                let idx = address - 0xFF10;
+                self.registers[idx] | READ_MASKS[idx]
+            }
+            0xFF30...0xFF3F => self.wave.ram[address - 0xFF30],
+            _ => panic!("{:04X} is not a valid Apu-mapped address.", address),
+        }
+    }
+    pub fn write_u8(&mut self, address: usize, value: u8) {
+        if let Some(ref mut log) = self.log {
+            log.add(address, value);
+        }
+        match address {
+            0xFF26 => self.set_power(value & 0x80 != 0),
+            0xFF30...0xFF3F => self.wave.ram[address - 0xFF30] = value,
+            // While powered off only the DMG's length counters can be
+            // written.
+            0xFF11 | 0xFF16 | 0xFF20 if !self.powered && !self.model.is_cgb() => {
+                self.write_length(address, value & 0x3F)
+            }
+            0xFF1B if !self.powered && !self.model.is_cgb() => self.wave.length.load(value),
+            _ if !self.powered => {}
+            0xFF10...0xFF2F => {
+                self.registers[address - 0xFF10] = value;
+                match address {
+                    0xFF10...0xFF14 => self.square1.write(address - 0xFF10, value),
+                    0xFF15 => {}
+                    0xFF16...0xFF19 => self.square2.write(address - 0xFF15, value),
+                    0xFF1A...0xFF1E => self.wave.write(address - 0xFF1A, value),
+                    0xFF1F...0xFF23 => self.noise.write(address - 0xFF1F, value),
+                    0xFF24 => self.volume = value,
+                    0xFF25 => self.panning = value,
+                    _ => {}
+                }
+            }
+            _ => panic!("{:04X} is not a valid Apu-mapped address.", address),
+        }
+    }
+    fn write_length(&mut self, address: usize, value: u8) {
+        match address {
+            0xFF11 => self.square1.length.load(value),
+            0xFF16 => self.square2.length.load(value),
+            _ => self.noise.length.load(value),
+        }
+    }
+    // Powering off clears every register except wave RAM, and on the DMG
+    // the length counters.
+    fn set_power(&mut self, on: bool) {
+        if self.powered && !on {
+            self.reset();
+        } else if !self.powered && on {
+            self.sequencer_step = 0;
+            self.sequencer_cycles = 0;
+        }
+        self.powered = on;
+    }
+    // Clear the registers and channels, keeping wave RAM and anything
+    // the frontend has yet to collect.
+    fn reset(&mut self) {
+        let wave_ram = self.wave.ram;
+        let sampler = mem::replace(&mut self.sampler, Sampler::new());
+        let muted = self.muted;
+        let log = self.log.take();
+        let lengths = [
+            self.square1.length.counter,
+            self.square2.length.counter,
+            self.wave.length.counter,
+            self.noise.length.counter,
+        ];
+        *self = Apu::new(self.model);
+        if !self.model.is_cgb() {
+            self.square1.length.counter = lengths[0];
+            self.square2.length.counter = lengths[1];
+            self.wave.length.counter = lengths[2];
+            self.noise.length.counter = lengths[3];
+        }
+        self.wave.ram = wave_ram;
+        self.sampler = sampler;
+        self.muted = muted;
+        self.log = log;
+    }
+    // Start collecting samples for `take_samples`, and with `channels`
+    // for `take_channel_samples` as well.
+    pub fn enable_samples(&mut self, channels: bool) {
+        self.sampler.enabled = true;
+        self.sampler.channels |= channels;
+    }
+    // Interleaved left and right samples at SAMPLE_RATE produced since
+    // the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        mem::replace(&mut self.sampler.buffer, Vec::new())
+    }
+    // Each channel's samples at SAMPLE_RATE, four to a frame.
+    pub fn take_channel_samples(&mut self) -> Vec<f32> {
+        mem::replace(&mut self.sampler.channel_buffer, Vec::new())
+    }
+    // Start logging register writes for `take_writes`. The log opens
+    // with writes that recreate the current settings, without
+    // retriggering any channels.
+    pub fn start_log(&mut self) {
+        self.log = Some(WriteLog::new());
+        self.log_settings();
+    }
+    fn log_settings(&mut self) {
+        if let Some(ref mut log) = self.log {
+            log.add(0xFF26, (self.powered as u8) << 7);
+            for (i, &value) in self.registers.iter().enumerate().take(0x16) {
+                let address = 0xFF10 + i;
+                match address {
+                    0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => log.add(address, value & 0x7F),
+                    0xFF15 => {}
+                    _ => log.add(address, value),
+                }
+            }
+            for (i, &value) in self.wave.ram.iter().enumerate() {
+                log.add(0xFF30 + i, value);
+            }
+        }
+    }
+    pub fn stop_log(&mut self) {
+        self.log = None;
+    }
+    // Leave writes and time out of the log while paused. Resuming logs
+    // the settings the registers have ended up with.
+    pub fn pause_log(&mut self, paused: bool) {
+        let resumed = match self.log {
+            Some(ref mut log) => {
+                let resumed = log.paused && !paused;
+                log.paused = paused;
+                resumed
+            }
+            None => false,
+        };
+        if resumed {
+            self.log_settings();
+        }
+    }
+    pub fn logging(&self) -> bool {
+        self.log.is_some()
+    }
+    // Register writes since the last call, and the cycles that have
+    // passed in that time.
+    pub fn take_writes(&mut self) -> (Vec<RegisterWrite>, u32) {
+        match self.log {
+            Some(ref mut log) => {
+                let cycles = mem::replace(&mut log.cycles, 0);
+                (mem::replace(&mut log.writes, Vec::new()), cycles)
+            }
+            None => (Vec::new(), 0),
+        }
+    }
+    pub fn step(&mut self, cycles: u32) {
+        if let Some(ref mut log) = self.log {
+            if !log.paused {
+                log.cycles += cycles;
+            }
+        }
+        if self.sampler.enabled {
+            let (left, right) = self.output();
+            let channels = self.channel_outputs();
+            let levels = [left, right, channels[0], channels[1], channels[2], channels[3]];
+            self.sampler.add(levels, cycles);
+        }
+        if !self.powered {
+            return;
+        }
+        self.square1.step(cycles);
+        self.square2.step(cycles);
+        self.wave.step(cycles);
+        self.noise.step(cycles);
+
+        self.sequencer_cycles += cycles;
+        while self.sequencer_cycles >= SEQUENCER_CYCLES {
+            self.sequencer_cycles -= SEQUENCER_CYCLES;
+            self.clock_sequencer();
+        }
+    }
+    fn clock_sequencer(&mut self) {
+        let step = self.sequencer_step;
+        self.sequencer_step = (step + 1) % 8;
+        if step % 2 == 0 {
+            self.square1.enabled &= self.square1.length.clock();
+            self.square2.enabled &= self.square2.length.clock();
+            self.wave.enabled &= self.wave.length.clock();
+            self.noise.enabled &= self.noise.length.clock();
+        }
+        if step == 2 || step == 6 {
+            self.square1.clock_sweep();
+        }
+        if step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+    }
+    // Each channel's DAC output from -1.0 to 1.0, or 0.0 with its DAC
+    // turned off.
+    pub fn channel_outputs(&self) -> [f32; 4] {
+        let dac = |enabled: bool, digital: u8| match enabled {
+            true => digital as f32 / 7.5 - 1.0,
+            false => 0.0,
+        };
+        [
+            dac(self.square1.envelope.dac_enabled(), self.square1.output()),
+            dac(self.square2.envelope.dac_enabled(), self.square2.output()),
+            dac(self.wave.dac_enabled, self.wave.output()),
+            dac(self.noise.envelope.dac_enabled(), self.noise.output()),
+        ]
+    }
+    // The current left and right output levels from -1.0 to 1.0 after
+    // panning and master volume.
+    pub fn output(&self) -> (f32, f32) {
+        if !self.powered {
+            return (0.0, 0.0);
+        }
+        let channels = self.channel_outputs();
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, level) in channels.iter().enumerate() {
+            if self.muted & (1 << i) != 0 {
+                continue;
+            }
+            if self.panning & (0x10 << i) != 0 {
+                left += level;
+            }
+            if self.panning & (0x01 << i) != 0 {
+                right += level;
+            }
+        }
+        let left_volume = ((self.volume >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (self.volume & 0x07) as f32 + 1.0;
+        (left * left_volume / 32.0, right * right_volume / 32.0)
+    }
+    // Mute or unmute channel 0-3 in the mix. Returns whether it is now
+    // muted.
+    pub fn toggle_mute(&mut self, channel: usize) -> bool {
+        self.muted ^= 1 << channel;
+        self.muted & (1 << channel) != 0
+    }
+    // Mute every channel but one, or unmute them all if it was already
+    // the only one playing. Returns whether the channel is now soloed.
+    pub fn toggle_solo(&mut self, channel: usize) -> bool {
+        let others = 0x0F & !(1 << channel);
+        self.muted = if self.muted == others { 0 } else { others };
+        self.muted != 0
+    }
+    pub fn channel_info(&self, channel: usize) -> ChannelInfo {
+        let square = |square: &Square| {
+            ChannelInfo {
+                enabled: square.enabled,
+                muted: false,
+                frequency: 131072.0 / (2048 - square.frequency) as f32,
+                volume: square.envelope.volume,
+                duty: Some([12.5, 25.0, 50.0, 75.0][square.duty]),
+            }
+        };
+        let mut info = match channel {
+            0 => square(&self.square1),
+            1 => square(&self.square2),
+            2 => {
+                ChannelInfo {
+                    enabled: self.wave.enabled,
+                    muted: false,
+                    frequency: 65536.0 / (2048 - self.wave.frequency) as f32,
+                    volume: 15 >> self.wave.volume_shift,
+                    duty: None,
+                }
+            }
+            _ => {
+                ChannelInfo {
+                    enabled: self.noise.enabled,
+                    muted: false,
+                    frequency: CLOCK_SPEED as f32 / self.noise.period() as f32,
+                    volume: self.noise.envelope.volume,
+                    duty: None,
+                }
+            }
+        };
+        info.muted = self.muted & (1 << channel) != 0;
+        info
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.powered);
+        state.write_bytes(&self.registers);
+        self.square1.save_state(state);
+        self.square2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.write_u32(self.sequencer_cycles);
+        state.write_u8(self.sequencer_step);
+    }
+    // A log being taken gets the loaded settings rather than the writes
+    // that rebuild them.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let log = self.log.take();
+        let result = self.restore(state);
+        self.log = log;
+        self.log_settings();
+        result
+    }
+    // Register values are replayed to rebuild the channel settings,
+    // then the running state is restored over the top.
+    fn restore(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.reset();
+        self.powered = state.read_bool()?;
+        let mut registers = [0; 0x20];
+        state.read_into(&mut registers)?;
+        for (i, &value) in registers.iter().enumerate() {
+            let address = 0xFF10 + i;
+            match address {
+                0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => self.write_u8(address, value & 0x7F),
+                0xFF26 => {}
+                _ => self.write_u8(address, value),
+            }
+        }
+        self.square1.load_state(state)?;
+        self.square2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.sequencer_cycles = state.read_u32()? % SEQUENCER_CYCLES;
+        self.sequencer_step = state.read_u8()? % 8;
+        Ok(())
+    }
+}
+
+
+#[test]
+fn test_apu_registers() {
+    let mut apu = Apu::new(Model::Dmg);
+    apu.write_u8(0xFF12, 0xF0);
+    assert_eq!(0x00, apu.read_u8(0xFF12));
+    apu.write_u8(0xFF26, 0x80);
+    apu.write_u8(0xFF11, 0x80);
+    apu.write_u8(0xFF12, 0xF0);
+    apu.write_u8(0xFF14, 0xC7);
+    assert_eq!(0xBF, apu.read_u8(0xFF11));
+    assert_eq!(0xFF, apu.read_u8(0xFF13));
+    assert_eq!(0xFF, apu.read_u8(0xFF14));
+    assert_eq!(0xF1, apu.read_u8(0xFF26));
+
+    // A length of 64 runs out after 64 clocks at 256 Hz
+    apu.step(SEQUENCER_CYCLES * 126);
+    assert_eq!(0xF1, apu.read_u8(0xFF26));
+    apu.step(SEQUENCER_CYCLES * 2);
+    assert_eq!(0xF0, apu.read_u8(0xFF26));
+
+    apu.write_u8(0xFF26, 0x00);
+    assert_eq!(0x70, apu.read_u8(0xFF26));
+    assert_eq!(0x3F, apu.read_u8(0xFF11));
+}
+
+
+#[test]
+fn test_power_cycle_lengths() {
+    for &model in [Model::Dmg, Model::Cgb].iter() {
+        let mut apu = Apu::new(model);
+        apu.write_u8(0xFF11, 0x3E);
+        apu.write_u8(0xFF26, 0x80);
+        apu.write_u8(0xFF26, 0x00);
+        apu.write_u8(0xFF26, 0x80);
+        // Length enabled on a channel that's still silent
+        apu.write_u8(0xFF14, 0x40);
+        apu.step(SEQUENCER_CYCLES * 2);
+        let expected = if model.is_cgb() { 0 } else { 1 };
+        assert_eq!(expected, apu.square1.length.counter, "{:?}", model);
+    }
+}
+
+#[test]
+fn test_log_across_load_state() {
+    let mut apu = Apu::new(Model::Dmg);
+    apu.write_u8(0xFF26, 0x80);
+    apu.write_u8(0xFF12, 0xF3);
+    apu.write_u8(0xFF14, 0x87);
+    let mut state = StateWriter::new();
+    apu.save_state(&mut state);
+    let data = state.into_bytes();
+
+    apu.start_log();
+    let (settings, _) = apu.take_writes();
+    apu.write_u8(0xFF12, 0x00);
+    apu.take_writes();
+    apu.load_state(&mut StateReader::new(&data)).unwrap();
+    assert_eq!(settings, apu.take_writes().0);
+
+    apu.pause_log(true);
+    apu.write_u8(0xFF12, 0x00);
+    apu.step(100);
+    assert_eq!((Vec::new(), 0), apu.take_writes());
+    apu.write_u8(0xFF12, 0xF3);
+    apu.pause_log(false);
+    assert_eq!(settings, apu.take_writes().0);
+}
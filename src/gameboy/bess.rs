@@ -0,0 +1,340 @@
+// Best Effort Save State (BESS), the save state format shared between
+// emulators such as SameBoy. A BESS section is a series of blocks
+// appended to an emulator's own state data, located through a footer
+// holding the offset of the first block and the "BESS" magic. Memory
+// contents are not stored in the blocks themselves; the CORE block
+// points at buffers elsewhere in the file.
+use gameboy::Gameboy;
+use gameboy::state::{StateError, StateReader, StateWriter};
+
+
+const FOOTER_MAGIC: &'static [u8; 4] = b"BESS";
+const CORE_MAJOR: u16 = 1;
+const CORE_MINOR: u16 = 1;
+const NAME: &'static str = concat!("BitRomney ", env!("CARGO_PKG_VERSION"));
+
+// Executing, as opposed to halted or stopped
+const STATE_RUNNING: u8 = 0;
+
+
+// Append a BESS section describing `gameboy` to `data`.
+pub fn export(gameboy: &Gameboy, data: Vec<u8>) -> Vec<u8> {
+    let mut out = StateWriter::with_bytes(data);
+    let mmu = &gameboy.mmu;
+
+    let buffers: [&[u8]; 5] = [
+        mmu.wram(),
+        mmu.ppu.vram(),
+        mmu.rom().ram(),
+        mmu.ppu.oam(),
+        mmu.hram(),
+    ];
+    let mut locations = Vec::new();
+    for buffer in buffers.iter() {
+        locations.push((buffer.len() as u32, out.len() as u32));
+        out.write_bytes(buffer);
+    }
+    // DMG has no colour palette RAM
+    if gameboy.model().is_cgb() {
+        for buffer in [mmu.ppu.bg_colors(), mmu.ppu.obj_colors()].iter() {
+            locations.push((buffer.len() as u32, out.len() as u32));
+            out.write_bytes(buffer);
+        }
+    } else {
+        locations.push((0, 0));
+        locations.push((0, 0));
+    }
+
+    let first_block = out.len() as u32;
+
+    write_header(&mut out, b"NAME", NAME.len());
+    out.write_bytes(NAME.as_bytes());
+
+    write_header(&mut out, b"INFO", 0x12);
+    out.write_bytes(mmu.rom().header_title());
+    let checksum = mmu.rom().header_checksum();
+    out.write_u8((checksum >> 8) as u8);
+    out.write_u8(checksum as u8);
+
+    let regs = &gameboy.cpu.regs;
+    write_header(&mut out, b"CORE", 0xD0);
+    out.write_u16(CORE_MAJOR);
+    out.write_u16(CORE_MINOR);
+    out.write_bytes(gameboy.model().bess_code());
+    out.write_u16(regs.pc as u16);
+    out.write_u16(regs.af());
+    out.write_u16(regs.bc());
+    out.write_u16(regs.de());
+    out.write_u16(regs.hl());
+    out.write_u16(regs.sp as u16);
+    out.write_bool(mmu.ime);
+    out.write_u8(mmu.read(0xFFFF));
+    out.write_u8(STATE_RUNNING);
+    out.write_u8(0);
+    for address in 0xFF00..0xFF80 {
+        out.write_u8(mmu.read_io(address));
+    }
+    for &(size, offset) in locations.iter() {
+        out.write_u32(size);
+        out.write_u32(offset);
+    }
+
+    let writes = mmu.rom().mbc_writes();
+    if !writes.is_empty() {
+        write_header(&mut out, b"MBC ", writes.len() * 3);
+        for &(address, value) in writes.iter() {
+            out.write_u16(address);
+            out.write_u8(value);
+        }
+    }
+
+    write_header(&mut out, b"END ", 0);
+
+    out.write_u32(first_block);
+    out.write_bytes(FOOTER_MAGIC);
+    out.into_bytes()
+}
+
+pub fn has_footer(data: &[u8]) -> bool {
+    data.len() >= 8 && &data[data.len() - 4..] == FOOTER_MAGIC
+}
+
+// Whether everything in `data` from `offset` on is a BESS section, as
+// `export` appends after a native state.
+pub fn appended_at(data: &[u8], offset: usize) -> bool {
+    if !has_footer(data) {
+        return false;
+    }
+    let footer = data.len() - 8;
+    match StateReader::new(&data[footer..]).read_u32() {
+        Ok(first_block) => offset <= first_block as usize && first_block as usize <= footer,
+        Err(_) => false,
+    }
+}
+
+// Load the BESS section of `data`. On failure the machine is left as
+// it was.
+pub fn import(gameboy: &mut Gameboy, data: &[u8]) -> Result<(), StateError> {
+    let backup = gameboy.save_state();
+    // The I/O registers are written one at a time; a sound log only
+    // wants the settings they end up with.
+    gameboy.mmu.apu.pause_log(true);
+    let result = apply(gameboy, data);
+    if result.is_err() {
+        gameboy.load_state(&backup).unwrap();
+    }
+    gameboy.mmu.apu.pause_log(false);
+    result
+}
+
+fn write_header(out: &mut StateWriter, id: &[u8; 4], len: usize) {
+    out.write_bytes(id);
+    out.write_u32(len as u32);
+}
+
+fn apply(gameboy: &mut Gameboy, data: &[u8]) -> Result<(), StateError> {
+    if !has_footer(data) {
+        return Err(StateError::BadMagic);
+    }
+    let footer = data.len() - 8;
+    let first_block = StateReader::new(&data[footer..]).read_u32()? as usize;
+    if first_block > footer {
+        return Err(StateError::Invalid("block offset"));
+    }
+
+    let mut blocks = StateReader::new(&data[first_block..footer]);
+    let mut seen_core = false;
+    loop {
+        let id = blocks.read_bytes(4)?;
+        let len = blocks.read_u32()? as usize;
+        let body = blocks.read_bytes(len)?;
+        match id {
+            b"INFO" => check_info(gameboy, body)?,
+            b"CORE" => {
+                load_core(gameboy, body, data)?;
+                seen_core = true;
+            }
+            b"MBC " => {
+                if !seen_core {
+                    return Err(StateError::Invalid("block order"));
+                }
+                load_mbc(gameboy, body)?;
+            }
+            b"END " => break,
+            // NAME and anything we don't understand
+            _ => {}
+        }
+    }
+    if !seen_core {
+        return Err(StateError::Invalid("CORE block"));
+    }
+    Ok(())
+}
+
+fn check_info(gameboy: &Gameboy, body: &[u8]) -> Result<(), StateError> {
+    let mut info = StateReader::new(body);
+    let title = info.read_bytes(0x10)?;
+    let checksum = (info.read_u8()? as u16) << 8 | info.read_u8()? as u16;
+    let rom = gameboy.mmu.rom();
+    if title != rom.header_title() || checksum != rom.header_checksum() {
+        return Err(StateError::RomMismatch {
+            expected: rom.header_checksum() as u32,
+            found: checksum as u32,
+        });
+    }
+    Ok(())
+}
+
+fn load_core(gameboy: &mut Gameboy, body: &[u8], data: &[u8]) -> Result<(), StateError> {
+    let mut core = StateReader::new(body);
+    let major = core.read_u16()?;
+    let _minor = core.read_u16()?;
+    if major != CORE_MAJOR {
+        return Err(StateError::UnsupportedVersion(major as u32));
+    }
+    let model = core.read_bytes(4)?;
+    if model[0] != gameboy.model().bess_code()[0] {
+        return Err(StateError::Invalid("model"));
+    }
+
+    {
+        let regs = &mut gameboy.cpu.regs;
+        regs.pc = core.read_u16()? as usize;
+        let af = core.read_u16()?;
+        regs.a = (af >> 8) as u8;
+        regs.flags.set_u8(af as u8);
+        let bc = core.read_u16()?;
+        regs.set_bc(bc);
+        let de = core.read_u16()?;
+        regs.set_de(de);
+        let hl = core.read_u16()?;
+        regs.set_hl(hl);
+        regs.sp = core.read_u16()? as usize;
+    }
+
+    let mmu = &mut gameboy.mmu;
+    mmu.ime = core.read_bool()?;
+    let ie = core.read_u8()?;
+    mmu.write(0xFFFF, ie);
+    let _execution_state = core.read_u8()?;
+    let _reserved = core.read_u8()?;
+
+    let io = core.read_bytes(0x80)?;
+    // The sound registers ignore writes until NR52 powers the APU on.
+    mmu.write_io(0xFF26, io[0x26]);
+    for (i, value) in io.iter().enumerate() {
+        let address = 0xFF00 + i;
+        match address {
+            // Writing DMA or HDMA5 would start a transfer, and HDMA1-4 only
+            // read back as 0xFF.
+            0xFF46 | 0xFF51...0xFF55 => {}
+            // Writing palette data would move the palette index on
+            0xFF69 | 0xFF6B => {}
+            0xFF26 => {}
+            // Bit 7 of NRx4 reads back high, and writing it restarts the channel
+            0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => mmu.write_io(address, *value & 0x7F),
+            0xFF44 if *value > 153 => return Err(StateError::Invalid("LY value")),
+            _ => mmu.write_io(address, *value),
+        }
+    }
+
+    copy_buffer(&mut core, data, mmu.wram_mut())?;
+    copy_buffer(&mut core, data, mmu.ppu.vram_mut())?;
+    copy_buffer(&mut core, data, mmu.rom_mut().ram_mut())?;
+    copy_buffer(&mut core, data, mmu.ppu.oam_mut())?;
+    copy_buffer(&mut core, data, mmu.hram_mut())?;
+    copy_buffer(&mut core, data, mmu.ppu.bg_colors_mut())?;
+    copy_buffer(&mut core, data, mmu.ppu.obj_colors_mut())?;
+    Ok(())
+}
+
+// Copy the buffer described by the next size/offset pair into `dest`.
+// If the sizes disagree, as much as fits is copied.
+fn copy_buffer(core: &mut StateReader, data: &[u8], dest: &mut [u8]) -> Result<(), StateError> {
+    let size = core.read_u32()? as usize;
+    let offset = core.read_u32()? as usize;
+    if offset.checked_add(size).map_or(true, |end| end > data.len()) {
+        return Err(StateError::Truncated);
+    }
+    let len = size.min(dest.len());
+    dest[..len].copy_from_slice(&data[offset..offset + len]);
+    Ok(())
+}
+
+fn load_mbc(gameboy: &mut Gameboy, body: &[u8]) -> Result<(), StateError> {
+    if body.len() % 3 != 0 {
+        return Err(StateError::Invalid("MBC block"));
+    }
+    let mut mbc = StateReader::new(body);
+    for _ in 0..body.len() / 3 {
+        let address = mbc.read_u16()? as usize;
+        let value = mbc.read_u8()?;
+        match address {
+            0x0000...0x7FFF | 0xA000...0xBFFF => gameboy.mmu.write(address, value),
+            _ => return Err(StateError::Invalid("MBC register address")),
+        }
+    }
+    Ok(())
+}
+
+
+#[test]
+fn test_trailing_data() {
+    use gameboy::model::Model;
+    use gameboy::rom::Rom;
+
+    let rom = Rom::from_bytes(vec![0; 0x8000], "bess").unwrap();
+    let mut gameboy = Gameboy::new(rom, Model::Dmg);
+    let state = gameboy.save_state();
+    let exported = export(&gameboy, state.clone());
+    assert!(gameboy.load_state(&exported).is_ok());
+    let mut padded = state.clone();
+    padded.push(0);
+    assert!(gameboy.load_state(&padded).is_err());
+    assert!(gameboy.load_state(&state[..state.len() - 1]).is_err());
+}
+
+#[test]
+fn test_round_trip() {
+    use gameboy::model::Model;
+    use gameboy::rom::Rom;
+
+    // MBC1 with RAM, each bank starting with its own number
+    let mut data = vec![0; 0x4000 * 8];
+    for bank in 0..8 {
+        data[bank * 0x4000] = bank as u8;
+    }
+    data[0x147] = 0x03;
+    data[0x149] = 0x02;
+    let mut gameboy = Gameboy::new(Rom::from_bytes(data.clone(), "bess").unwrap(), Model::Dmg);
+    gameboy.skip_boot_rom();
+    for _ in 0..3 {
+        gameboy.run_frame();
+    }
+    gameboy.mmu.write(0x0000, 0x0A);
+    gameboy.mmu.write(0x2000, 0x05);
+    gameboy.mmu.write(0xA010, 0x77);
+    gameboy.mmu.write(0xC123, 0x42);
+    gameboy.mmu.write(0xFF90, 0x99);
+    gameboy.mmu.write(0xFF47, 0xE4);
+    gameboy.cpu.regs.set_hl(0x1234);
+    let exported = export(&gameboy, Vec::new());
+
+    let mut copy = Gameboy::new(Rom::from_bytes(data, "bess").unwrap(), Model::Dmg);
+    import(&mut copy, &exported).unwrap();
+    let (a, b) = (&gameboy.cpu.regs, &copy.cpu.regs);
+    assert_eq!((a.pc, a.sp, a.af(), a.bc(), a.de()), (b.pc, b.sp, b.af(), b.bc(), b.de()));
+    assert_eq!(0x1234, b.hl());
+    assert_eq!(gameboy.mmu.ime, copy.mmu.ime);
+    assert_eq!(gameboy.mmu.wram(), copy.mmu.wram());
+    assert_eq!(gameboy.mmu.hram(), copy.mmu.hram());
+    assert_eq!(gameboy.mmu.ppu.vram(), copy.mmu.ppu.vram());
+    assert_eq!(gameboy.mmu.ppu.oam(), copy.mmu.ppu.oam());
+    assert_eq!(gameboy.mmu.rom().ram(), copy.mmu.rom().ram());
+    assert_eq!(5, copy.mmu.read(0x4000));
+    assert_eq!(0x77, copy.mmu.read(0xA010));
+    for address in (0xFF40..0xFF46).chain(0xFF47..0xFF4C) {
+        assert_eq!(gameboy.mmu.read_io(address), copy.mmu.read_io(address), "{:04X}", address);
+    }
+    assert_eq!(gameboy.mmu.read(0xFFFF), copy.mmu.read(0xFFFF));
+}
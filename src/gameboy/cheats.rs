@@ -0,0 +1,260 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Code {
+    // Replaces a byte read from ROM, optionally only while the byte there
+    // matches `compare` so other banks at the same address are untouched.
+    GameGenie {
+        address: usize,
+        value: u8,
+        compare: Option<u8>,
+    },
+    // Writes a byte to RAM every frame. Bank 0x90-0x97 puts a CGB work
+    // RAM bank at D000 for the write.
+    GameShark {
+        bank: u8,
+        address: usize,
+        value: u8,
+    },
+}
+
+impl Code {
+    // Game Genie codes look like `ABC-DEF` or `ABC-DEF-GHI` and GameShark
+    // codes like `01VVLLHH`.
+    pub fn from_str(text: &str) -> Option<Code> {
+        let text = text.to_uppercase();
+        let parts: Vec<&str> = text.split('-').collect();
+        match parts.len() {
+            1 => parse_gameshark(parts[0]),
+            2 | 3 => parse_gamegenie(&parts),
+            _ => None,
+        }
+    }
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    text.chars().map(|c| c.to_digit(16).map(|x| x as u8)).collect()
+}
+
+// The address is scrambled as F^F, C, D, E and the compare value as G
+// and I rotated right by two and XORed with 0xBA. H is a check digit
+// the adapter ignores, and so do we.
+fn parse_gamegenie(parts: &[&str]) -> Option<Code> {
+    if parts.iter().any(|x| x.len() != 3) {
+        return None;
+    }
+    let digits = parse_hex(&parts.concat())?;
+    let address = ((digits[5] ^ 0xF) as usize) << 12 | (digits[2] as usize) << 8 |
+        (digits[3] as usize) << 4 | digits[4] as usize;
+    if address > 0x7FFF {
+        return None;
+    }
+    let compare = match digits.len() {
+        9 => Some((digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA),
+        _ => None,
+    };
+    Some(Code::GameGenie {
+        address: address,
+        value: digits[0] << 4 | digits[1],
+        compare: compare,
+    })
+}
+
+fn parse_gameshark(text: &str) -> Option<Code> {
+    if text.len() != 8 {
+        return None;
+    }
+    let digits = parse_hex(text)?;
+    let byte = |i: usize| digits[i * 2] << 4 | digits[i * 2 + 1];
+    let bank = byte(0);
+    let address = (byte(3) as usize) << 8 | byte(2) as usize;
+    match (bank, address) {
+        (0x00...0x01, 0xA000...0xDFFF) |
+        (0x90...0x97, 0xD000...0xDFFF) => {
+            Some(Code::GameShark {
+                bank: bank,
+                address: address,
+                value: byte(1),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    pub text: String,
+    pub name: String,
+    pub code: Code,
+    pub enabled: bool,
+}
+
+// The cheats in use for a ROM. They're kept in a plain-text file with
+// one code per line, followed by an optional name. A `!` before the code
+// turns it off:
+//
+//     # comments and blank lines are ignored
+//     010138C1 Infinite lives
+//     !00A-17B-C49 Start on the last level
+//
+// Saving writes the codes back without the comments.
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats { cheats: Vec::new() }
+    }
+
+    pub fn load(path: &Path) -> Result<Cheats, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Cheats::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn parse(text: &str) -> Result<Cheats, String> {
+        let mut cheats = Cheats::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (enabled, line) = match line.starts_with('!') {
+                true => (false, &line[1..]),
+                false => (true, line),
+            };
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let text = parts.next().unwrap();
+            let name = parts.next().unwrap_or("").trim();
+            match cheats.add(text, name) {
+                Some(index) => cheats.set_enabled(index, enabled),
+                None => return Err(format!("line {}: '{}' is not a cheat code", i + 1, text)),
+            };
+        }
+        Ok(cheats)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut text = String::new();
+        for cheat in &self.cheats {
+            if !cheat.enabled {
+                text.push('!');
+            }
+            text.push_str(&cheat.text);
+            if !cheat.name.is_empty() {
+                text.push(' ');
+                text.push_str(&cheat.name);
+            }
+            text.push('\n');
+        }
+        File::create(path)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    // Add an enabled code, returning its index, or None if it isn't valid.
+    pub fn add(&mut self, text: &str, name: &str) -> Option<usize> {
+        let code = Code::from_str(text)?;
+        self.cheats.push(Cheat {
+            text: text.to_uppercase(),
+            name: name.to_string(),
+            code: code,
+            enabled: true,
+        });
+        Some(self.cheats.len() - 1)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Cheat> {
+        match index < self.cheats.len() {
+            true => Some(self.cheats.remove(index)),
+            false => None,
+        }
+    }
+
+    // Returns false if there's no cheat at `index`.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.cheats.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The byte the CPU sees when reading `value` from ROM at `address`.
+    pub fn patch(&self, address: usize, value: u8) -> u8 {
+        for cheat in self.cheats.iter().filter(|x| x.enabled) {
+            if let Code::GameGenie { address: target, value: new, compare } = cheat.code {
+                if target == address && compare.map_or(true, |x| x == value) {
+                    return new;
+                }
+            }
+        }
+        value
+    }
+
+    // The RAM writes to make as each frame ends, as (bank, address, value).
+    pub fn pokes(&self) -> Vec<(u8, usize, u8)> {
+        self.cheats
+            .iter()
+            .filter(|x| x.enabled)
+            .filter_map(|cheat| match cheat.code {
+                Code::GameShark { bank, address, value } => Some((bank, address, value)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+
+#[test]
+fn test_parse_codes() {
+    assert_eq!(
+        Some(Code::GameGenie {
+            address: 0x45A8,
+            value: 0x00,
+            compare: Some(0x01),
+        }),
+        Code::from_str("005-a8b-e6e")
+    );
+    assert_eq!(
+        Some(Code::GameGenie {
+            address: 0x45A8,
+            value: 0x3E,
+            compare: None,
+        }),
+        Code::from_str("3E5-A8B")
+    );
+    assert_eq!(
+        Some(Code::GameShark {
+            bank: 0x01,
+            address: 0xC0D3,
+            value: 0x07,
+        }),
+        Code::from_str("0107D3C0")
+    );
+    // Addresses outside ROM or RAM, bad digits and bad lengths
+    assert_eq!(None, Code::from_str("005-A87-E6E"));
+    assert_eq!(None, Code::from_str("0107D380"));
+    assert_eq!(None, Code::from_str("0107D3CG"));
+    assert_eq!(None, Code::from_str("005A-8B-E6E"));
+
+    let mut cheats = Cheats::parse("# lives\n0107D3C0 Lives\n!005-A8B-E6E\n").unwrap();
+    assert_eq!(vec![(0x01, 0xC0D3, 0x07)], cheats.pokes());
+    assert_eq!(0x01, cheats.patch(0x45A8, 0x01));
+    cheats.set_enabled(1, true);
+    assert_eq!(0x00, cheats.patch(0x45A8, 0x01));
+    assert_eq!(0x02, cheats.patch(0x45A8, 0x02));
+    assert!(Cheats::parse("0107D3C0\nlives").is_err());
+}
@@ -1,40 +1,75 @@
-use gameboy::mmu::Mmu;
+use gameboy::mmu::{Interrupt, Mmu};
+use gameboy::model::Model;
 use gameboy::registers::Registers;
 use gameboy::operations::{get_operation, Operation};
+use gameboy::state::{StateError, StateReader, StateWriter};
 
 
 pub struct Cpu {
     pub regs: Registers,
     pub counter: u8, // Will count down until next instruction
+    model: Model,
 }
 
 
 impl Cpu {
-    pub fn new(registers: Registers) -> Cpu {
+    pub fn new(registers: Registers, model: Model) -> Cpu {
         Cpu {
             regs: registers,
             counter: 0,
+            model: model,
         }
     }
-    pub fn cycle(&mut self, mmu: &mut Mmu) {
+    // 16-bit increments and decrements put `value` on the address bus,
+    // which trips the OAM bug on the models that have it.
+    pub fn idu_address(&self, mmu: &mut Mmu, value: u16) {
+        if self.model.has_oam_bug() {
+            mmu.corrupt_oam(value as usize, false);
+        }
+    }
+    // The same for increments that happen while the CPU reads, as in POP
+    // and LD A, (HL+), which corrupt OAM differently.
+    pub fn idu_read(&self, mmu: &mut Mmu, value: u16) {
+        if self.model.has_oam_bug() {
+            mmu.corrupt_oam(value as usize, true);
+        }
+    }
+    pub fn cycle(&mut self, mmu: &mut Mmu) -> u32 {
         let operation = self.get_operation(mmu);
         (operation.func)(self, mmu);
-        mmu.step();
+        let cycles = mmu.step(operation.cycles as u32);
         self.handle_interrupts(mmu);
+        cycles
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.regs.save_state(state);
+        state.write_u8(self.counter);
     }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.regs.load_state(state)?;
+        self.counter = state.read_u8()?;
+        Ok(())
+    }
+    // Jump to the vector of the highest priority interrupt that is both
+    // requested and enabled.
     fn handle_interrupts(&mut self, mmu: &mut Mmu) {
-        let interrupts = mmu.read(0xFF0F);
-        if interrupts != 0 && mmu.ime {
-            let ie = mmu.read(0xFFFF);
-            let vblank_enabled = ie & 0b1 == 1;
-            if vblank_enabled {
-                let pc = self.regs.pc;
-                self.stack_push_u16(pc as u16, mmu);
-                self.regs.pc = 0x0040;
-                mmu.ime = false;
-            }
+        if !mmu.ime {
+            return;
         }
-
+        let pending = mmu.read(0xFF0F) & mmu.read(0xFFFF) & 0x1F;
+        let interrupt = match pending.trailing_zeros() {
+            0 => Interrupt::VBlank,
+            1 => Interrupt::Stat,
+            2 => Interrupt::Timer,
+            3 => Interrupt::Serial,
+            4 => Interrupt::Joypad,
+            _ => return,
+        };
+        mmu.clear_interrupt(interrupt);
+        let pc = self.regs.pc;
+        self.stack_push_u16(pc as u16, mmu);
+        self.regs.pc = 0x0040 + 8 * pending.trailing_zeros() as usize;
+        mmu.ime = false;
     }
     pub fn get_operation(&mut self, mmu: &mut Mmu) -> Operation {
         let first = self.immediate_u8_pc(mmu) as u16;
@@ -75,17 +110,25 @@ impl Cpu {
     //         mmu.write(sp, val);
     //         self.regs.sp -= 1;
     //     }
+    // Every step of SP goes through the IDU, so the stack can trip the
+    // OAM bug too.
     pub fn stack_pop_u16(&mut self, mmu: &mut Mmu) -> u16 {
+        let sp = self.regs.sp as u16;
+        self.idu_read(mmu, sp);
         self.regs.sp += 1;
         let sp = self.regs.sp as usize;
         let ret = mmu.read_u16(sp);
+        self.idu_read(mmu, sp as u16);
         self.regs.sp += 1;
         ret
     }
     pub fn stack_push_u16(&mut self, val: u16, mmu: &mut Mmu) {
+        let sp = self.regs.sp as u16;
+        self.idu_address(mmu, sp);
         self.regs.sp -= 1;
         let sp = self.regs.sp as usize;
         mmu.write_u16(sp, val);
+        self.idu_address(mmu, sp as u16);
         self.regs.sp -= 1;
     }
 }
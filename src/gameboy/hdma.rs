@@ -0,0 +1,87 @@
+use gameboy::state::{StateError, StateReader, StateWriter};
+
+
+pub const BLOCK_SIZE: usize = 0x10;
+
+
+// The CGB's VRAM DMA registers, HDMA1-HDMA5. A general purpose transfer
+// copies every block at once while the CPU waits; an HBlank transfer
+// copies one block each HBlank. The Mmu does the copying.
+pub struct Hdma {
+    source: usize, // FF51-FF52
+    // Offset into VRAM
+    destination: usize, // FF53-FF54
+    // Blocks left less one, as HDMA5 reads while copying
+    length: u8,
+    hblank: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            destination: 0,
+            length: 0x7F,
+            hblank: false,
+        }
+    }
+    // Bit 7 of HDMA5 reads high once nothing is being copied, which
+    // leaves 0xFF after a transfer finishes.
+    pub fn read_u8(&self, address: usize) -> u8 {
+        match address {
+            0xFF51...0xFF54 => 0xFF,
+            0xFF55 => (!self.hblank as u8) << 7 | self.length,
+            _ => panic!("{:04X} is not a valid Hdma-mapped address.", address),
+        }
+    }
+    // Returns the number of blocks to copy right away for a general
+    // purpose transfer.
+    pub fn write_u8(&mut self, address: usize, value: u8) -> Option<usize> {
+        match address {
+            0xFF51 => self.source = (value as usize) << 8 | self.source & 0xF0,
+            0xFF52 => self.source = self.source & 0xFF00 | (value & 0xF0) as usize,
+            0xFF53 => self.destination = ((value & 0x1F) as usize) << 8 | self.destination & 0xF0,
+            0xFF54 => self.destination = self.destination & 0x1F00 | (value & 0xF0) as usize,
+            // Clearing bit 7 during an HBlank transfer cancels it.
+            0xFF55 if self.hblank && value & 0x80 == 0 => self.hblank = false,
+            0xFF55 => {
+                self.length = value & 0x7F;
+                if value & 0x80 == 0 {
+                    return Some(self.length as usize + 1);
+                }
+                self.hblank = true;
+            }
+            _ => panic!("{:04X} is not a valid Hdma-mapped address.", address),
+        }
+        None
+    }
+    pub fn hblank_pending(&self) -> bool {
+        self.hblank
+    }
+    // The source and VRAM destination of the next block, moving past it.
+    pub fn next_block(&mut self) -> (usize, usize) {
+        let block = (self.source, 0x8000 | self.destination);
+        self.source = (self.source + BLOCK_SIZE) & 0xFFFF;
+        self.destination = (self.destination + BLOCK_SIZE) & 0x1FFF;
+        if self.length == 0 {
+            self.length = 0x7F;
+            self.hblank = false;
+        } else {
+            self.length -= 1;
+        }
+        block
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.source as u16);
+        state.write_u16(self.destination as u16);
+        state.write_u8(self.length);
+        state.write_bool(self.hblank);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.read_u16()? as usize & 0xFFF0;
+        self.destination = state.read_u16()? as usize & 0x1FF0;
+        self.length = state.read_u8()? & 0x7F;
+        self.hblank = state.read_bool()?;
+        Ok(())
+    }
+}
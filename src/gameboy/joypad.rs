@@ -0,0 +1,153 @@
+use gameboy::state::{StateError, StateReader, StateWriter};
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // Position of the button in a button bitmask; the low nibble holds
+    // the directions and the high nibble the action buttons, matching
+    // the two rows of P1.
+    pub fn mask(&self) -> u8 {
+        match *self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+    pub fn from_str(name: &str) -> Option<Button> {
+        match &*name.to_uppercase() {
+            "RIGHT" => Some(Button::Right),
+            "LEFT" => Some(Button::Left),
+            "UP" => Some(Button::Up),
+            "DOWN" => Some(Button::Down),
+            "A" => Some(Button::A),
+            "B" => Some(Button::B),
+            "SELECT" => Some(Button::Select),
+            "START" => Some(Button::Start),
+            _ => None,
+        }
+    }
+}
+
+pub struct Joypad {
+    // Buttons held on each of the SGB's four joypads
+    pressed: [u8; 4],
+    // P1 bits 4 and 5; a cleared bit selects that row of buttons
+    select: u8,
+    // The SGB's MLT_REQ reads more than one joypad, moving on to the
+    // next each time P15 goes high.
+    players: u8,
+    player: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            pressed: [0; 4],
+            select: 0x30,
+            players: 1,
+            player: 0,
+        }
+    }
+    pub fn set_players(&mut self, players: u8) {
+        self.players = players;
+        self.player = 0;
+    }
+    // Buttons for the first joypad, the only one outside MLT_REQ.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.pressed[0] = buttons;
+    }
+    pub fn buttons(&self) -> u8 {
+        self.pressed[0]
+    }
+    // Buttons for joypad `player`, counting from 0.
+    pub fn set_player_buttons(&mut self, player: usize, buttons: u8) {
+        self.pressed[player] = buttons;
+    }
+    // With neither row selected during MLT_REQ, the low nibble reads
+    // 0xF less the current joypad's number.
+    pub fn read_u8(&self) -> u8 {
+        if self.players > 1 && self.select == 0x30 {
+            return 0xF0 | (0x0F - self.player);
+        }
+        let pressed = self.pressed[self.player as usize];
+        let mut lines = 0;
+        if self.select & 0x10 == 0 {
+            lines |= pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            lines |= pressed >> 4;
+        }
+        0xC0 | self.select | (!lines & 0x0F)
+    }
+    pub fn write_u8(&mut self, byte: u8) {
+        if self.players > 1 && self.select & 0x20 == 0 && byte & 0x20 != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+        self.select = byte & 0x30;
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.pressed);
+        state.write_u8(self.select);
+        state.write_u8(self.players);
+        state.write_u8(self.player);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.pressed)?;
+        self.select = state.read_u8()? & 0x30;
+        self.players = state.read_u8()?;
+        self.player = state.read_u8()?;
+        if self.players == 0 || self.players > 4 || self.player >= self.players {
+            return Err(StateError::Invalid("joypad player"));
+        }
+        Ok(())
+    }
+}
+
+
+#[test]
+fn test_joypad_rows() {
+    let mut joypad = Joypad::new();
+    joypad.set_buttons(Button::Down.mask() | Button::Start.mask());
+    assert_eq!(0xFF, joypad.read_u8());
+    joypad.write_u8(0x20);
+    assert_eq!(0xE7, joypad.read_u8());
+    joypad.write_u8(0x10);
+    assert_eq!(0xD7, joypad.read_u8());
+}
+
+#[test]
+fn test_two_players() {
+    let mut joypad = Joypad::new();
+    joypad.set_players(2);
+    joypad.set_buttons(Button::A.mask());
+    joypad.set_player_buttons(1, Button::Down.mask());
+    joypad.write_u8(0x10);
+    assert_eq!(0xDE, joypad.read_u8());
+    // P15 going high moves on to the second joypad.
+    joypad.write_u8(0x30);
+    assert_eq!(0xFE, joypad.read_u8());
+    joypad.write_u8(0x20);
+    assert_eq!(0xE7, joypad.read_u8());
+    joypad.write_u8(0x10);
+    assert_eq!(0xDF, joypad.read_u8());
+    joypad.write_u8(0x30);
+    assert_eq!(0xFF, joypad.read_u8());
+    joypad.write_u8(0x10);
+    assert_eq!(0xDE, joypad.read_u8());
+}
@@ -1,6 +1,18 @@
+use std::mem;
+
+use gameboy::apu::Apu;
+use gameboy::cheats::Cheats;
 use gameboy::rom::Rom;
+use gameboy::hdma::{Hdma, BLOCK_SIZE};
 use gameboy::ppu::Ppu;
+use gameboy::joypad::Joypad;
+use gameboy::model::Model;
+use gameboy::serial::Serial;
+use gameboy::state::{StateError, StateReader, StateWriter};
+
 
+// VRAM DMA copies a block every 8 machine cycles of normal speed.
+const HDMA_BLOCK_CYCLES: u32 = 32;
 
 const BOOT_ROM: [u8; 0x100] = [
     0x31,
@@ -261,35 +273,73 @@ const BOOT_ROM: [u8; 0x100] = [
     0x50,
 ];
 
+// Bits of IF and IE, in priority order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interrupt {
+    VBlank = 0x01,
+    Stat = 0x02,
+    Timer = 0x04,
+    Serial = 0x08,
+    Joypad = 0x10,
+}
+
 pub struct Mmu {
     rom: Rom,
     pub ppu: Ppu,
+    pub apu: Apu,
+    pub joypad: Joypad,
+    // Running a CGB game with the CGB's extra hardware
+    cgb: bool,
     bios: Box<[u8]>,
-    sram: Box<[u8]>,
+    // Eight 4KB banks on the CGB, two on the DMG. The first is always at
+    // C000 and SVBK picks which is at D000.
     wram: Box<[u8]>,
+    wram_bank: usize, // FF70
     echo: Box<[u8]>,
     hram: Box<[u8]>,
     io: Box<[u8]>,
     in_bios: bool,
     ie: u8,
     pub ime: bool,
+    pub serial: Serial,
+    // KEY1: the CPU runs twice as fast once STOP switches speed after
+    // bit 0 has been set.
+    double_speed: bool,
+    speed_switch: bool,
+    hdma: Hdma,
+    // Normal speed cycles the CPU is held up for by VRAM DMA, passed on
+    // by the next step.
+    stall_cycles: u32,
+    pub cheats: Cheats,
 }
 
 
 impl Mmu {
-    pub fn new(rom: Rom) -> Mmu {
+    // CGB mode needs both the hardware and a game that supports it.
+    pub fn new(rom: Rom, model: Model) -> Mmu {
+        let cgb = model.is_cgb() && rom.supports_cgb();
+        let wram_size = if cgb { 0x8000 } else { 0x2000 };
         Mmu {
             rom: rom,
-            ppu: Ppu::new(),
+            ppu: Ppu::new(model, cgb),
+            apu: Apu::new(model),
+            joypad: Joypad::new(),
+            cgb: cgb,
             bios: Box::new(BOOT_ROM),
-            sram: Box::new([0; 0x2000]),
-            wram: Box::new([0; 0x2000]),
+            wram: vec![0; wram_size].into_boxed_slice(),
+            wram_bank: 1,
             echo: Box::new([0; 0x2000]),
             hram: Box::new([0; 0x80]),
             io: Box::new([0; 0x80]),
             in_bios: true,
             ie: 0,
             ime: false,
+            serial: Serial::new(),
+            double_speed: false,
+            speed_switch: false,
+            hdma: Hdma::new(),
+            stall_cycles: 0,
+            cheats: Cheats::new(),
         }
     }
     //    fn map_location(&self, address: usize) -> MemoryMap {
@@ -332,27 +382,99 @@ impl Mmu {
     //        InterruptEnableFlags
     //    }
     //
+    pub fn set_boot_rom(&mut self, data: Vec<u8>) {
+        self.bios = data.into_boxed_slice();
+    }
+    pub fn skip_boot_rom(&mut self) {
+        self.in_bios = false;
+        self.write(0xFF40, 0x91);
+        self.write(0xFF47, 0xFC);
+        self.write(0xFF48, 0xFF);
+        self.write(0xFF49, 0xFF);
+    }
+    pub fn sram(&self) -> &[u8] {
+        self.rom.ram()
+    }
+    pub fn load_sram(&mut self, data: &[u8]) {
+        let ram = self.rom.ram_mut();
+        let len = data.len().min(ram.len());
+        ram[..len].copy_from_slice(&data[..len]);
+    }
+    pub fn rom(&self) -> &Rom {
+        &self.rom
+    }
+    pub fn rom_mut(&mut self) -> &mut Rom {
+        &mut self.rom
+    }
+    pub fn wram(&self) -> &[u8] {
+        &self.wram
+    }
+    pub fn wram_mut(&mut self) -> &mut [u8] {
+        &mut self.wram
+    }
+    pub fn hram(&self) -> &[u8] {
+        &self.hram[..0x7F]
+    }
+    pub fn hram_mut(&mut self) -> &mut [u8] {
+        &mut self.hram[..0x7F]
+    }
+    // Read an I/O register (0xFF00-0xFF7F) without tripping over the
+    // holes in the map, for dumping the whole block at once.
+    pub fn read_io(&self, address: usize) -> u8 {
+        match address {
+            0xFF00...0xFF4B | 0xFF4D | 0xFF4F | 0xFF51...0xFF56 | 0xFF68...0xFF6C | 0xFF70 |
+            0xFF72...0xFF77 => self.read(address),
+            0xFF50 => !self.in_bios as u8,
+            _ => 0xFF,
+        }
+    }
+    pub fn write_io(&mut self, address: usize, byte: u8) {
+        match address {
+            0xFF00...0xFF4B | 0xFF4D | 0xFF4F | 0xFF51...0xFF56 | 0xFF68...0xFF6C | 0xFF70 |
+            0xFF72...0xFF77 => self.write(address, byte),
+            0xFF50 => self.in_bios = byte & 1 == 0,
+            _ => {}
+        }
+    }
     pub fn read(&self, address: usize) -> u8 {
         match address {
             0xFF0F => self.read_interrupts(),
             0x0000...0x00FF => {
                 match self.in_bios {
                     true => self.bios[address],
-                    false => self.rom.read(address),
+                    false => self.read_rom(address),
                 }
             }
-            0x0000...0x7FFF => self.rom.read(address), // Cartridge
+            // The rest of a CGB boot ROM, around the cartridge header
+            0x0200...0x08FF if self.in_bios && address < self.bios.len() => self.bios[address],
+            0x0000...0x7FFF => self.read_rom(address), // Cartridge
             0x8000...0x9FFF => self.ppu.read_u8(address),  // Tile Maps
-            0xA000...0xBFFF => self.sram[address - 0xA000],
-            0xC000...0xDFFF => self.wram[address - 0xC000],
-            0xE000...0xFDFF => self.wram[address - 0xE000], // ECHO
+            0xA000...0xBFFF => self.rom.read(address),
+            0xC000...0xDFFF => self.wram[self.wram_index(address)],
+            0xE000...0xFDFF => self.wram[self.wram_index(address - 0x2000)], // ECHO
             0xFE00...0xFE9F => self.ppu.read_u8(address), // OAM
             0xFEA0...0xFEFF => {
                 println!("Unused ram Access (Read)");
                 0
             }
+            0xFF00 => self.joypad.read_u8(),
+            0xFF01...0xFF02 => self.serial.read_u8(address),
+            0xFF10...0xFF3F => self.apu.read_u8(address),
             0xFF00...0xFF3F => self.io[address - 0xFF00],
             0xFF40...0xFF4B => self.ppu.read_u8(address),
+            0xFF4D if self.cgb => (self.double_speed as u8) << 7 | self.speed_switch as u8 | 0x7E,
+            0xFF4F | 0xFF68...0xFF6B => self.ppu.read_u8(address),
+            0xFF51...0xFF55 if self.cgb => self.hdma.read_u8(address),
+            0xFF70 if self.cgb => self.wram_bank as u8 | 0xF8,
+            // The infrared port never receives anything, so bit 1 reads high.
+            0xFF56 if self.cgb => self.io[0x56] & 0xC1 | 0x3E,
+            0xFF6C if self.cgb => self.io[0x6C] & 0x01 | 0xFE,
+            0xFF72...0xFF74 if self.cgb => self.io[address - 0xFF00],
+            0xFF75 if self.cgb => self.io[0x75] & 0x70 | 0x8F,
+            // PCM12 and PCM34 read the channels' output levels, which the
+            // Apu doesn't expose.
+            0xFF76...0xFF77 if self.cgb => 0x00,
+            0xFF4D | 0xFF51...0xFF56 | 0xFF6C | 0xFF70 | 0xFF72...0xFF77 => 0xFF,
             0xFF80...0xFFFE => self.hram[address - 0xFF80],
             0xFFFF => self.ie,
             _ => panic!("{:04X} is an unused address.", address),
@@ -362,21 +484,116 @@ impl Mmu {
         match address {
             0x0000...0x7FFF => self.rom.write(address, byte),
             0x8000...0x9FFF => self.ppu.write_u8(address, byte),
-            0xA000...0xBFFF => self.sram[address - 0xA000] = byte,
-            0xC000...0xDFFF => self.wram[address - 0xC000] = byte,
-            0xE000...0xFDFF => self.wram[address - 0xE000] = byte,
+            0xA000...0xBFFF => self.rom.write(address, byte),
+            0xC000...0xDFFF => self.wram[self.wram_index(address)] = byte,
+            0xE000...0xFDFF => self.wram[self.wram_index(address - 0x2000)] = byte,
             0xFE00...0xFE9F => self.ppu.write_u8(address, byte),
             0xFEA0...0xFEFF => println!("Unused ram Access (Write)"),
+            0xFF00 => {
+                self.joypad.write_u8(byte);
+                if let Some(players) = self.ppu.write_sgb(byte) {
+                    self.joypad.set_players(players as u8);
+                }
+            }
+            0xFF01...0xFF02 => self.serial.write_u8(address, byte),
+            0xFF10...0xFF3F => self.apu.write_u8(address, byte),
             0xFF00...0xFF3F => self.io[address - 0xFF00] = byte,
+            0xFF46 => {
+                self.ppu.write_u8(address, byte);
+                self.oam_dma(byte);
+            }
             0xFF40...0xFF4B => self.ppu.write_u8(address, byte),
+            0xFF4D if self.cgb => self.speed_switch = byte & 1 != 0,
+            0xFF4F | 0xFF68...0xFF6B => self.ppu.write_u8(address, byte),
+            0xFF51...0xFF55 if self.cgb => {
+                if let Some(blocks) = self.hdma.write_u8(address, byte) {
+                    for _ in 0..blocks {
+                        self.hdma_block();
+                    }
+                }
+            }
+            // Bank 0 can't be picked for D000, so asking for it gets bank 1.
+            0xFF70 if self.cgb => self.wram_bank = (byte as usize & 0x07).max(1),
+            0xFF56 | 0xFF6C | 0xFF72...0xFF75 if self.cgb => self.io[address - 0xFF00] = byte,
             0xFF80...0xFFFE => self.hram[address - 0xFF80] = byte,
             0xFFFF => self.ie = byte,
             _ => {}
         }
     }
+    // Game Genie codes patch what the CPU reads, not the ROM itself.
+    fn read_rom(&self, address: usize) -> u8 {
+        self.cheats.patch(address, self.rom.read(address))
+    }
+    // Make the GameShark's RAM writes, as it does once a frame.
+    pub fn apply_cheats(&mut self) {
+        for (bank, address, value) in self.cheats.pokes() {
+            match bank {
+                0x90...0x97 if self.cgb => {
+                    let bank = (bank as usize & 0x07).max(1);
+                    self.wram[bank * 0x1000 + address - 0xD000] = value;
+                }
+                _ => self.write(address, value),
+            }
+        }
+    }
+    fn wram_index(&self, address: usize) -> usize {
+        match address {
+            0xC000...0xCFFF => address - 0xC000,
+            _ => self.wram_bank * 0x1000 + address - 0xD000,
+        }
+    }
+    // Copy 160 bytes from `page` * 0x100 to OAM. This happens all at
+    // once rather than over the 640 cycles the real transfer takes.
+    fn oam_dma(&mut self, page: u8) {
+        let source = (page as usize) << 8;
+        for i in 0..0xA0 {
+            let byte = match source + i {
+                address @ 0xE000...0xFFFF => self.read(address - 0x2000),
+                address => self.read(address),
+            };
+            self.ppu.oam_mut()[i] = byte;
+        }
+    }
+    // Copy the next 16 bytes of a VRAM DMA into the current VRAM bank,
+    // holding up the CPU for 32 cycles at either speed.
+    fn hdma_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+        for i in 0..BLOCK_SIZE {
+            let byte = match source + i {
+                address @ 0xE000...0xFFFF => self.read(address - 0x2000),
+                address => self.read(address),
+            };
+            self.ppu.write_u8(destination + i, byte);
+        }
+        self.stall_cycles += HDMA_BLOCK_CYCLES;
+    }
+    // An address the CPU put on the bus outside a memory access, which
+    // corrupts OAM while the PPU is searching it. `read` is set when the
+    // CPU also reads in that cycle.
+    pub fn corrupt_oam(&mut self, address: usize, read: bool) {
+        match (address, read) {
+            (0xFE00...0xFEFF, false) => self.ppu.corrupt_oam(),
+            (0xFE00...0xFEFF, true) => self.ppu.corrupt_oam_read(),
+            _ => {}
+        }
+    }
+    // STOP switches CPU speed if KEY1 asked for it.
+    pub fn switch_speed(&mut self) {
+        if self.speed_switch {
+            self.double_speed = !self.double_speed;
+            self.speed_switch = false;
+        }
+    }
+    // Interrupts stay latched in IF until handled or cleared. The top
+    // three bits are unused and read high.
     fn read_interrupts(&self) -> u8 {
-        let interrupts = self.ppu.stat.vblank_int_enable as u8;
-        interrupts
+        self.io[0x0F] | 0xE0
+    }
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.io[0x0F] |= interrupt as u8;
+    }
+    pub fn clear_interrupt(&mut self, interrupt: Interrupt) {
+        self.io[0x0F] &= !(interrupt as u8);
     }
     pub fn read_range(&self, low: usize, high: usize) -> Vec<u8> {
         (low..high).into_iter().map(|x| self.read(x)).collect()
@@ -392,7 +609,205 @@ impl Mmu {
         self.write(address, first);
         self.write(address + 1, second);
     }
-    pub fn step(&mut self) {
-        self.ppu.step();
+    pub fn rom_checksum(&self) -> u32 {
+        self.rom.checksum()
+    }
+    // Timer registers currently live in `io`, so they are covered by
+    // saving it wholesale.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.in_bios);
+        state.write_bool(self.ime);
+        state.write_u8(self.ie);
+        self.rom.save_state(state);
+        state.write_bytes(&self.wram);
+        state.write_u8(self.wram_bank as u8);
+        state.write_bool(self.double_speed);
+        state.write_bool(self.speed_switch);
+        self.hdma.save_state(state);
+        state.write_u32(self.stall_cycles);
+        state.write_bytes(&self.hram);
+        state.write_bytes(&self.io);
+        self.joypad.save_state(state);
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        self.serial.save_state(state);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.in_bios = state.read_bool()?;
+        self.ime = state.read_bool()?;
+        self.ie = state.read_u8()?;
+        self.rom.load_state(state)?;
+        state.read_into(&mut self.wram)?;
+        self.wram_bank = state.read_u8()? as usize;
+        if self.wram_bank == 0 || self.wram_bank * 0x1000 >= self.wram.len() {
+            return Err(StateError::Invalid("WRAM bank"));
+        }
+        self.double_speed = state.read_bool()?;
+        self.speed_switch = state.read_bool()?;
+        self.hdma.load_state(state)?;
+        self.stall_cycles = state.read_u32()?;
+        state.read_into(&mut self.hram)?;
+        state.read_into(&mut self.io)?;
+        self.joypad.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.serial.load_state(state)
+    }
+    pub fn has_battery(&self) -> bool {
+        self.rom.has_battery()
+    }
+    // Run everything besides the CPU for the time `cycles` CPU cycles
+    // take plus any VRAM DMA stall, giving back that time in normal
+    // speed cycles.
+    pub fn step(&mut self, cycles: u32) -> u32 {
+        let cycles = if self.double_speed { cycles / 2 } else { cycles };
+        let cycles = cycles + mem::replace(&mut self.stall_cycles, 0);
+        if self.ppu.step(cycles) && self.hdma.hblank_pending() {
+            self.hdma_block();
+        }
+        if self.ppu.take_vblank() {
+            self.request_interrupt(Interrupt::VBlank);
+        }
+        self.apu.step(cycles);
+        if self.serial.step(cycles) {
+            self.request_interrupt(Interrupt::Serial);
+        }
+        cycles
+    }
+}
+
+
+#[test]
+fn test_oam_dma() {
+    let mut mmu = Mmu::new(Rom::from_bytes(vec![0; 0x8000], "dma").unwrap(), Model::Dmg);
+    for i in 0..0xA0 {
+        mmu.write(0xC100 + i, i as u8);
+    }
+    mmu.write(0xFF46, 0xC1);
+    assert_eq!(0xC1, mmu.read(0xFF46));
+    assert_eq!(0x9F, mmu.read(0xFE9F));
+    // Pages from E0 on read work RAM, as echo RAM would.
+    mmu.write(0xFF46, 0xE1);
+    assert_eq!(0x10, mmu.read(0xFE10));
+}
+
+
+#[test]
+fn test_cgb_banks_and_speed() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x143] = 0x80;
+    let mut mmu = Mmu::new(Rom::from_bytes(rom, "cgb").unwrap(), Model::Cgb);
+    mmu.write(0xFF70, 3);
+    mmu.write(0xD000, 0x33);
+    mmu.write(0xFF70, 0);
+    assert_eq!(0xF9, mmu.read(0xFF70));
+    mmu.write(0xD000, 0x11);
+    mmu.write(0xFF70, 3);
+    assert_eq!(0x33, mmu.read(0xF000));
+
+    mmu.write(0xFF4D, 0x01);
+    assert_eq!(0x7F, mmu.read(0xFF4D));
+    assert_eq!(8, mmu.step(8));
+    mmu.switch_speed();
+    assert_eq!(0xFE, mmu.read(0xFF4D));
+    assert_eq!(4, mmu.step(8));
+}
+
+#[test]
+fn test_vram_dma() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x143] = 0x80;
+    for (i, byte) in rom[0x1200..0x1240].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let mut mmu = Mmu::new(Rom::from_bytes(rom, "cgb").unwrap(), Model::Cgb);
+    mmu.in_bios = false;
+    // A general purpose copy of two blocks from 1205 to 9F05, which
+    // round down to 1200 and 8010.
+    mmu.write(0xFF51, 0x12);
+    mmu.write(0xFF52, 0x05);
+    mmu.write(0xFF53, 0xE0);
+    mmu.write(0xFF54, 0x15);
+    mmu.write(0xFF55, 0x01);
+    assert_eq!(0xFF, mmu.read(0xFF55));
+    assert_eq!(0x00, mmu.read(0x8010));
+    assert_eq!(0x1F, mmu.read(0x802F));
+    assert_eq!(4 + 64, mmu.step(4));
+
+    // An HBlank copy of three blocks carries on from there, one block
+    // a line, until cancelled.
+    mmu.write(0xFF55, 0x82);
+    assert_eq!(0x02, mmu.read(0xFF55));
+    for _ in 0..456 / 4 {
+        mmu.step(4);
+    }
+    assert_eq!(0x01, mmu.read(0xFF55));
+    assert_eq!(0x2F, mmu.read(0x803F));
+    assert_eq!(0x00, mmu.read(0x8040));
+    mmu.write(0xFF55, 0x00);
+    assert_eq!(0x81, mmu.read(0xFF55));
+    for _ in 0..456 / 4 {
+        mmu.step(4);
+    }
+    assert_eq!(0x00, mmu.read(0x8040));
+}
+
+#[test]
+fn test_cheats() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x143] = 0x80;
+    rom[0x45A8] = 0x01;
+    let mut mmu = Mmu::new(Rom::from_bytes(rom, "cgb").unwrap(), Model::Cgb);
+    mmu.in_bios = false;
+    mmu.cheats.add("005-A8B-E6E", "").unwrap();
+    mmu.cheats.add("0107D3C0", "").unwrap();
+    mmu.cheats.add("9209D3D0", "").unwrap();
+    assert_eq!(0x00, mmu.read(0x45A8));
+    assert_eq!(0x01, mmu.rom().read(0x45A8));
+    mmu.apply_cheats();
+    assert_eq!(0x07, mmu.read(0xC0D3));
+    assert_eq!(0x00, mmu.read(0xD0D3));
+    mmu.write(0xFF70, 0x02);
+    assert_eq!(0x09, mmu.read(0xD0D3));
+}
+
+
+#[test]
+fn test_cgb_io_reads() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x143] = 0x80;
+    let mut mmu = Mmu::new(Rom::from_bytes(rom.clone(), "cgb").unwrap(), Model::Cgb);
+    mmu.write(0xFF56, 0xC1);
+    mmu.write(0xFF72, 0x12);
+    mmu.write(0xFF75, 0xFF);
+    assert_eq!(0xFF, mmu.read(0xFF56));
+    assert_eq!(0xFE, mmu.read(0xFF6C));
+    assert_eq!(0x12, mmu.read_io(0xFF72));
+    assert_eq!(0xFF, mmu.read(0xFF75));
+    assert_eq!(0x00, mmu.read(0xFF76));
+
+    rom[0x143] = 0x00;
+    let mmu = Mmu::new(Rom::from_bytes(rom, "dmg").unwrap(), Model::Dmg);
+    for address in [0xFF56, 0xFF6C, 0xFF72, 0xFF77].iter() {
+        assert_eq!(0xFF, mmu.read(*address));
+    }
+}
+
+#[test]
+fn test_vblank_interrupt() {
+    let mut mmu = Mmu::new(Rom::from_bytes(vec![0; 0x8000], "vblank").unwrap(), Model::Dmg);
+    // STAT's mode 1 enable bit has nothing to do with IF
+    mmu.write(0xFF41, 0x10);
+    mmu.write(0xFF0F, 0x00);
+    while mmu.read(0xFF44) != 144 {
+        assert_eq!(0xE0, mmu.read(0xFF0F));
+        mmu.step(4);
+    }
+    assert_eq!(0xE1, mmu.read(0xFF0F));
+    // Once handled, it isn't raised again for the rest of VBlank.
+    mmu.clear_interrupt(Interrupt::VBlank);
+    while mmu.read(0xFF44) != 0 {
+        mmu.step(4);
+        assert_eq!(0xE0, mmu.read(0xFF0F));
     }
 }
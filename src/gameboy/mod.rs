@@ -1,35 +1,181 @@
 pub mod rom;
+pub mod apu;
+pub mod bess;
+pub mod cheats;
 mod cpu;
+pub mod joypad;
+mod hdma;
 mod mmu;
-mod ppu;
+pub mod model;
+pub mod ppu;
 pub mod operations;
 mod registers;
-
-use std::thread::sleep;
-use std::time::Duration;
+pub mod serial;
+mod sgb;
+pub mod state;
 
 use self::rom::Rom;
 use self::cpu::Cpu;
 use self::registers::Registers;
 use self::mmu::Mmu;
+use self::model::Model;
+use self::state::{StateError, StateReader, StateWriter};
+
+// The DMG runs at 4194304 Hz and draws one frame every 70224 cycles,
+// which works out to roughly 59.73 frames per second. Cycles are counted
+// at this speed even when the CGB's CPU runs at double speed.
+pub const CLOCK_SPEED: u32 = 4_194_304;
+pub const CYCLES_PER_FRAME: u32 = 70_224;
 
 pub struct Gameboy {
     pub mmu: Mmu,
     pub cpu: Cpu,
+    model: Model,
+    frame_cycles: u32,
+    frames: u64,
 }
 
 impl Gameboy {
-    pub fn new(rom: Rom) -> Gameboy {
+    pub fn new(rom: Rom, model: Model) -> Gameboy {
         let registers = Registers::new();
         let gb = Gameboy {
-            cpu: Cpu::new(registers),
-            mmu: Mmu::new(rom),
+            cpu: Cpu::new(registers, model),
+            mmu: Mmu::new(rom, model),
+            model: model,
+            frame_cycles: 0,
+            frames: 0,
         };
         gb
     }
 
-    pub fn step(&mut self) {
-        self.cpu.cycle(&mut self.mmu);
-        sleep(Duration::from_millis(0));
+    pub fn set_boot_rom(&mut self, data: Vec<u8>) {
+        self.mmu.set_boot_rom(data);
+    }
+
+    // Start at the cartridge entry point with the state the boot ROM
+    // would have left behind.
+    pub fn skip_boot_rom(&mut self) {
+        self.cpu.regs = Registers::after_boot(self.model, self.mmu.rom());
+        self.mmu.skip_boot_rom();
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    // Hold down the buttons in a bitmask of `Button::mask` values.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.mmu.joypad.set_buttons(buttons);
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.mmu.joypad.buttons()
+    }
+
+    // Hold down buttons on one of the SGB's other joypads, counting the
+    // first as 0.
+    pub fn set_player_buttons(&mut self, player: usize, buttons: u8) {
+        self.mmu.joypad.set_player_buttons(player, buttons);
+    }
+
+    // Number of whole frames emulated since power-on.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn step(&mut self) -> u32 {
+        let cycles = self.cpu.cycle(&mut self.mmu);
+        self.frame_cycles += cycles;
+        if self.frame_cycles >= CYCLES_PER_FRAME {
+            self.frame_cycles -= CYCLES_PER_FRAME;
+            self.frames += 1;
+            self.mmu.apply_cheats();
+        }
+        cycles
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_bytes(state::MAGIC);
+        state.write_u32(state::VERSION);
+        state.write_u32(self.mmu.rom_checksum());
+        state.write_u8(self.model.to_u8());
+        state.write_u32(self.frame_cycles);
+        state.write_u64(self.frames);
+        self.cpu.save_state(&mut state);
+        self.mmu.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    // Restore a state made by `save_state`. If the data turns out to be
+    // unusable part way through, the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        let result = self.restore(data);
+        if result.is_err() {
+            self.restore(&backup).unwrap();
+        }
+        result
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        let mut magic = [0; 4];
+        state.read_into(&mut magic)?;
+        if &magic != state::MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = state.read_u32()?;
+        if version != state::VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let checksum = state.read_u32()?;
+        if checksum != self.mmu.rom_checksum() {
+            return Err(StateError::RomMismatch {
+                expected: self.mmu.rom_checksum(),
+                found: checksum,
+            });
+        }
+        // The components' state is laid out differently for each model.
+        match Model::from_u8(state.read_u8()?) {
+            Some(model) if model != self.model => {
+                return Err(StateError::ModelMismatch {
+                    expected: self.model,
+                    found: model,
+                });
+            }
+            Some(_) => {}
+            None => return Err(StateError::Invalid("model")),
+        }
+        self.frame_cycles = state.read_u32()?;
+        self.frames = state.read_u64()?;
+        self.cpu.load_state(&mut state)?;
+        self.mmu.load_state(&mut state)?;
+        if !state.is_empty() && !bess::appended_at(data, state.position()) {
+            return Err(StateError::Invalid("length"));
+        }
+        Ok(())
+    }
+
+    pub fn run_frame(&mut self) {
+        let frame = self.frames;
+        while self.frames == frame {
+            self.step();
+        }
+    }
+}
+
+
+#[test]
+fn test_state_model() {
+    let data = vec![0; 0x8000];
+    let dmg = Gameboy::new(Rom::from_bytes(data.clone(), "model").unwrap(), Model::Dmg);
+    let mut cgb = Gameboy::new(Rom::from_bytes(data.clone(), "model").unwrap(), Model::Cgb);
+    match cgb.load_state(&dmg.save_state()) {
+        Err(StateError::ModelMismatch { expected: Model::Cgb, found: Model::Dmg }) => {}
+        other => panic!("loaded a DMG state on the CGB: {:?}", other),
     }
+    let mut mgb = Gameboy::new(Rom::from_bytes(data, "model").unwrap(), Model::Mgb);
+    assert!(mgb.load_state(&dmg.save_state()).is_err());
+    assert!(cgb.load_state(&cgb.save_state()).is_ok());
 }
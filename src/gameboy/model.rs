@@ -0,0 +1,94 @@
+use gameboy::rom::Rom;
+
+
+// The hardware being emulated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    Dmg,
+    // The Game Boy Pocket, which differs from the DMG mostly in A after
+    // booting.
+    Mgb,
+    Sgb,
+    Cgb,
+    // A Game Boy Advance running Game Boy games, which is a CGB that sets
+    // B bit 0 after booting.
+    Agb,
+}
+
+impl Model {
+    pub fn from_str(name: &str) -> Option<Model> {
+        match &*name.to_lowercase() {
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "cgb" => Some(Model::Cgb),
+            "agb" => Some(Model::Agb),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Model::Dmg => "DMG",
+            Model::Mgb => "MGB",
+            Model::Sgb => "SGB",
+            Model::Cgb => "CGB",
+            Model::Agb => "AGB",
+        }
+    }
+    // Number identifying the model in save states.
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            Model::Dmg => 0,
+            Model::Mgb => 1,
+            Model::Sgb => 2,
+            Model::Cgb => 3,
+            Model::Agb => 4,
+        }
+    }
+    pub fn from_u8(byte: u8) -> Option<Model> {
+        match byte {
+            0 => Some(Model::Dmg),
+            1 => Some(Model::Mgb),
+            2 => Some(Model::Sgb),
+            3 => Some(Model::Cgb),
+            4 => Some(Model::Agb),
+            _ => None,
+        }
+    }
+    // The model a cartridge's header says it makes the most of: the CGB
+    // for games using its hardware, the SGB for games with SGB features
+    // and the DMG for the rest.
+    pub fn for_rom(rom: &Rom) -> Model {
+        if rom.supports_cgb() {
+            Model::Cgb
+        } else if rom.supports_sgb() {
+            Model::Sgb
+        } else {
+            Model::Dmg
+        }
+    }
+    // Whether the machine has the CGB's hardware, which games without
+    // CGB support only get in the DMG compatibility mode.
+    pub fn is_cgb(&self) -> bool {
+        match *self {
+            Model::Cgb | Model::Agb => true,
+            _ => false,
+        }
+    }
+    // The DMG family corrupts OAM when 16-bit increments and decrements,
+    // including the ones in HL+/HL- loads and stack operations, put an
+    // address in FE00-FEFF on the bus while the PPU searches OAM.
+    pub fn has_oam_bug(&self) -> bool {
+        !self.is_cgb()
+    }
+    // Model code for BESS save states, whose first letter is the family.
+    pub fn bess_code(&self) -> &'static [u8; 4] {
+        match *self {
+            Model::Dmg => b"GD  ",
+            Model::Mgb => b"GM  ",
+            Model::Sgb => b"SN  ",
+            Model::Cgb => b"CC  ",
+            Model::Agb => b"CA  ",
+        }
+    }
+}
@@ -87,7 +87,7 @@ pub fn get_operation(code: u16) -> Operation {
                 }
                 0x10 => {
                     match lcode {
-                        0x00 => Operation::new(opx00, 2, "STOP 0", ValueMode::None),
+                        0x00 => Operation::new(opx10, 4, "STOP 0", ValueMode::None),
                         0x01 => Operation::new(opx11, 12, "LD DE, {}", ValueMode::D16),
                         0x02 => Operation::new(opx12, 8, "LD (DE), A", ValueMode::None),
                         0x03 => Operation::new(opx13, 8, "INC DE", ValueMode::None),
@@ -98,7 +98,7 @@ pub fn get_operation(code: u16) -> Operation {
                         0x08 => Operation::new(opx18, 8, "JR {}", ValueMode::R8),
                         0x09 => Operation::new(opx19, 8, "ADD HL, DE", ValueMode::None),
                         0x0A => Operation::new(opx1A, 8, "LD A, (DE)", ValueMode::None),
-                        0x0B => Operation::new(panic, 8, "DEC DE", ValueMode::None),
+                        0x0B => Operation::new(opx1B, 8, "DEC DE", ValueMode::None),
                         0x0C => Operation::new(opx1C, 4, "INC E", ValueMode::None),
                         0x0D => Operation::new(opx1D, 4, "DEC E", ValueMode::None),
                         0x0E => Operation::new(opx1E, 8, "LD E, {}", ValueMode::D8),
@@ -119,7 +119,7 @@ pub fn get_operation(code: u16) -> Operation {
                         0x08 => Operation::new(opx28, 12, "JR Z, {}", ValueMode::R8),
                         0x09 => Operation::new(opx29, 8, "ADD HL, HL", ValueMode::None),
                         0x0A => Operation::new(opx2A, 8, "LD A, (HL+)", ValueMode::None),
-                        0x0B => Operation::new(panic, 8, "DEC HL", ValueMode::None),
+                        0x0B => Operation::new(opx2B, 8, "DEC HL", ValueMode::None),
                         0x0C => Operation::new(opx2C, 4, "INC L", ValueMode::None),
                         0x0D => Operation::new(opx2D, 4, "DEC L", ValueMode::None),
                         0x0E => Operation::new(opx2E, 8, "LD L, {}", ValueMode::D8),
@@ -140,7 +140,7 @@ pub fn get_operation(code: u16) -> Operation {
                         0x08 => Operation::new(panic, 12, "JR C, r8", ValueMode::R8),
                         0x09 => Operation::new(opx39, 8, "ADD HL, SP", ValueMode::None),
                         0x0A => Operation::new(opx3A, 8, "LD A, (HL-)", ValueMode::None),
-                        0x0B => Operation::new(panic, 8, "DEC SP", ValueMode::None),
+                        0x0B => Operation::new(opx3B, 8, "DEC SP", ValueMode::None),
                         0x0C => Operation::new(opx3C, 4, "INC A", ValueMode::None),
                         0x0D => Operation::new(opx3D, 4, "DEC A", ValueMode::None),
                         0x0E => Operation::new(opx3E, 8, "LD A, {}", ValueMode::D8),
@@ -639,6 +639,13 @@ pub fn panic(cpu: &mut Cpu, mmu: &mut Mmu) {
 pub fn opx00(cpu: &mut Cpu, mmu: &mut Mmu) {
     /* NOP */
 }
+pub fn opx10(cpu: &mut Cpu, mmu: &mut Mmu) {
+    // STOP 0
+    // Skip the padding byte. Only the CGB speed switch is emulated, not
+    // stopping the clock until a button is pressed.
+    cpu.regs.pc += 1;
+    mmu.switch_speed();
+}
 pub fn opx01(cpu: &mut Cpu, mmu: &mut Mmu) {
     // LD BC, d16
     // Load d16 into the address specified at HL
@@ -653,6 +660,7 @@ pub fn opx02(cpu: &mut Cpu, mmu: &mut Mmu) {
 }
 pub fn opx03(cpu: &mut Cpu, mmu: &mut Mmu) {
     let val = cpu.regs.bc();
+    cpu.idu_address(mmu, val);
     cpu.regs.set_bc(val.wrapping_add(1));
 }
 pub fn opx04(cpu: &mut Cpu, mmu: &mut Mmu) {
@@ -690,6 +698,7 @@ pub fn opx0A(cpu: &mut Cpu, mmu: &mut Mmu) {
 }
 pub fn opx0B(cpu: &mut Cpu, mmu: &mut Mmu) {
     let bc = cpu.regs.bc();
+    cpu.idu_address(mmu, bc);
     cpu.regs.set_bc(bc.wrapping_sub(1))
 }
 pub fn opx0C(cpu: &mut Cpu, mmu: &mut Mmu) {
@@ -755,19 +764,24 @@ pub fn opx22(cpu: &mut Cpu, mmu: &mut Mmu) {
     // Increment HL
     let hl = cpu.regs.hl() as usize;
     mmu.write(hl, cpu.regs.a);
+    cpu.idu_address(mmu, hl as u16);
     cpu.regs.set_hl((hl + 1) as u16);
 }
 
 pub fn opx23(cpu: &mut Cpu, mmu: &mut Mmu) {
     // INC HL
     // Increment HL by one.
-    let new = cpu.regs.hl().wrapping_add(1);
+    let hl = cpu.regs.hl();
+    cpu.idu_address(mmu, hl);
+    let new = hl.wrapping_add(1);
     cpu.regs.set_hl(new);
 }
 pub fn opx13(cpu: &mut Cpu, mmu: &mut Mmu) {
     // INC DE
     // Increment DE by one.
-    let new = cpu.regs.de().wrapping_add(1);
+    let de = cpu.regs.de();
+    cpu.idu_address(mmu, de);
+    let new = de.wrapping_add(1);
     cpu.regs.set_de(new);
 }
 pub fn opx32(cpu: &mut Cpu, mmu: &mut Mmu) {
@@ -777,13 +791,30 @@ pub fn opx32(cpu: &mut Cpu, mmu: &mut Mmu) {
     let addr = cpu.regs.hl() as usize;
     let a = cpu.regs.a;
     mmu.write(addr, a);
+    cpu.idu_address(mmu, addr as u16);
     cpu.regs.set_hl((addr as u16).wrapping_sub(1));
     info!("\n\nH: 0b{:08b}\n", cpu.regs.h);
 }
 pub fn opx33(cpu: &mut Cpu, mmu: &mut Mmu) {
     let val = cpu.regs.sp;
+    cpu.idu_address(mmu, val as u16);
     cpu.regs.sp = val.wrapping_add(1)
 }
+pub fn opx1B(cpu: &mut Cpu, mmu: &mut Mmu) {
+    let de = cpu.regs.de();
+    cpu.idu_address(mmu, de);
+    cpu.regs.set_de(de.wrapping_sub(1))
+}
+pub fn opx2B(cpu: &mut Cpu, mmu: &mut Mmu) {
+    let hl = cpu.regs.hl();
+    cpu.idu_address(mmu, hl);
+    cpu.regs.set_hl(hl.wrapping_sub(1))
+}
+pub fn opx3B(cpu: &mut Cpu, mmu: &mut Mmu) {
+    let val = cpu.regs.sp as u16;
+    cpu.idu_address(mmu, val);
+    cpu.regs.sp = val.wrapping_sub(1) as usize
+}
 pub fn opx31(cpu: &mut Cpu, mmu: &mut Mmu) {
     // LD SP, d16
     // Load immediate 16-bit into Stack Pointer
@@ -908,7 +939,7 @@ pub fn opxFE(cpu: &mut Cpu, mmu: &mut Mmu) {
     let a = cpu.regs.a;
     let d8 = cpu.immediate_u8_pc(mmu);
     let hc = sub_hc_u8(a, d8);
-    cpu.regs.flags.z = a - d8 == 0;
+    cpu.regs.flags.z = a == d8;
     cpu.regs.flags.c = a < d8;
     cpu.regs.flags.n = true;
     cpu.regs.flags.h = hc;
@@ -952,9 +983,9 @@ fn rl_n(reg: &mut u8, flags: &mut FlagRegister) {
 
 fn bit_x_n(bit_no: usize, reg: &mut u8, flags: &mut FlagRegister) {
     // BIT x, n
-    // Clear the zero flag if bit x of register n == 1
+    // Set the zero flag if bit x of register n == 0
     // set N flag to 0 and H flag to 1
-    flags.z = reg.get_bit(bit_no) == 1;
+    flags.z = reg.get_bit(bit_no) == 0;
     info!("Z: {}", flags.z);
     flags.n = false;
     flags.h = true;
@@ -1580,12 +1611,14 @@ pub fn opx2A(cpu: &mut Cpu, mmu: &mut Mmu) {
     let addr = cpu.regs.hl();
     let hl = mmu.read(addr as usize);
     ld_x_y(&mut cpu.regs.a, hl);
+    cpu.idu_read(mmu, addr);
     cpu.regs.set_hl(addr.wrapping_add(1));
 }
 pub fn opx3A(cpu: &mut Cpu, mmu: &mut Mmu) {
     let addr = cpu.regs.hl();
     let hl = mmu.read(addr as usize);
     ld_x_y(&mut cpu.regs.a, hl);
+    cpu.idu_read(mmu, addr);
     cpu.regs.set_hl(addr.wrapping_sub(1));
 }
 pub fn opx12(cpu: &mut Cpu, mmu: &mut Mmu) {
@@ -2311,3 +2344,54 @@ pub fn cbxFF(cpu: &mut Cpu, mmu: &mut Mmu) {
 fn set_bit(reg: &mut u8, bitno: u8) {
     *reg |= 1 << bitno;
 }
+
+
+#[test]
+fn test_bit_and_cp() {
+    use gameboy::model::Model;
+    use gameboy::registers::Registers;
+    use gameboy::rom::Rom;
+
+    let mut flags = FlagRegister::new();
+    let mut reg = 0x80;
+    bit_x_n(7, &mut reg, &mut flags);
+    assert!(!flags.z);
+    bit_x_n(6, &mut reg, &mut flags);
+    assert!(flags.z && flags.h && !flags.n);
+
+    // CP d8 with an operand above A mustn't overflow.
+    let mut cpu = Cpu::new(Registers::new(), Model::Dmg);
+    let mut mmu = Mmu::new(Rom::from_bytes(vec![0; 0x8000], "test.gb").unwrap(), Model::Dmg);
+    cpu.regs.pc = 0xC000;
+    mmu.write(0xC000, 0x42);
+    mmu.write(0xC001, 0x43);
+    cpu.regs.a = 0x42;
+    opxFE(&mut cpu, &mut mmu);
+    assert!(cpu.regs.flags.z && !cpu.regs.flags.c);
+    opxFE(&mut cpu, &mut mmu);
+    assert!(!cpu.regs.flags.z && cpu.regs.flags.c);
+}
+
+
+#[test]
+fn test_dec_r16() {
+    use gameboy::model::Model;
+    use gameboy::registers::Registers;
+    use gameboy::rom::Rom;
+
+    let mut cpu = Cpu::new(Registers::new(), Model::Dmg);
+    let mut mmu = Mmu::new(Rom::from_bytes(vec![0; 0x8000], "test.gb").unwrap(), Model::Dmg);
+    // DEC DE, DEC HL and DEC SP, leaving the flags alone
+    for (i, &opcode) in [0x1B, 0x2B, 0x3B].iter().enumerate() {
+        mmu.write(0xC000 + i, opcode);
+    }
+    cpu.regs.pc = 0xC000;
+    cpu.regs.set_hl(0x1234);
+    cpu.regs.sp = 0;
+    cpu.regs.flags.z = true;
+    for _ in 0..3 {
+        assert_eq!(8, cpu.cycle(&mut mmu));
+    }
+    assert_eq!((0xFFFF, 0x1233, 0xFFFF), (cpu.regs.de(), cpu.regs.hl(), cpu.regs.sp));
+    assert!(cpu.regs.flags.z);
+}
@@ -1,13 +1,54 @@
-use graphics::{Control, Palette, Stat, Tile, Shade};
+use std::mem;
 
+use gameboy::model::Model;
+use gameboy::sgb;
+use gameboy::sgb::Sgb;
+use gameboy::state::{StateError, StateReader, StateWriter};
+use graphics::{rgb555_to_rgba, ColorScheme, Control, Palette, Stat, StatMode, Shade};
 
-const FRAMEBUFFER_SIZE: usize = 92160;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// Each scanline takes 456 cycles: 80 searching OAM, 172 transferring
+// pixels and the remainder in H-Blank.
+const LINE_CYCLES: u32 = 456;
+const SEARCH_CYCLES: u32 = 80;
+const TRANSFER_CYCLES: u32 = 172;
+
+// Sprites are picked from OAM in order, at most 10 to a line.
+const SPRITES_PER_LINE: usize = 10;
+// CGB palette RAM holds eight palettes of four 15-bit colours.
+const COLOR_RAM_SIZE: usize = 64;
+// Running a DMG game, the CGB boot ROM picks colours by the title of
+// Nintendo's games; these are the ones it gives everything else.
+const COMPAT_BG_COLORS: [u16; 4] = [0x7FFF, 0x1BEF, 0x6180, 0x0000];
+const COMPAT_OBJ_COLORS: [u16; 4] = [0x7FFF, 0x421F, 0x1CF2, 0x0000];
+// OAM holds 20 rows of 8 bytes, searched one every 4 cycles.
+const OAM_ROW_CYCLES: u32 = 4;
+
+// Bits of CGB tile map attributes and of sprite flags.
+const ATTR_PALETTE: u8 = 0x07;
+const ATTR_BANK: u8 = 0x08;
+const ATTR_DMG_PALETTE: u8 = 0x10;
+const ATTR_FLIP_X: u8 = 0x20;
+const ATTR_FLIP_Y: u8 = 0x40;
+const ATTR_PRIORITY: u8 = 0x80;
 
 
 pub struct Ppu {
-    framebuffer: [u8; FRAMEBUFFER_SIZE],
-    on_refresh: Option<Box<FnMut([u8; FRAMEBUFFER_SIZE])>>,
+    framebuffer: Box<[u8]>,
+    on_refresh: Option<Box<FnMut(&[u8])>>,
+    color_scheme: ColorScheme,
+    model: Model,
+    // Drawing with CGB tile attributes and colour palettes
+    cgb: bool,
+    // Colouring the screen and drawing a border as the SGB asks
+    sgb: Option<Sgb>,
+    // Both CGB banks, the second holding tile map attributes and more
+    // tiles. The DMG only has the first.
     vram: Box<[u8]>,
+    vram_bank: usize, // FF4F
     oam: Box<[u8]>,
     control: Control, // FF40
     pub stat: Stat, // FF41
@@ -17,6 +58,11 @@ pub struct Ppu {
     // Vertical line to which we are transferring data
     ly: usize, // FF44
 
+    // Cycles spent so far on the current line
+    line_cycles: u32,
+    // Set on reaching line 144 until the MMU raises the interrupt
+    vblank_requested: bool,
+
     // Compares this to ly. When equal, set the coincident
     // bit and request a STAT interrupt
     lyc: usize, // FF45
@@ -31,20 +77,39 @@ pub struct Ppu {
     obj1_palette: Palette, // FF49
     window_y: u8, // FF4A
     window_x: u8, // FF4B
+    // Window lines drawn so far this frame
+    window_line: usize,
+
+    // CGB palette RAM is reached through an index register, which
+    // counts up after each write to the data register when bit 7 is set.
+    bg_color_index: u8, // FF68
+    bg_colors: [u8; COLOR_RAM_SIZE], // FF69
+    obj_color_index: u8, // FF6A
+    obj_colors: [u8; COLOR_RAM_SIZE], // FF6B
 }
 
 impl Ppu {
-    pub fn new() -> Ppu {
-        Ppu {
-            framebuffer: [0; FRAMEBUFFER_SIZE],
+    // CGB hardware outside CGB mode draws DMG games with the colours the
+    // boot ROM left in palette RAM.
+    pub fn new(model: Model, cgb: bool) -> Ppu {
+        let vram_size = if cgb { 0x4000 } else { 0x2000 };
+        let mut ppu = Ppu {
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4].into_boxed_slice(),
             on_refresh: None,
-            vram: Box::new([0; 0x2000]),
+            color_scheme: ColorScheme::Green,
+            model: model,
+            cgb: cgb,
+            sgb: None,
+            vram: vec![0; vram_size].into_boxed_slice(),
+            vram_bank: 0,
             oam: Box::new([0; 0xA0]),
             control: Control::new(),
             stat: Stat::new(),
             scroll_x: 0,
             scroll_y: 0,
             ly: 0,
+            line_cycles: 0,
+            vblank_requested: false,
             lyc: 0,
             dma_address: 0,
             bg_palette: Palette::new(),
@@ -52,20 +117,56 @@ impl Ppu {
             obj1_palette: Palette::new(),
             window_y: 0,
             window_x: 0,
+            window_line: 0,
+            bg_color_index: 0,
+            // The CGB boot ROM starts every background colour off white.
+            bg_colors: [0xFF; COLOR_RAM_SIZE],
+            obj_color_index: 0,
+            obj_colors: [0; COLOR_RAM_SIZE],
+        };
+        // The SGB draws a larger frame with the border.
+        if model == Model::Sgb {
+            ppu.sgb = Some(Sgb::new());
+            ppu.framebuffer = vec![0; sgb::WIDTH * sgb::HEIGHT * 4].into_boxed_slice();
         }
+        if model.is_cgb() && !cgb {
+            for (i, &color) in COMPAT_BG_COLORS.iter().enumerate() {
+                ppu.bg_colors[i * 2] = color as u8;
+                ppu.bg_colors[i * 2 + 1] = (color >> 8) as u8;
+            }
+            for (i, &color) in COMPAT_OBJ_COLORS.iter().cycle().take(8).enumerate() {
+                ppu.obj_colors[i * 2] = color as u8;
+                ppu.obj_colors[i * 2 + 1] = (color >> 8) as u8;
+            }
+        }
+        ppu
     }
 
-    fn get_tile(&self, tile_no: usize) -> Tile {
-        let idx = match self.control.bg_data_select {
-            false => ((tile_no.wrapping_add(128) as u16) * 16 + 0x800) as usize,
-            true => (tile_no * 16) as usize,
-        };
-        let slice = &self.vram[idx..idx + 16];
-        Tile::new(slice)
+    // Pass a P1 write on to the SGB, carrying out any command it
+    // finishes. Gives back how many players MLT_REQ asked for.
+    pub fn write_sgb(&mut self, value: u8) -> Option<usize> {
+        let command = self.sgb.as_mut().and_then(|sgb| sgb.write_p1(value))?;
+        let vram = self.screen_tiles();
+        self.sgb.as_mut().unwrap().run(&command, &vram)
+    }
+    // The tile data of the first 256 tiles on screen, going across 20 to
+    // a row, which is how the SGB's *_TRN commands receive data.
+    fn screen_tiles(&self) -> Vec<u8> {
+        let base = if self.control.bg_tilemap_select { 0x1C00 } else { 0x1800 };
+        let mut data = Vec::with_capacity(0x1000);
+        for i in 0..0x100 {
+            let tile = self.vram[base + (i / 20) * 32 + i % 20];
+            let address = match self.control.bg_data_select {
+                true => tile as usize * 16,
+                false => (0x1000 + (tile as i8 as isize) * 16) as usize,
+            };
+            data.extend_from_slice(&self.vram[address..address + 16]);
+        }
+        data
     }
     pub fn read_u8(&self, loc: usize) -> u8 {
         let result = match loc {
-            0x8000...0x9FFF => self.vram[loc - 0x8000],
+            0x8000...0x9FFF => self.vram[self.vram_bank * 0x2000 + loc - 0x8000],
             0xFE00...0xFE9F => self.oam[loc - 0xFE00],
             0xFF40 => self.control.read_u8(),
             0xFF41 => self.stat.read_u8(),
@@ -79,6 +180,13 @@ impl Ppu {
             0xFF49 => self.obj1_palette.read_u8(),
             0xFF4A => self.window_y,
             0xFF4B => self.window_x,
+            // The CGB registers read back all bits set on the DMG.
+            _ if !self.cgb => 0xFF,
+            0xFF4F => self.vram_bank as u8 | 0xFE,
+            0xFF68 => self.bg_color_index | 0x40,
+            0xFF69 => self.bg_colors[(self.bg_color_index & 0x3F) as usize],
+            0xFF6A => self.obj_color_index | 0x40,
+            0xFF6B => self.obj_colors[(self.obj_color_index & 0x3F) as usize],
             _ => panic!("{} is not a valid Ppu-mapped address.", loc),
         };
         info!("Memory Read: {:04X} @ Loc:{:04X}", result, loc);
@@ -86,7 +194,7 @@ impl Ppu {
     }
     pub fn write_u8(&mut self, loc: usize, value: u8) {
         match loc {
-            0x8000...0x9FFF => self.vram[loc - 0x8000] = value,
+            0x8000...0x9FFF => self.vram[self.vram_bank * 0x2000 + loc - 0x8000] = value,
             0xFE00...0xFE9F => self.oam[loc - 0xFE00] = value,
             0xFF40 => self.control.write_u8(value),
             0xFF41 => self.stat.write_u8(value),
@@ -100,58 +208,503 @@ impl Ppu {
             0xFF49 => self.obj1_palette.write_u8(value),
             0xFF4A => self.window_y = value,
             0xFF4B => self.window_x = value,
+            _ if !self.cgb => {}
+            0xFF4F => self.vram_bank = (value & 1) as usize,
+            0xFF68 => self.bg_color_index = value & 0xBF,
+            0xFF69 => {
+                self.bg_colors[(self.bg_color_index & 0x3F) as usize] = value;
+                self.bg_color_index = next_color_index(self.bg_color_index);
+            }
+            0xFF6A => self.obj_color_index = value & 0xBF,
+            0xFF6B => {
+                self.obj_colors[(self.obj_color_index & 0x3F) as usize] = value;
+                self.obj_color_index = next_color_index(self.obj_color_index);
+            }
             _ => panic!("{} is not a valid Ppu-mapped address.", loc),
         };
     }
 
-    pub fn tile_line(&self, x: usize) -> &[u8] {
-        let base = match self.control.bg_tilemap_select {
-            true => (0x1C00 + x),
-            false => (0x1800 + x),
-        };
-        &self.vram[base..base + 20]
-    }
-    fn update_framebuffer(&mut self) {
-        let nth_tile = ((self.scroll_y as usize + self.ly) / 8) * 32 + (self.scroll_x / 8);
-        let tiles: Vec<Tile> = self.tile_line(nth_tile)
-            .into_iter()
-            .map(|x| self.get_tile(*x as usize))
-            .collect();
+    // Draw line LY: the background, the window over it, then sprites.
+    fn render_line(&mut self) {
+        let ly = self.ly;
+        // Colour number of each background or window pixel and whether
+        // its CGB attributes put it in front of sprites
+        let mut background = [(0, false); SCREEN_WIDTH];
+        let mut line = [[0; 4]; SCREEN_WIDTH];
 
-        let row = (self.scroll_y + self.ly) % 8;
-        let lines: Vec<u8> = tiles.iter().fold(Vec::new(), |mut v, x| {
-            v.extend(&x.lines[row]);
-            v
-        });
-        let shades: Vec<Shade> = lines.into_iter().map(|x| Shade::from_u8(x)).collect();
+        // On the DMG clearing LCDC bit 0 blanks the background and window.
+        // On the CGB they stay, but lose any priority over sprites.
+        let bg_enabled = self.cgb || self.control.bg_display;
+        let bg_priority = !self.cgb || self.control.bg_display;
+        let window_x = self.window_x as usize;
+        let window = bg_enabled && self.control.display_enable && ly >= self.window_y as usize &&
+            window_x <= 166;
+        for x in 0..SCREEN_WIDTH {
+            if !bg_enabled {
+                line[x] = self.dmg_color(x, None, Shade::White);
+                continue;
+            }
+            let (color, attributes) = if window && x + 7 >= window_x {
+                let map = self.control.tilemap_select;
+                self.map_pixel(map, x + 7 - window_x, self.window_line)
+            } else {
+                let map = self.control.bg_tilemap_select;
+                self.map_pixel(map, (self.scroll_x + x) & 0xFF, (self.scroll_y + ly) & 0xFF)
+            };
+            background[x] = (color, attributes & ATTR_PRIORITY != 0);
+            line[x] = match self.cgb {
+                true => self.cgb_color(&self.bg_colors, attributes & ATTR_PALETTE, color),
+                false => self.dmg_color(x, None, self.bg_palette.shade(color)),
+            };
+        }
+        if window {
+            self.window_line += 1;
+        }
+
+        if self.control.obj_enable {
+            let height = if self.control.obj_size { 16 } else { 8 };
+            let mut sprites: Vec<usize> = (0..40)
+                .filter(|&i| {
+                    let y = self.oam[i * 4] as usize;
+                    ly + 16 >= y && ly + 16 < y + height
+                })
+                .take(SPRITES_PER_LINE)
+                .collect();
+            // The CGB goes by OAM order alone; the DMG puts the sprite
+            // furthest left on top, then the first in OAM.
+            if !self.cgb {
+                sprites.sort_by_key(|&i| self.oam[i * 4 + 1]);
+            }
+            let mut drawn = [false; SCREEN_WIDTH];
+            for i in sprites {
+                let sprite = &self.oam[i * 4..i * 4 + 4];
+                let (y, x, flags) = (sprite[0] as usize, sprite[1] as usize, sprite[3]);
+                let mut row = ly + 16 - y;
+                if flags & ATTR_FLIP_Y != 0 {
+                    row = height - 1 - row;
+                }
+                let tile = if height == 16 { sprite[2] & 0xFE } else { sprite[2] } as usize;
+                let bank = if self.cgb && flags & ATTR_BANK != 0 { 0x2000 } else { 0 };
+                let address = bank + tile * 16 + row * 2;
+                for column in 0..8 {
+                    if x + column < 8 || x + column >= SCREEN_WIDTH + 8 {
+                        continue;
+                    }
+                    let screen_x = x + column - 8;
+                    let bit = if flags & ATTR_FLIP_X != 0 { 7 - column } else { column };
+                    let color = self.tile_pixel(address, bit);
+                    // A higher priority sprite's pixel hides the rest,
+                    // even when the background then covers it.
+                    if color == 0 || drawn[screen_x] {
+                        continue;
+                    }
+                    drawn[screen_x] = true;
+                    let (bg_color, bg_on_top) = background[screen_x];
+                    if bg_priority && bg_color != 0 && (flags & ATTR_PRIORITY != 0 || bg_on_top) {
+                        continue;
+                    }
+                    line[screen_x] = match self.cgb {
+                        true => self.cgb_color(&self.obj_colors, flags & ATTR_PALETTE, color),
+                        false => {
+                            let (number, palette) = match flags & ATTR_DMG_PALETTE {
+                                0 => (0, &self.obj0_palette),
+                                _ => (1, &self.obj1_palette),
+                            };
+                            self.dmg_color(screen_x, Some(number), palette.shade(color))
+                        }
+                    };
+                }
+            }
+        }
 
-        let offset = (self.ly as usize * 20 * 8 * 4) as usize;
-        for (i, shade) in shades.into_iter().enumerate() {
-            let b = offset + i * 4;
-            self.framebuffer[b..b + 4].copy_from_slice(&shade.to_rgba());
+        if let Some(ref sgb) = self.sgb {
+            sgb.draw_line(&mut self.framebuffer, ly, &line);
+            return;
+        }
+        let offset = ly * SCREEN_WIDTH * 4;
+        for (x, rgba) in line.iter().enumerate() {
+            self.framebuffer[offset + x * 4..offset + x * 4 + 4].copy_from_slice(rgba);
+        }
+    }
+    // Colour of a shade from BGP, or OBP0 or OBP1 for sprite palette
+    // `obj`. The SGB colours each shade by where it is on screen.
+    fn dmg_color(&self, x: usize, obj: Option<u8>, shade: Shade) -> [u8; 4] {
+        match (&self.sgb, obj) {
+            (&Some(ref sgb), _) => sgb.color(x, self.ly, shade.to_u8()),
+            (_, None) if self.model.is_cgb() => self.cgb_color(&self.bg_colors, 0, shade.to_u8()),
+            (_, Some(obj)) if self.model.is_cgb() => {
+                self.cgb_color(&self.obj_colors, obj, shade.to_u8())
+            }
+            _ => self.color_scheme.to_rgba(&shade),
+        }
+    }
+    // The OAM row, as a byte offset, the search has reached, if the OAM
+    // bug can hit one.
+    fn oam_bug_row(&self) -> Option<usize> {
+        match self.stat.mode {
+            StatMode::Search => {}
+            _ => return None,
+        }
+        let row = (self.line_cycles / OAM_ROW_CYCLES) as usize * 8;
+        match row {
+            0 => None,
+            _ if row >= self.oam.len() => None,
+            _ => Some(row),
+        }
+    }
+    fn oam_word(&self, i: usize) -> u16 {
+        self.oam[i] as u16 | (self.oam[i + 1] as u16) << 8
+    }
+    // Mangles the first word of `row` with `first` and copies the rest of
+    // the row before over it.
+    fn corrupt_oam_row(&mut self, row: usize, first: u16) {
+        self.oam[row] = first as u8;
+        self.oam[row + 1] = (first >> 8) as u8;
+        for i in 2..8 {
+            self.oam[row + i] = self.oam[row - 8 + i];
+        }
+    }
+    // Write corruption from the OAM bug, which hits the row the search
+    // has reached: its first word is mangled with the row before, whose
+    // other three words are copied over it.
+    pub fn corrupt_oam(&mut self) {
+        if let Some(row) = self.oam_bug_row() {
+            let (a, b, c) = (self.oam_word(row), self.oam_word(row - 8), self.oam_word(row - 4));
+            self.corrupt_oam_row(row, ((a ^ c) & (b ^ c)) ^ c);
+        }
+    }
+    // Corruption from a read in the same cycle as an increment. Past the
+    // first four rows and before the last, the row before is mangled with
+    // its neighbours and copied over this row and the one two back. Then
+    // it's corrupted like a plain read.
+    pub fn corrupt_oam_read(&mut self) {
+        let row = match self.oam_bug_row() {
+            Some(row) => row,
+            None => return,
+        };
+        if row >= 4 * 8 && row < self.oam.len() - 8 {
+            let (a, b) = (self.oam_word(row - 16), self.oam_word(row - 8));
+            let (c, d) = (self.oam_word(row), self.oam_word(row - 4));
+            let first = (b & (a | c | d)) | (a & c & d);
+            self.oam[row - 8] = first as u8;
+            self.oam[row - 7] = (first >> 8) as u8;
+            for i in 0..8 {
+                self.oam[row - 16 + i] = self.oam[row - 8 + i];
+                self.oam[row + i] = self.oam[row - 8 + i];
+            }
+        }
+        let (a, b, c) = (self.oam_word(row), self.oam_word(row - 8), self.oam_word(row - 4));
+        self.corrupt_oam_row(row, b | (a & c));
+    }
+    // Colour number and CGB attributes of a pixel of a 256x256 tile map.
+    fn map_pixel(&self, high_map: bool, x: usize, y: usize) -> (u8, u8) {
+        let base = if high_map { 0x1C00 } else { 0x1800 };
+        let index = base + (y / 8) * 32 + x / 8;
+        let attributes = if self.cgb { self.vram[0x2000 + index] } else { 0 };
+        let tile = self.vram[index];
+        let address = match self.control.bg_data_select {
+            true => tile as usize * 16,
+            // Tiles numbered from -128 to 127 around 0x9000
+            false => (0x1000 + (tile as i8 as isize) * 16) as usize,
+        };
+        let bank = if attributes & ATTR_BANK != 0 { 0x2000 } else { 0 };
+        let row = match attributes & ATTR_FLIP_Y {
+            0 => y % 8,
+            _ => 7 - y % 8,
+        };
+        let column = match attributes & ATTR_FLIP_X {
+            0 => x % 8,
+            _ => 7 - x % 8,
+        };
+        (self.tile_pixel(bank + address + row * 2, column), attributes)
+    }
+    // Colour number of a pixel in the tile row at `address`, counting
+    // columns from the left.
+    fn tile_pixel(&self, address: usize, column: usize) -> u8 {
+        let bit = 7 - column;
+        (self.vram[address] >> bit) & 1 | ((self.vram[address + 1] >> bit) & 1) << 1
+    }
+    fn cgb_color(&self, colors: &[u8; COLOR_RAM_SIZE], palette: u8, color: u8) -> [u8; 4] {
+        let offset = (palette as usize * 4 + color as usize) * 2;
+        rgb555_to_rgba(colors[offset] as u16 | (colors[offset + 1] as u16) << 8)
+    }
+    // Returns true on entering HBlank on a visible line, when an HBlank
+    // DMA copies its next block.
+    pub fn step(&mut self, cycles: u32) -> bool {
+        let was_hblank = match self.stat.mode {
+            StatMode::Hblank => true,
+            _ => false,
+        };
+        self.line_cycles += cycles;
+        if self.line_cycles >= LINE_CYCLES {
+            self.line_cycles -= LINE_CYCLES;
+            self.next_line();
+        }
+        self.stat.mode = match self.ly {
+            144...153 => StatMode::Vblank,
+            _ if self.line_cycles < SEARCH_CYCLES => StatMode::Search,
+            _ if self.line_cycles < SEARCH_CYCLES + TRANSFER_CYCLES => StatMode::Transfer,
+            _ => StatMode::Hblank,
+        };
+        match self.stat.mode {
+            StatMode::Hblank => !was_hblank,
+            _ => false,
         }
     }
-    pub fn step(&mut self) {
+    fn next_line(&mut self) {
         match self.ly {
             0...143 => {
-                self.stat.vblank_int_enable = false;
                 if self.ly == 0 {
+                    if let Some(ref sgb) = self.sgb {
+                        sgb.draw_border(&mut self.framebuffer);
+                    }
                     if let Some(ref mut cb) = self.on_refresh {
-                        cb(self.framebuffer)
+                        cb(&self.framebuffer)
                     }
                 }
-                self.update_framebuffer();
+                self.render_line();
                 self.ly += 1;
+                self.vblank_requested = self.ly == 144;
             }
-            144...153 => {
-                self.stat.vblank_int_enable = true;
-                self.ly += 1;
+            144...152 => self.ly += 1,
+            153 => {
+                self.ly = 0;
+                self.window_line = 0;
             }
-            154 => self.ly = 0,
             _ => panic!("LY out of range."),
         }
     }
-    pub fn set_on_refresh(&mut self, callback: Box<FnMut([u8; 23_040 * 4])>) {
+    // Whether VBlank began since the last call.
+    pub fn take_vblank(&mut self) -> bool {
+        mem::replace(&mut self.vblank_requested, false)
+    }
+    // The screen as RGBA bytes, `screen_size` pixels across and down.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+    // The Game Boy's own screen, or the SGB's larger frame.
+    pub fn screen_size(&self) -> (usize, usize) {
+        match self.sgb {
+            Some(_) => (sgb::WIDTH, sgb::HEIGHT),
+            None => (SCREEN_WIDTH, SCREEN_HEIGHT),
+        }
+    }
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+    pub fn vram_mut(&mut self) -> &mut [u8] {
+        &mut self.vram
+    }
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+    pub fn oam_mut(&mut self) -> &mut [u8] {
+        &mut self.oam
+    }
+    // CGB background and sprite palette RAM.
+    pub fn bg_colors(&self) -> &[u8] {
+        &self.bg_colors
+    }
+    pub fn bg_colors_mut(&mut self) -> &mut [u8] {
+        &mut self.bg_colors
+    }
+    pub fn obj_colors(&self) -> &[u8] {
+        &self.obj_colors
+    }
+    pub fn obj_colors_mut(&mut self) -> &mut [u8] {
+        &mut self.obj_colors
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.vram);
+        state.write_bytes(&self.oam);
+        state.write_u8(self.control.read_u8());
+        state.write_u8(self.stat.read_u8());
+        state.write_u8(self.scroll_y as u8);
+        state.write_u8(self.scroll_x as u8);
+        state.write_u8(self.ly as u8);
+        state.write_u32(self.line_cycles);
+        state.write_u8(self.lyc as u8);
+        state.write_u8(self.dma_address as u8);
+        state.write_u8(self.bg_palette.read_u8());
+        state.write_u8(self.obj0_palette.read_u8());
+        state.write_u8(self.obj1_palette.read_u8());
+        state.write_u8(self.window_y);
+        state.write_u8(self.window_x);
+        state.write_u8(self.window_line as u8);
+        state.write_u8(self.vram_bank as u8);
+        state.write_u8(self.bg_color_index);
+        state.write_bytes(&self.bg_colors);
+        state.write_u8(self.obj_color_index);
+        state.write_bytes(&self.obj_colors);
+        state.write_bytes(&self.framebuffer);
+        state.write_bool(self.sgb.is_some());
+        if let Some(ref sgb) = self.sgb {
+            sgb.save_state(state);
+        }
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.vram)?;
+        state.read_into(&mut self.oam)?;
+        self.control.write_u8(state.read_u8()?);
+        self.stat.write_u8(state.read_u8()?);
+        self.scroll_y = state.read_u8()? as usize;
+        self.scroll_x = state.read_u8()? as usize;
+        self.ly = state.read_u8()? as usize;
+        if self.ly > 153 {
+            return Err(StateError::Invalid("LY value"));
+        }
+        self.line_cycles = state.read_u32()?;
+        self.lyc = state.read_u8()? as usize;
+        self.dma_address = state.read_u8()? as usize;
+        self.bg_palette.write_u8(state.read_u8()?);
+        self.obj0_palette.write_u8(state.read_u8()?);
+        self.obj1_palette.write_u8(state.read_u8()?);
+        self.window_y = state.read_u8()?;
+        self.window_x = state.read_u8()?;
+        self.window_line = state.read_u8()? as usize;
+        self.vram_bank = match state.read_u8()? {
+            bank @ 0...1 if bank as usize * 0x2000 < self.vram.len() => bank as usize,
+            _ => return Err(StateError::Invalid("VRAM bank")),
+        };
+        self.bg_color_index = state.read_u8()? & 0xBF;
+        state.read_into(&mut self.bg_colors)?;
+        self.obj_color_index = state.read_u8()? & 0xBF;
+        state.read_into(&mut self.obj_colors)?;
+        state.read_into(&mut self.framebuffer)?;
+        if state.read_bool()? != self.sgb.is_some() {
+            return Err(StateError::Invalid("SGB mode"));
+        }
+        match self.sgb {
+            Some(ref mut sgb) => sgb.load_state(state),
+            None => Ok(()),
+        }
+    }
+    pub fn set_color_scheme(&mut self, scheme: ColorScheme) {
+        self.color_scheme = scheme;
+    }
+    pub fn take_on_refresh(&mut self) -> Option<Box<FnMut(&[u8])>> {
+        self.on_refresh.take()
+    }
+    // Hand the current framebuffer to the refresh callback right away.
+    pub fn refresh(&mut self) {
+        if let Some(ref mut cb) = self.on_refresh {
+            cb(&self.framebuffer)
+        }
+    }
+    pub fn set_on_refresh(&mut self, callback: Box<FnMut(&[u8])>) {
         self.on_refresh = Some(callback);
     }
 }
+
+// Move on a palette index register after a write, if it auto-increments.
+fn next_color_index(index: u8) -> u8 {
+    match index & 0x80 {
+        0 => index,
+        _ => 0x80 | (index + 1) & 0x3F,
+    }
+}
+
+
+#[test]
+fn test_window_and_sprites() {
+    let mut ppu = Ppu::new(Model::Dmg, false);
+    // Tile 1 is solid colour 3 and tile 2 solid colour 1. The window
+    // from x 80 shows tile 0 then tile 1 over a background of tile 0.
+    for row in 0..8 {
+        ppu.write_u8(0x8010 + row * 2, 0xFF);
+        ppu.write_u8(0x8011 + row * 2, 0xFF);
+        ppu.write_u8(0x8020 + row * 2, 0xFF);
+    }
+    ppu.write_u8(0x9C01, 1);
+    ppu.write_u8(0xFF4B, 7 + 80);
+    ppu.write_u8(0xFF47, 0xE4);
+    ppu.write_u8(0xFF48, 0xE4);
+    ppu.write_u8(0xFF49, 0xC0);
+
+    // Tile 2 at x 10, tile 1 in OBP1 at x 6 and tile 2 behind the
+    // window at x 84.
+    for (i, &byte) in [16, 18, 2, 0, 16, 14, 1, 0x10, 16, 92, 2, 0x80].iter().enumerate() {
+        ppu.write_u8(0xFE00 + i, byte);
+    }
+
+    ppu.write_u8(0xFF40, 0xF3);
+    ppu.step(LINE_CYCLES);
+    let pixel = |x: usize| &ppu.framebuffer()[x * 4..x * 4 + 4];
+    assert_eq!(&Shade::White.to_rgba(), pixel(0));
+    // The sprite furthest left is on top.
+    assert_eq!(&Shade::Black.to_rgba(), pixel(12));
+    assert_eq!(&Shade::LightGray.to_rgba(), pixel(15));
+    // The sprite behind the window only shows over its colour 0.
+    assert_eq!(&Shade::White.to_rgba(), pixel(80));
+    assert_eq!(&Shade::LightGray.to_rgba(), pixel(85));
+    assert_eq!(&Shade::Black.to_rgba(), pixel(88));
+}
+
+
+#[test]
+fn test_cgb_attributes_and_priority() {
+    let mut ppu = Ppu::new(Model::Cgb, true);
+    // Tile 1 in bank 1 has only its leftmost pixel set, flipped to the
+    // right by the map attributes, which also pick palette 2.
+    ppu.write_u8(0xFF4F, 1);
+    ppu.write_u8(0x8010, 0x80);
+    ppu.write_u8(0x9800, ATTR_FLIP_X | ATTR_BANK | 2);
+    ppu.write_u8(0xFF4F, 0);
+    ppu.write_u8(0x9800, 1);
+    ppu.write_u8(0xFF68, 0x80 | (2 * 8));
+    for &byte in &[0x00, 0x7C, 0x1F, 0x00] {
+        ppu.write_u8(0xFF69, byte);
+    }
+    assert_eq!(0xC0 | (2 * 8 + 4), ppu.read_u8(0xFF68));
+
+    // Two solid sprites overlapping, the first in OAM further right.
+    ppu.write_u8(0x8020, 0xFF);
+    ppu.write_u8(0x8021, 0xFF);
+    for (i, &byte) in [16, 28, 2, 1, 16, 24, 2, 0].iter().enumerate() {
+        ppu.write_u8(0xFE00 + i, byte);
+    }
+    for &(index, color) in &[(0x86, 0x03E0), (0x8E, 0x7FFF)] {
+        ppu.write_u8(0xFF6A, index);
+        ppu.write_u8(0xFF6B, color as u8);
+        ppu.write_u8(0xFF6B, (color >> 8) as u8);
+    }
+
+    ppu.write_u8(0xFF40, 0x93);
+    ppu.step(LINE_CYCLES);
+    let pixel = |x: usize| &ppu.framebuffer()[x * 4..x * 4 + 4];
+    assert_eq!(&[0x00, 0x00, 0xFF, 0xFF], pixel(0));
+    assert_eq!(&[0xFF, 0x00, 0x00, 0xFF], pixel(7));
+    // OAM order decides, not X position as on the DMG.
+    assert_eq!(&[0x00, 0xFF, 0x00, 0xFF], pixel(17));
+    assert_eq!(&[0xFF, 0xFF, 0xFF, 0xFF], pixel(20));
+}
+
+#[test]
+fn test_oam_bug_and_compat_colors() {
+    let mut ppu = Ppu::new(Model::Dmg, false);
+    for i in 0..0xA0 {
+        ppu.oam_mut()[i] = i as u8;
+    }
+    // Outside the OAM search nothing happens.
+    ppu.corrupt_oam();
+    assert_eq!(0x10, ppu.oam()[0x10]);
+    ppu.step(LINE_CYCLES + 2 * OAM_ROW_CYCLES);
+    ppu.corrupt_oam();
+    // ((0x1110 ^ 0x0D0C) & (0x0908 ^ 0x0D0C)) ^ 0x0D0C
+    assert_eq!(&[0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F], &ppu.oam()[0x10..0x18]);
+    // A read during an increment copies row 3 over rows 2 and 4.
+    ppu.step(2 * OAM_ROW_CYCLES);
+    ppu.corrupt_oam_read();
+    for row in 2..5 {
+        let oam = &ppu.oam()[row * 8..row * 8 + 8];
+        assert_eq!(&[0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F], oam);
+    }
+
+    // A DMG game on the CGB is drawn with the boot ROM's colours.
+    let mut ppu = Ppu::new(Model::Cgb, false);
+    ppu.write_u8(0xFF40, 0x91);
+    ppu.write_u8(0xFF47, 0x03);
+    ppu.step(LINE_CYCLES);
+    assert_eq!(&[0x00, 0x00, 0x00, 0xFF], &ppu.framebuffer()[..4]);
+    assert_eq!(0xFF, ppu.read_u8(0xFF69));
+}
@@ -1,5 +1,8 @@
 use std::fmt;
 use bitty::BitFlags;
+use gameboy::model::Model;
+use gameboy::rom::Rom;
+use gameboy::state::{StateError, StateReader, StateWriter};
 
 pub struct FlagRegister {
     pub z: bool,
@@ -73,6 +76,58 @@ impl Registers {
             pc: 0x000,
         }
     }
+    // Register values the boot ROM leaves behind when it hands control
+    // to the cartridge at 0x0100. Games tell models apart by A, and the
+    // AGB by B as well.
+    pub fn after_boot(model: Model, rom: &Rom) -> Registers {
+        let (a, f, b, c, d, e, h, l) = match model {
+            Model::Dmg => (0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Mgb => (0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Sgb => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Cgb | Model::Agb if !rom.supports_cgb() => Registers::compat_boot(rom),
+            Model::Cgb => (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            Model::Agb => (0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+        };
+        let mut flags = FlagRegister::new();
+        flags.set_u8(f);
+        // The AGB boot ROM ends with an extra INC B.
+        let b = match model {
+            Model::Agb if !rom.supports_cgb() => {
+                let b = b.wrapping_add(1);
+                flags.z = b == 0;
+                flags.n = false;
+                flags.h = b & 0x0F == 0;
+                b
+            }
+            _ => b,
+        };
+        Registers {
+            b: b,
+            c: c,
+            d: d,
+            e: e,
+            h: h,
+            l: l,
+            a: a,
+            flags: flags,
+            sp: 0xFFFE,
+            pc: 0x100,
+        }
+    }
+    // A DMG game on the CGB: the boot ROM picks its palette from the
+    // title checksum, which stays in B, and leaves HL pointing into the
+    // tile map for two of the titles.
+    fn compat_boot(rom: &Rom) -> (u8, u8, u8, u8, u8, u8, u8, u8) {
+        let b = match rom.is_nintendo() {
+            true => rom.header_title().iter().fold(0u8, |sum, x| sum.wrapping_add(*x)),
+            false => 0x00,
+        };
+        let (h, l) = match b {
+            0x43 | 0x58 => (0x99, 0x1A),
+            _ => (0x00, 0x7C),
+        };
+        (0x11, 0x80, b, 0x00, 0x00, 0x08, h, l)
+    }
     pub fn bc(&self) -> u16 {
         let mut bc = (self.b as u16) << 8;
         bc |= self.c as u16;
@@ -107,6 +162,31 @@ impl Registers {
         self.l = (val & 0xFF) as u8;
         info!("Setting L to: {:02X}", self.l);
     }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.a);
+        state.write_u8(self.flags.as_u8());
+        state.write_u8(self.b);
+        state.write_u8(self.c);
+        state.write_u8(self.d);
+        state.write_u8(self.e);
+        state.write_u8(self.h);
+        state.write_u8(self.l);
+        state.write_u16(self.sp as u16);
+        state.write_u16(self.pc as u16);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.a = state.read_u8()?;
+        self.flags.set_u8(state.read_u8()?);
+        self.b = state.read_u8()?;
+        self.c = state.read_u8()?;
+        self.d = state.read_u8()?;
+        self.e = state.read_u8()?;
+        self.h = state.read_u8()?;
+        self.l = state.read_u8()?;
+        self.sp = state.read_u16()? as usize;
+        self.pc = state.read_u16()? as usize;
+        Ok(())
+    }
     pub fn set_af(&mut self, val: u16) {
         self.a = (val & 0xFF00) as u8;
         self.flags.set_u8((val & 0xFF) as u8);
@@ -129,3 +209,27 @@ impl fmt::Debug for Registers {
         )
     }
 }
+
+
+#[test]
+fn test_compat_boot() {
+    let mut data = vec![0; 0x8000];
+    data[0x134..0x138].copy_from_slice(b"TEST");
+    let rom = Rom::from_bytes(data.clone(), "test.gb").unwrap();
+    let regs = Registers::after_boot(Model::Cgb, &rom);
+    assert_eq!((0x0000, 0x0008, 0x007C), (regs.bc(), regs.de(), regs.hl()));
+
+    // 'T' + 'E' + 'S' + 'T' from Nintendo
+    data[0x14B] = 0x01;
+    let rom = Rom::from_bytes(data.clone(), "test.gb").unwrap();
+    let regs = Registers::after_boot(Model::Cgb, &rom);
+    assert_eq!((0x1180, 0x4000), (regs.af(), regs.bc()));
+    let regs = Registers::after_boot(Model::Agb, &rom);
+    assert_eq!((0x1100, 0x4100), (regs.af(), regs.bc()));
+
+    // A CGB game gets the CGB values.
+    data[0x143] = 0x80;
+    let rom = Rom::from_bytes(data, "test.gb").unwrap();
+    let regs = Registers::after_boot(Model::Cgb, &rom);
+    assert_eq!((0xFF56, 0x000D), (regs.de(), regs.hl()));
+}
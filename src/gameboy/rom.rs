@@ -1,41 +1,286 @@
+use std::io;
 use std::io::Read;
 use std::fs::File;
 use std::fmt;
 use std::str;
 
+use bitty::crc32;
+use gameboy::state::{StateError, StateReader, StateWriter};
+
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+
+// The memory bank controller on the cartridge, which maps ROM and RAM
+// banks into the address space in response to writes to the ROM area.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mbc {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+impl Mbc {
+    fn from_cartridge_type(byte: u8) -> Mbc {
+        match byte {
+            0x01...0x03 => Mbc::Mbc1,
+            0x05 | 0x06 => Mbc::Mbc2,
+            0x0F...0x13 => Mbc::Mbc3,
+            0x19...0x1E => Mbc::Mbc5,
+            _ => Mbc::None,
+        }
+    }
+}
 
 pub struct Rom {
     data: Vec<u8>,
     filename: String,
+    checksum: u32,
     pub size: usize,
+    mbc: Mbc,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: usize,
+    // RAM bank, or on MBC1 the upper bits of the ROM bank
+    ram_bank: usize,
+    // MBC1 mode select: false=ROM banking, true=RAM banking
+    ram_banking: bool,
 }
 
 impl Rom {
-    pub fn new(filepath: &str) -> Rom {
-        let filename = filepath.to_string();
-        let mut file = File::open(filepath).unwrap();
+    pub fn new(filepath: &str) -> io::Result<Rom> {
+        let mut file = File::open(filepath)?;
         let mut data: Vec<u8> = Vec::new();
-        file.read_to_end(&mut data).unwrap();
+        file.read_to_end(&mut data)?;
+        Rom::from_bytes(data, filepath)
+    }
+    // Build a cartridge from an image already in memory; `filename` is
+    // only used for display.
+    pub fn from_bytes(data: Vec<u8>, filename: &str) -> io::Result<Rom> {
+        if data.len() < 0x150 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file is too small to be a Gameboy ROM",
+            ));
+        }
         let size = data.len();
+        let checksum = crc32(&data);
+        let mbc = Mbc::from_cartridge_type(data[0x147]);
+        let ram_size = match mbc {
+            Mbc::Mbc2 => 0x200,
+            _ => {
+                match data[0x149] {
+                    0x03 => RAM_BANK_SIZE * 4,
+                    0x04 => RAM_BANK_SIZE * 16,
+                    0x05 => RAM_BANK_SIZE * 8,
+                    // Anything smaller gets one full bank, so ROMs that
+                    // under-declare their RAM still have somewhere to write.
+                    _ => RAM_BANK_SIZE,
+                }
+            }
+        };
 
-        Rom {
+        Ok(Rom {
             data: data,
-            filename: filename,
+            filename: filename.to_string(),
+            checksum: checksum,
             size: size,
-        }
+            mbc: mbc,
+            ram: vec![0; ram_size],
+            ram_enabled: mbc == Mbc::None,
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_banking: false,
+        })
     }
     pub fn read(&self, address: usize) -> u8 {
         match address {
-            0x0000...0x7FFF => self.data[address],
+            0x0000...0x3FFF => {
+                let bank = match self.mbc {
+                    Mbc::Mbc1 if self.ram_banking => self.ram_bank << 5,
+                    _ => 0,
+                };
+                self.read_banked(bank, address)
+            }
+            0x4000...0x7FFF => {
+                let bank = match self.mbc {
+                    Mbc::Mbc1 => self.ram_bank << 5 | self.rom_bank,
+                    _ => self.rom_bank,
+                };
+                self.read_banked(bank, address - ROM_BANK_SIZE)
+            }
+            0xA000...0xBFFF => {
+                match self.ram_address(address) {
+                    Some(idx) if self.mbc == Mbc::Mbc2 => self.ram[idx] | 0xF0,
+                    Some(idx) => self.ram[idx],
+                    None => 0xFF,
+                }
+            }
             _ => panic!("Memory Address {:X} does not belong to the ROM", address),
         }
     }
     pub fn write(&mut self, address: usize, byte: u8) {
         match address {
-            0x0000...0x7FFF => self.data[address] = byte,
+            0x0000...0x7FFF => self.write_register(address, byte),
+            0xA000...0xBFFF => {
+                if let Some(idx) = self.ram_address(address) {
+                    self.ram[idx] = byte;
+                }
+            }
             _ => panic!("Memory Address {:X} does not belong to the ROM", address),
         }
     }
+    fn read_banked(&self, bank: usize, offset: usize) -> u8 {
+        let idx = (bank * ROM_BANK_SIZE + offset) % self.data.len();
+        self.data[idx]
+    }
+    fn ram_address(&self, address: usize) -> Option<usize> {
+        if !self.ram_enabled {
+            return None;
+        }
+        let offset = address - 0xA000;
+        let idx = match self.mbc {
+            Mbc::Mbc2 => offset & 0x1FF,
+            Mbc::Mbc1 if !self.ram_banking => offset,
+            Mbc::None => offset,
+            // MBC3 banks 0x08-0x0C select the real time clock, which
+            // isn't emulated.
+            Mbc::Mbc3 if self.ram_bank > 0x03 => return None,
+            _ => self.ram_bank * RAM_BANK_SIZE + offset,
+        };
+        Some(idx % self.ram.len())
+    }
+    fn write_register(&mut self, address: usize, byte: u8) {
+        match self.mbc {
+            Mbc::None => {}
+            Mbc::Mbc1 => {
+                match address {
+                    0x0000...0x1FFF => self.ram_enabled = byte & 0x0F == 0x0A,
+                    0x2000...0x3FFF => self.rom_bank = ((byte & 0x1F) as usize).max(1),
+                    0x4000...0x5FFF => self.ram_bank = (byte & 0x03) as usize,
+                    _ => self.ram_banking = byte & 0x01 == 1,
+                }
+            }
+            Mbc::Mbc2 => {
+                if address < 0x4000 {
+                    // Address bit 8 picks between RAM enable and ROM bank.
+                    match address & 0x100 {
+                        0 => self.ram_enabled = byte & 0x0F == 0x0A,
+                        _ => self.rom_bank = ((byte & 0x0F) as usize).max(1),
+                    }
+                }
+            }
+            Mbc::Mbc3 => {
+                match address {
+                    0x0000...0x1FFF => self.ram_enabled = byte & 0x0F == 0x0A,
+                    0x2000...0x3FFF => self.rom_bank = ((byte & 0x7F) as usize).max(1),
+                    0x4000...0x5FFF => self.ram_bank = (byte & 0x0F) as usize,
+                    _ => {}
+                }
+            }
+            Mbc::Mbc5 => {
+                match address {
+                    0x0000...0x1FFF => self.ram_enabled = byte & 0x0F == 0x0A,
+                    0x2000...0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | byte as usize,
+                    0x3000...0x3FFF => {
+                        self.rom_bank = (self.rom_bank & 0xFF) | ((byte as usize & 0x01) << 8)
+                    }
+                    0x4000...0x5FFF => self.ram_bank = (byte & 0x0F) as usize,
+                    _ => {}
+                }
+            }
+        }
+    }
+    // Register writes that would put a freshly reset controller into
+    // its current state.
+    pub fn mbc_writes(&self) -> Vec<(u16, u8)> {
+        let enable = if self.ram_enabled { 0x0A } else { 0x00 };
+        match self.mbc {
+            Mbc::None => Vec::new(),
+            Mbc::Mbc1 => {
+                vec![
+                    (0x0000, enable),
+                    (0x2000, self.rom_bank as u8),
+                    (0x4000, self.ram_bank as u8),
+                    (0x6000, self.ram_banking as u8),
+                ]
+            }
+            Mbc::Mbc2 => vec![(0x0000, enable), (0x0100, self.rom_bank as u8)],
+            Mbc::Mbc3 => {
+                vec![
+                    (0x0000, enable),
+                    (0x2000, self.rom_bank as u8),
+                    (0x4000, self.ram_bank as u8),
+                ]
+            }
+            Mbc::Mbc5 => {
+                vec![
+                    (0x0000, enable),
+                    (0x2000, self.rom_bank as u8),
+                    (0x3000, (self.rom_bank >> 8) as u8),
+                    (0x4000, self.ram_bank as u8),
+                ]
+            }
+        }
+    }
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_enabled);
+        state.write_u16(self.rom_bank as u16);
+        state.write_u8(self.ram_bank as u8);
+        state.write_bool(self.ram_banking);
+        state.write_bytes(&self.ram);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank = state.read_u16()? as usize;
+        self.ram_bank = state.read_u8()? as usize;
+        self.ram_banking = state.read_bool()?;
+        state.read_into(&mut self.ram)
+    }
+    // CRC-32 of the ROM image as it was loaded from disk.
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+    // The title and global checksum from the cartridge header.
+    pub fn header_title(&self) -> &[u8] {
+        &self.data[0x134..0x144]
+    }
+    pub fn header_checksum(&self) -> u16 {
+        (self.data[0x14E] as u16) << 8 | self.data[0x14F] as u16
+    }
+    // Whether the header's CGB flag marks the game as made for or working
+    // with the CGB's extra hardware.
+    pub fn supports_cgb(&self) -> bool {
+        self.data[0x143] & 0x80 != 0
+    }
+    // Nintendo's own games, by either licensee code. The CGB boot ROM
+    // only has palettes for these.
+    pub fn is_nintendo(&self) -> bool {
+        let old = self.data[0x14B];
+        old == 0x01 || old == 0x33 && &self.data[0x144..0x146] == b"01"
+    }
+    // The SGB flag only counts alongside the new licensee code.
+    pub fn supports_sgb(&self) -> bool {
+        self.data[0x146] == 0x03 && self.data[0x14B] == 0x33
+    }
+    pub fn cartridge_type(&self) -> u8 {
+        self.data[0x147]
+    }
+    pub fn has_battery(&self) -> bool {
+        match self.cartridge_type() {
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0xFF => true,
+            _ => false,
+        }
+    }
     pub fn read_raw(&self, address: usize) -> u8 {
         self.data[address]
     }
@@ -49,3 +294,73 @@ impl fmt::Debug for Rom {
         write!(f, "Gameboy ROM:\n  RoFilename: {}\n", self.filename)
     }
 }
+
+
+#[cfg(test)]
+fn test_rom(cartridge_type: u8, ram_size: u8, banks: usize) -> Rom {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    // Each bank starts with its own number.
+    let mut data = vec![0; banks * ROM_BANK_SIZE];
+    for bank in 0..banks {
+        data[bank * ROM_BANK_SIZE] = bank as u8;
+        data[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+    }
+    data[0x147] = cartridge_type;
+    data[0x149] = ram_size;
+    let name = format!("bitromney_test_mbc_{:02X}.gb", cartridge_type);
+    let path = env::temp_dir().join(name);
+    File::create(&path).unwrap().write_all(&data).unwrap();
+    let rom = Rom::new(&path.to_string_lossy()).unwrap();
+    fs::remove_file(&path).unwrap();
+    rom
+}
+
+#[test]
+fn test_mbc1_banking() {
+    let mut rom = test_rom(0x03, 0x03, 64);
+    rom.write(0x2000, 0x00);
+    assert_eq!(1, rom.read(0x4000));
+    rom.write(0x2000, 0x05);
+    rom.write(0x4000, 0x01);
+    assert_eq!(0x25, rom.read(0x4000));
+    assert_eq!(0x00, rom.read(0x0000));
+
+    // RAM ignores writes until enabled, then banks in the RAM mode.
+    rom.write(0xA000, 0x12);
+    assert_eq!(0xFF, rom.read(0xA000));
+    rom.write(0x0000, 0x0A);
+    rom.write(0x6000, 0x01);
+    rom.write(0xA000, 0x34);
+    rom.write(0x4000, 0x02);
+    rom.write(0xA000, 0x56);
+    assert_eq!(0x56, rom.read(0xA000));
+    assert_eq!(0x34, rom.ram()[RAM_BANK_SIZE]);
+    assert_eq!(0x56, rom.ram()[RAM_BANK_SIZE * 2]);
+}
+
+#[test]
+fn test_mbc2_mbc3_mbc5_banking() {
+    let mut rom = test_rom(0x06, 0x00, 16);
+    rom.write(0x2100, 0x03);
+    assert_eq!(3, rom.read(0x4000));
+    rom.write(0x0000, 0x0A);
+    rom.write(0xA201, 0x5C);
+    assert_eq!(0xFC, rom.read(0xA001));
+
+    let mut rom = test_rom(0x13, 0x03, 128);
+    rom.write(0x2000, 0x7F);
+    assert_eq!(0x7F, rom.read(0x4000));
+    rom.write(0x0000, 0x0A);
+    rom.write(0x4000, 0x08);
+    assert_eq!(0xFF, rom.read(0xA000));
+
+    let mut rom = test_rom(0x19, 0x00, 512);
+    rom.write(0x2000, 0x00);
+    assert_eq!(0, rom.read(0x4000));
+    rom.write(0x2000, 0x02);
+    rom.write(0x3000, 0x01);
+    assert_eq!([0x02, 0x01], [rom.read(0x4000), rom.read(0x4001)]);
+}
@@ -0,0 +1,187 @@
+use std::io::{stdout, Write};
+use std::mem;
+
+use gameboy::state::{StateError, StateReader, StateWriter};
+
+
+// The internal clock shifts one bit every 512 cycles, 8192 times a
+// second.
+const BIT_CYCLES: u32 = 512;
+
+
+// Whatever is on the other end of the link cable.
+pub trait Peer {
+    // This side clocked a transfer: send `byte` and give back the byte
+    // shifted in from the other end.
+    fn transfer(&mut self, byte: u8) -> u8;
+    // Called as time passes, with SB in `ready` while this side waits on
+    // an external clock. Gives back a byte if the other end has clocked
+    // one in, having sent it SB in exchange.
+    fn poll(&mut self, cycles: u32, ready: Option<u8>) -> Option<u8>;
+}
+
+pub enum Connection {
+    // Nothing plugged in, so every bit received is high.
+    Disconnected,
+    // Collect sent bytes for `Serial::output`.
+    Buffer(Vec<u8>),
+    // Print sent bytes as they go, mostly for test ROMs.
+    Stdout,
+    Peer(Box<Peer>),
+}
+
+pub struct Serial {
+    data: u8, // FF01
+    control: u8, // FF02
+    // Progress of an internally clocked transfer: the byte being shifted
+    // in, the bits left and cycles until the next one.
+    incoming: u8,
+    bits: u8,
+    timer: u32,
+    // Which end is plugged in is a frontend setting, not machine state.
+    connection: Connection,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            control: 0,
+            incoming: 0,
+            bits: 0,
+            timer: 0,
+            connection: Connection::Disconnected,
+        }
+    }
+    pub fn connect(&mut self, connection: Connection) -> Connection {
+        mem::replace(&mut self.connection, connection)
+    }
+    // Bytes sent so far while capturing to a buffer.
+    pub fn output(&self) -> &[u8] {
+        match self.connection {
+            Connection::Buffer(ref output) => output,
+            _ => &[],
+        }
+    }
+    pub fn read_u8(&self, address: usize) -> u8 {
+        match address {
+            0xFF01 => self.data,
+            0xFF02 => self.control | 0x7E,
+            _ => panic!("{:04X} is not a valid Serial-mapped address.", address),
+        }
+    }
+    pub fn write_u8(&mut self, address: usize, value: u8) {
+        match address {
+            0xFF01 => self.data = value,
+            0xFF02 => {
+                self.control = value & 0x81;
+                if value & 0x81 == 0x81 {
+                    self.start_transfer();
+                } else {
+                    self.bits = 0;
+                }
+            }
+            _ => panic!("{:04X} is not a valid Serial-mapped address.", address),
+        }
+    }
+    fn start_transfer(&mut self) {
+        let byte = self.data;
+        self.incoming = match self.connection {
+            Connection::Disconnected => 0xFF,
+            Connection::Buffer(ref mut output) => {
+                output.push(byte);
+                0xFF
+            }
+            Connection::Stdout => {
+                let mut out = stdout();
+                let _ = out.write_all(&[byte]).and_then(|_| out.flush());
+                0xFF
+            }
+            Connection::Peer(ref mut peer) => peer.transfer(byte),
+        };
+        self.bits = 8;
+        self.timer = BIT_CYCLES;
+    }
+    // Returns true when a transfer finishes, to raise the serial
+    // interrupt.
+    pub fn step(&mut self, cycles: u32) -> bool {
+        // The other end sets the pace of an external clock, so a byte
+        // arrives all at once.
+        let waiting = self.control & 0x81 == 0x80;
+        if let Connection::Peer(ref mut peer) = self.connection {
+            let ready = if waiting { Some(self.data) } else { None };
+            match peer.poll(cycles, ready) {
+                Some(byte) if waiting => {
+                    self.data = byte;
+                    self.control &= 0x7F;
+                    return true;
+                }
+                _ => {}
+            }
+        }
+        if self.control & 0x81 != 0x81 {
+            return false;
+        }
+        let mut remaining = cycles;
+        while self.bits > 0 && remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = BIT_CYCLES;
+            self.bits -= 1;
+            self.data = self.data << 1 | (self.incoming >> self.bits) & 1;
+        }
+        if self.bits > 0 {
+            self.timer -= remaining;
+            return false;
+        }
+        self.control &= 0x7F;
+        true
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.data);
+        state.write_u8(self.control);
+        state.write_u8(self.incoming);
+        state.write_u8(self.bits);
+        state.write_u32(self.timer);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data = state.read_u8()?;
+        self.control = state.read_u8()? & 0x81;
+        self.incoming = state.read_u8()?;
+        self.bits = state.read_u8()?.min(8);
+        self.timer = state.read_u32()?.min(BIT_CYCLES);
+        Ok(())
+    }
+}
+
+
+#[test]
+fn test_serial_transfer() {
+    struct Echo(u8);
+    impl Peer for Echo {
+        fn transfer(&mut self, byte: u8) -> u8 {
+            mem::replace(&mut self.0, byte)
+        }
+        fn poll(&mut self, _: u32, _: Option<u8>) -> Option<u8> {
+            None
+        }
+    }
+
+    let mut serial = Serial::new();
+    serial.connect(Connection::Buffer(Vec::new()));
+    serial.write_u8(0xFF01, 0x42);
+    serial.write_u8(0xFF02, 0x81);
+    assert_eq!(0xFF, serial.read_u8(0xFF02));
+    assert!(!serial.step(BIT_CYCLES * 8 - 1));
+    // Bits shift in from the disconnected end one at a time.
+    assert_eq!(0x7F, serial.read_u8(0xFF01));
+    assert!(serial.step(1));
+    assert_eq!(0x7F, serial.read_u8(0xFF02));
+    assert_eq!(0xFF, serial.read_u8(0xFF01));
+    assert_eq!(&[0x42], serial.output());
+
+    serial.connect(Connection::Peer(Box::new(Echo(0x99))));
+    serial.write_u8(0xFF01, 0x17);
+    serial.write_u8(0xFF02, 0x81);
+    assert!(serial.step(BIT_CYCLES * 8));
+    assert_eq!(0x99, serial.read_u8(0xFF01));
+}
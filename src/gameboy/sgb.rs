@@ -0,0 +1,490 @@
+use std::mem;
+
+use gameboy::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use gameboy::state::{StateError, StateReader, StateWriter};
+use graphics::rgb555_to_rgba;
+
+
+// The SGB draws the Game Boy screen in the middle of a 256x224 frame,
+// with the border around it.
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 224;
+const SCREEN_LEFT: usize = 48;
+const SCREEN_TOP: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+// Attributes give a palette to each 8x8 tile of the screen.
+const TILES_WIDE: usize = SCREEN_WIDTH / 8;
+const TILES_HIGH: usize = SCREEN_HEIGHT / 8;
+const ATTRIBUTES: usize = TILES_WIDE * TILES_HIGH;
+// ATTR_TRN sends 45 attribute files of two bits per tile.
+const ATTRIBUTE_FILES: usize = 45;
+const ATTRIBUTE_FILE_SIZE: usize = ATTRIBUTES / 4;
+const SYSTEM_PALETTES: usize = 512;
+// The border has 256 4bpp tiles, a 32x28 map and palettes 4-7.
+const BORDER_TILES_SIZE: usize = 256 * 32;
+const BORDER_MAP_SIZE: usize = 32 * 32 * 2;
+const BORDER_COLORS: usize = 4 * 16;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+// The SGB's own starting palette, before a game sends any.
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+
+// What MASK_EN shows in place of the Game Boy screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mask {
+    Off,
+    Freeze,
+    Black,
+    Color0,
+}
+
+impl Mask {
+    fn from_u8(value: u8) -> Mask {
+        match value & 0x03 {
+            0 => Mask::Off,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            _ => Mask::Color0,
+        }
+    }
+    fn to_u8(&self) -> u8 {
+        match *self {
+            Mask::Off => 0,
+            Mask::Freeze => 1,
+            Mask::Black => 2,
+            Mask::Color0 => 3,
+        }
+    }
+}
+
+// The Super Game Boy side of a game running on one. Games send it
+// commands in 16 byte packets, clocked out a bit at a time through P1:
+// writing 0x00 starts a packet, then 0x20 sends a 0 bit and 0x10 a 1,
+// with 0x30 between each. A packet ends with a 0 bit. The first byte
+// holds the command number and how many packets it takes.
+pub struct Sgb {
+    packet: [u8; PACKET_SIZE],
+    bit: usize,
+    receiving: bool,
+    // P1 went back to 0x30 since the last bit
+    released: bool,
+    // Packets of the command received so far
+    command: Vec<u8>,
+
+    // Colour 0 of the first palette is shared by all four.
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    // Palette of each tile of the screen
+    attributes: [u8; ATTRIBUTES],
+    attribute_files: Vec<u8>,
+    mask: Mask,
+
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>,
+    border_colors: [u16; BORDER_COLORS],
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            packet: [0; PACKET_SIZE],
+            bit: 0,
+            receiving: false,
+            released: false,
+            command: Vec::new(),
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; SYSTEM_PALETTES * 4],
+            attributes: [0; ATTRIBUTES],
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            mask: Mask::Off,
+            border_tiles: vec![0; BORDER_TILES_SIZE],
+            border_map: vec![0; BORDER_MAP_SIZE],
+            border_colors: [0; BORDER_COLORS],
+        }
+    }
+    // Take a write to P1, giving back a command once all its packets
+    // have arrived.
+    pub fn write_p1(&mut self, value: u8) -> Option<Vec<u8>> {
+        match value & 0x30 {
+            0x00 => {
+                self.packet = [0; PACKET_SIZE];
+                self.bit = 0;
+                self.receiving = true;
+                self.released = false;
+            }
+            0x30 => self.released = true,
+            pulse if self.receiving && self.released => {
+                self.released = false;
+                let one = pulse == 0x10;
+                if self.bit < PACKET_BITS {
+                    self.packet[self.bit / 8] |= (one as u8) << (self.bit % 8);
+                    self.bit += 1;
+                } else {
+                    self.receiving = false;
+                    if !one {
+                        return self.finish_packet();
+                    }
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+    fn finish_packet(&mut self) -> Option<Vec<u8>> {
+        if self.command.is_empty() && self.packet[0] & 0x07 == 0 {
+            return None;
+        }
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0x07) as usize;
+        match self.command.len() / PACKET_SIZE {
+            received if received < packets => None,
+            _ => Some(mem::replace(&mut self.command, Vec::new())),
+        }
+    }
+    // Carry out a command. `vram` is the 4KB the *_TRN commands copy
+    // from the screen. Gives back how many players MLT_REQ asked for.
+    pub fn run(&mut self, command: &[u8], vram: &[u8]) -> Option<usize> {
+        let data = &command[1..];
+        match command[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => self.attribute_blocks(data),
+            ATTR_LIN => {
+                let count = data[0] as usize;
+                for &line in data[1..].iter().take(count) {
+                    let (position, palette) = ((line & 0x1F) as usize, (line >> 5) & 0x03);
+                    for i in 0..ATTRIBUTES {
+                        // Bit 7 picks a row rather than a column.
+                        let (x, y) = (i % TILES_WIDE, i / TILES_WIDE);
+                        let coordinate = if line & 0x80 != 0 { y } else { x };
+                        if coordinate == position {
+                            self.attributes[i] = palette;
+                        }
+                    }
+                }
+            }
+            ATTR_DIV => {
+                let (palettes, position) = (data[0], (data[1] & 0x1F) as usize);
+                for i in 0..ATTRIBUTES {
+                    let (x, y) = (i % TILES_WIDE, i / TILES_WIDE);
+                    let coordinate = if palettes & 0x40 != 0 { y } else { x };
+                    self.attributes[i] = match coordinate {
+                        c if c < position => (palettes >> 2) & 0x03,
+                        c if c == position => (palettes >> 4) & 0x03,
+                        _ => palettes & 0x03,
+                    };
+                }
+            }
+            ATTR_CHR => self.attribute_tiles(data),
+            PAL_SET => {
+                for i in 0..4 {
+                    let number = (data[i * 2] as usize | (data[i * 2 + 1] as usize) << 8) & 0x1FF;
+                    let colors = &self.system_palettes[number * 4..number * 4 + 4];
+                    self.palettes[i].copy_from_slice(colors);
+                }
+                let flags = data[8];
+                if flags & 0x80 != 0 {
+                    self.apply_attribute_file((flags & 0x3F) as usize);
+                }
+                if flags & 0x40 != 0 {
+                    self.mask = Mask::Off;
+                }
+            }
+            PAL_TRN => {
+                for (i, color) in self.system_palettes.iter_mut().enumerate() {
+                    *color = vram[i * 2] as u16 | (vram[i * 2 + 1] as u16) << 8;
+                }
+            }
+            MLT_REQ => {
+                return Some(match data[0] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                })
+            }
+            CHR_TRN => {
+                let half = BORDER_TILES_SIZE / 2;
+                let start = (data[0] & 0x01) as usize * half;
+                self.border_tiles[start..start + half].copy_from_slice(&vram[..half]);
+            }
+            PCT_TRN => {
+                self.border_map.copy_from_slice(&vram[..BORDER_MAP_SIZE]);
+                for (i, color) in self.border_colors.iter_mut().enumerate() {
+                    let offset = BORDER_MAP_SIZE + i * 2;
+                    *color = vram[offset] as u16 | (vram[offset + 1] as u16) << 8;
+                }
+            }
+            ATTR_TRN => {
+                let length = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&vram[..length]);
+            }
+            ATTR_SET => {
+                self.apply_attribute_file((data[0] & 0x3F) as usize);
+                if data[0] & 0x40 != 0 {
+                    self.mask = Mask::Off;
+                }
+            }
+            MASK_EN => self.mask = Mask::from_u8(data[0]),
+            // Sound, the SNES side and the rest are left alone.
+            _ => {}
+        }
+        None
+    }
+    // PALxx: the shared colour 0, then colours 1-3 of each palette.
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| data[i * 2] as u16 | (data[i * 2 + 1] as u16) << 8;
+        self.palettes[0][0] = color(0);
+        for shade in 1..4 {
+            self.palettes[first][shade] = color(shade);
+            self.palettes[second][shade] = color(shade + 3);
+        }
+    }
+    // ATTR_BLK: for each rectangle, a palette for the tiles inside, on
+    // and outside its edge. Changing only the inside or only the outside
+    // takes the edge along with it.
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let count = data[0] as usize;
+        for block in data[1..].chunks(6).take(count).filter(|block| block.len() == 6) {
+            let (control, palettes) = (block[0] & 0x07, block[1]);
+            let (inside, outside) = (palettes & 0x03, (palettes >> 4) & 0x03);
+            let edge = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 != 0 => Some((palettes >> 2) & 0x03),
+                _ => None,
+            };
+            let (left, top) = ((block[2] & 0x1F) as usize, (block[3] & 0x1F) as usize);
+            let (right, bottom) = ((block[4] & 0x1F) as usize, (block[5] & 0x1F) as usize);
+            for i in 0..ATTRIBUTES {
+                let (x, y) = (i % TILES_WIDE, i / TILES_WIDE);
+                let palette = if x > left && x < right && y > top && y < bottom {
+                    if control & 0x01 != 0 { Some(inside) } else { None }
+                } else if x < left || x > right || y < top || y > bottom {
+                    if control & 0x04 != 0 { Some(outside) } else { None }
+                } else {
+                    edge
+                };
+                if let Some(palette) = palette {
+                    self.attributes[i] = palette;
+                }
+            }
+        }
+    }
+    // ATTR_CHR: palettes for a run of tiles from a starting tile, going
+    // across or down, packed four to a byte.
+    fn attribute_tiles(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[0] as usize, data[1] as usize);
+        let count = (data[2] as usize | (data[3] as usize) << 8).min(ATTRIBUTES);
+        let down = data[4] & 0x01 != 0;
+        for i in 0..count {
+            let byte = match data.get(5 + i / 4) {
+                Some(&byte) => byte,
+                None => break,
+            };
+            if x >= TILES_WIDE || y >= TILES_HIGH {
+                break;
+            }
+            self.attributes[y * TILES_WIDE + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+            if down {
+                y += 1;
+                if y == TILES_HIGH {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == TILES_WIDE {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+    fn apply_attribute_file(&mut self, file: usize) {
+        if file >= ATTRIBUTE_FILES {
+            return;
+        }
+        let start = file * ATTRIBUTE_FILE_SIZE;
+        for i in 0..ATTRIBUTES {
+            let byte = self.attribute_files[start + i / 4];
+            self.attributes[i] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+        }
+    }
+    // Colour of a Game Boy shade at a point on its screen.
+    pub fn color(&self, x: usize, y: usize, shade: u8) -> [u8; 4] {
+        let palette = self.attributes[(y / 8) * TILES_WIDE + x / 8] as usize;
+        match shade {
+            0 => rgb555_to_rgba(self.palettes[0][0]),
+            _ => rgb555_to_rgba(self.palettes[palette][shade as usize]),
+        }
+    }
+    // Copy a line of the Game Boy screen into the frame, or what the
+    // mask shows instead.
+    pub fn draw_line(&self, framebuffer: &mut [u8], y: usize, line: &[[u8; 4]]) {
+        let fill = match self.mask {
+            Mask::Off => None,
+            Mask::Freeze => return,
+            Mask::Black => Some([0x00, 0x00, 0x00, 0xFF]),
+            Mask::Color0 => Some(rgb555_to_rgba(self.palettes[0][0])),
+        };
+        let offset = ((SCREEN_TOP + y) * WIDTH + SCREEN_LEFT) * 4;
+        for (x, rgba) in line.iter().enumerate() {
+            let rgba = fill.as_ref().unwrap_or(rgba);
+            framebuffer[offset + x * 4..offset + x * 4 + 4].copy_from_slice(rgba);
+        }
+    }
+    // Draw the border around the screen. Colour 0 of a border tile shows
+    // the backdrop, colour 0 of the first palette.
+    pub fn draw_border(&self, framebuffer: &mut [u8]) {
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let screen_x = x >= SCREEN_LEFT && x < SCREEN_LEFT + SCREEN_WIDTH;
+                if screen_x && y >= SCREEN_TOP && y < SCREEN_TOP + SCREEN_HEIGHT {
+                    continue;
+                }
+                let index = ((y / 8) * 32 + x / 8) * 2;
+                let entry = self.border_map[index] as usize |
+                    (self.border_map[index + 1] as usize) << 8;
+                let row = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+                let bit = if entry & 0x4000 != 0 { x % 8 } else { 7 - x % 8 };
+                // Each row has two bitplanes in the first half of the tile
+                // and the other two in the second.
+                let address = (entry & 0xFF) * 32 + row * 2;
+                let color = [0, 1, 16, 17].iter().enumerate().fold(0, |color, (plane, &offset)| {
+                    color | ((self.border_tiles[address + offset] >> bit) & 1) << plane
+                }) as usize;
+                let rgba = match color {
+                    0 => rgb555_to_rgba(self.palettes[0][0]),
+                    _ => rgb555_to_rgba(self.border_colors[((entry >> 10) & 0x03) * 16 + color]),
+                };
+                let offset = (y * WIDTH + x) * 4;
+                framebuffer[offset..offset + 4].copy_from_slice(&rgba);
+            }
+        }
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.packet);
+        state.write_u8(self.bit as u8);
+        state.write_bool(self.receiving);
+        state.write_bool(self.released);
+        state.write_u8((self.command.len() / PACKET_SIZE) as u8);
+        state.write_bytes(&self.command);
+        for palette in &self.palettes {
+            for &color in palette {
+                state.write_u16(color);
+            }
+        }
+        for &color in self.system_palettes.iter().chain(self.border_colors.iter()) {
+            state.write_u16(color);
+        }
+        state.write_bytes(&self.attributes);
+        state.write_bytes(&self.attribute_files);
+        state.write_u8(self.mask.to_u8());
+        state.write_bytes(&self.border_tiles);
+        state.write_bytes(&self.border_map);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.packet)?;
+        self.bit = state.read_u8()? as usize;
+        if self.bit > PACKET_BITS {
+            return Err(StateError::Invalid("SGB packet bit"));
+        }
+        self.receiving = state.read_bool()?;
+        self.released = state.read_bool()?;
+        let packets = state.read_u8()? as usize;
+        self.command = state.read_bytes(packets * PACKET_SIZE)?.to_vec();
+        for palette in &mut self.palettes {
+            for color in palette.iter_mut() {
+                *color = state.read_u16()?;
+            }
+        }
+        for color in self.system_palettes.iter_mut().chain(self.border_colors.iter_mut()) {
+            *color = state.read_u16()?;
+        }
+        state.read_into(&mut self.attributes)?;
+        if self.attributes.iter().any(|&palette| palette > 3) {
+            return Err(StateError::Invalid("SGB attributes"));
+        }
+        state.read_into(&mut self.attribute_files)?;
+        self.mask = Mask::from_u8(state.read_u8()?);
+        state.read_into(&mut self.border_tiles)?;
+        state.read_into(&mut self.border_map)?;
+        Ok(())
+    }
+}
+
+
+#[test]
+fn test_sgb_packets() {
+    fn send(sgb: &mut Sgb, packet: &[u8]) -> Option<Vec<u8>> {
+        let mut command = None;
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        // The bit after the packet is the low stop bit.
+        for i in 0..PACKET_BITS + 1 {
+            let bit = packet.get(i / 8).map_or(0, |&byte| (byte >> (i % 8)) & 1);
+            command = command.or(sgb.write_p1(if bit != 0 { 0x10 } else { 0x20 }));
+            sgb.write_p1(0x30);
+        }
+        command
+    }
+
+    let mut sgb = Sgb::new();
+    // PAL01 with colour 0 black, palette 0's colours red and palette 1's
+    // blue, then ATTR_BLK giving the block from tile (2,2) to (4,4)
+    // palette 1.
+    let pal01 = [0x01, 0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00, 0x7C, 0x00, 0x7C,
+                 0x00, 0x7C];
+    let command = send(&mut sgb, &pal01).unwrap();
+    assert!(sgb.run(&command, &[]).is_none());
+    let attr_blk = [0x21, 0x01, 0x01, 0x01, 0x02, 0x02, 0x04, 0x04];
+    let command = send(&mut sgb, &attr_blk).unwrap();
+    sgb.run(&command, &[]);
+    assert_eq!([0x00, 0x00, 0x00, 0xFF], sgb.color(0, 0, 0));
+    assert_eq!([0xFF, 0x00, 0x00, 0xFF], sgb.color(8, 8, 3));
+    // The edge goes with the inside.
+    assert_eq!([0x00, 0x00, 0xFF, 0xFF], sgb.color(16, 16, 3));
+    assert_eq!([0x00, 0x00, 0xFF, 0xFF], sgb.color(39, 39, 1));
+    assert_eq!([0xFF, 0x00, 0x00, 0xFF], sgb.color(40, 16, 1));
+
+    // A packet without its stop bit low is thrown away.
+    sgb.write_p1(0x00);
+    for _ in 0..PACKET_BITS + 1 {
+        sgb.write_p1(0x30);
+        sgb.write_p1(0x10);
+    }
+    assert!(sgb.command.is_empty());
+
+    // MLT_REQ for four players
+    let command = send(&mut sgb, &[0x89, 0x03]).unwrap();
+    assert_eq!(Some(4), sgb.run(&command, &[]));
+
+    // MASK_EN black
+    let command = send(&mut sgb, &[0xB9, 0x02]).unwrap();
+    sgb.run(&command, &[]);
+    let mut framebuffer = vec![0; WIDTH * HEIGHT * 4];
+    sgb.draw_line(&mut framebuffer, 0, &[[0xFF; 4]; SCREEN_WIDTH]);
+    let offset = (SCREEN_TOP * WIDTH + SCREEN_LEFT) * 4;
+    assert_eq!(&[0x00, 0x00, 0x00, 0xFF], &framebuffer[offset..offset + 4]);
+}
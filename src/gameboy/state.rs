@@ -0,0 +1,183 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use gameboy::model::Model;
+
+
+// Save states start with this magic, a format version, the checksum
+// of the ROM they were taken from and the model that ran it, followed
+// by each component's state in a fixed order. The only thing allowed after that is an appended
+// BESS section.
+pub const MAGIC: &'static [u8; 4] = b"GRST";
+pub const VERSION: u32 = 11;
+
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    RomMismatch { expected: u32, found: u32 },
+    ModelMismatch { expected: Model, found: Model },
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::Io(ref err) => write!(f, "{}", err),
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::RomMismatch { expected, found } => {
+                write!(
+                    f,
+                    "save state is for a different ROM (checksum {:08X}, loaded {:08X})",
+                    found,
+                    expected
+                )
+            }
+            StateError::ModelMismatch { expected, found } => {
+                write!(
+                    f,
+                    "save state is for the {}, but the {} is being emulated",
+                    found.name(),
+                    expected.name()
+                )
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(what) => write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+impl From<io::Error> for StateError {
+    fn from(err: io::Error) -> StateError {
+        StateError::Io(err)
+    }
+}
+
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+    // Continue writing at the end of existing data.
+    pub fn with_bytes(data: Vec<u8>) -> StateWriter {
+        StateWriter { data: data }
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data: data, pos: 0 }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.pos < len {
+            return Err(StateError::Truncated);
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(bytes[0] as u16 | (bytes[1] as u16) << 8)
+    }
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.read_bytes(4)?;
+        let mut value = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            value |= (*byte as u32) << (i * 8);
+        }
+        Ok(value)
+    }
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let low = self.read_u32()? as u64;
+        let high = self.read_u32()? as u64;
+        Ok(high << 32 | low)
+    }
+    // Fill `dest` from the state; the sizes of saved memory regions are
+    // fixed, so no length prefix is stored.
+    pub fn read_into(&mut self, dest: &mut [u8]) -> Result<(), StateError> {
+        let len = dest.len();
+        dest.copy_from_slice(self.read_bytes(len)?);
+        Ok(())
+    }
+}
+
+
+#[test]
+fn test_state_round_trip() {
+    let mut writer = StateWriter::new();
+    writer.write_u8(0x12);
+    writer.write_bool(true);
+    writer.write_u16(0x3456);
+    writer.write_u32(0x789ABCDE);
+    writer.write_u64(0x0123456789ABCDEF);
+    writer.write_bytes(&[1, 2, 3]);
+    let data = writer.into_bytes();
+
+    let mut reader = StateReader::new(&data);
+    assert_eq!(0x12, reader.read_u8().unwrap());
+    assert_eq!(true, reader.read_bool().unwrap());
+    assert_eq!(0x3456, reader.read_u16().unwrap());
+    assert_eq!(0x789ABCDE, reader.read_u32().unwrap());
+    assert_eq!(0x0123456789ABCDEF, reader.read_u64().unwrap());
+    let mut bytes = [0; 3];
+    reader.read_into(&mut bytes).unwrap();
+    assert_eq!([1, 2, 3], bytes);
+    assert!(reader.is_empty());
+    assert!(reader.read_u8().is_err());
+}
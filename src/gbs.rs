@@ -0,0 +1,371 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use sdl2;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+use audio::Audio;
+use audio::vgm::VgmWriter;
+use audio::wav::AudioRecorder;
+use gameboy::{Gameboy, CYCLES_PER_FRAME};
+use gameboy::model::Model;
+use gameboy::rom::Rom;
+use throttle::Throttle;
+
+
+const MAGIC: &'static [u8; 3] = b"GBS";
+const HEADER_SIZE: usize = 0x70;
+// Routines are called with this address as their return address. It
+// holds a `JR -2` so the CPU idles there until the next call.
+const RETURN_ADDRESS: usize = 0x0070;
+// Give up on a routine that hasn't returned after a second.
+const CALL_LIMIT: u32 = 4_194_304;
+// Length of a headless export without a frame limit, about 3 minutes.
+const EXPORT_FRAMES: u64 = 10_752;
+// Input clock dividers for the four TAC speeds.
+const TIMER_DIVIDERS: [u32; 4] = [1024, 16, 64, 256];
+
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn header_text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+// The header at the start of a .gbs music rip.
+pub struct GbsHeader {
+    pub song_count: usize,
+    // 1-based, as in the file
+    pub first_song: usize,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> io::Result<GbsHeader> {
+        if data.len() < HEADER_SIZE || &data[0..3] != MAGIC {
+            return Err(invalid("not a GBS file"));
+        }
+        if data[3] != 1 {
+            return Err(invalid("unsupported GBS version"));
+        }
+        let header = GbsHeader {
+            song_count: data[4] as usize,
+            first_song: data[5] as usize,
+            load_address: read_u16(data, 0x06),
+            init_address: read_u16(data, 0x08),
+            play_address: read_u16(data, 0x0A),
+            stack_pointer: read_u16(data, 0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: header_text(&data[0x10..0x30]),
+            author: header_text(&data[0x30..0x50]),
+            copyright: header_text(&data[0x50..0x70]),
+        };
+        if header.song_count == 0 {
+            return Err(invalid("GBS file has no songs"));
+        }
+        if header.load_address < 0x400 || header.load_address >= 0x8000 {
+            return Err(invalid("GBS load address is outside the cartridge ROM"));
+        }
+        Ok(header)
+    }
+    // Cycles between calls to the play routine: the timer overflow rate
+    // if the timer is enabled, otherwise once per VBlank.
+    pub fn play_period(&self) -> u32 {
+        match self.timer_control & 0x04 {
+            0 => CYCLES_PER_FRAME,
+            _ => {
+                let divider = TIMER_DIVIDERS[(self.timer_control & 0x03) as usize];
+                (256 - self.timer_modulo as u32) * divider
+            }
+        }
+    }
+}
+
+// Lay the music data out as a cartridge image with an MBC5 for the
+// driver's bank switching and 8KB of RAM.
+fn build_rom(header: &GbsHeader, data: &[u8]) -> Vec<u8> {
+    let load = header.load_address as usize;
+    let size = (load + data.len() + 0x3FFF) & !0x3FFF;
+    let mut image = vec![0xFF; size];
+    image[load..load + data.len()].copy_from_slice(data);
+    // RST vectors jump to the same offset from the load address.
+    for vector in (0x00..0x40).step_by(8) {
+        let target = load + vector;
+        image[vector..vector + 3].copy_from_slice(&[0xC3, target as u8, (target >> 8) as u8]);
+    }
+    // Interrupts are never enabled, but return straight away if one is.
+    for vector in (0x40..0x68).step_by(8) {
+        image[vector] = 0xD9;
+    }
+    image[RETURN_ADDRESS] = 0x18;
+    image[RETURN_ADDRESS + 1] = 0xFE;
+    image[0x147] = 0x19;
+    image[0x149] = 0x02;
+    image
+}
+
+pub struct GbsPlayer {
+    header: GbsHeader,
+    rom: Vec<u8>,
+    gameboy: Gameboy,
+    // 0-based
+    track: usize,
+    until_play: i64,
+    record_channels: bool,
+}
+
+impl GbsPlayer {
+    pub fn load(path: &Path) -> io::Result<GbsPlayer> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let header = GbsHeader::parse(&data)?;
+        let rom = build_rom(&header, &data[HEADER_SIZE..]);
+        let gameboy = Gameboy::new(
+            Rom::from_bytes(rom.clone(), &path.to_string_lossy())?,
+            Model::Dmg,
+        );
+        let track = header.first_song.max(1).min(header.song_count) - 1;
+        Ok(GbsPlayer {
+            header: header,
+            rom: rom,
+            gameboy: gameboy,
+            track: track,
+            until_play: 0,
+            record_channels: false,
+        })
+    }
+    pub fn header(&self) -> &GbsHeader {
+        &self.header
+    }
+    pub fn track(&self) -> usize {
+        self.track
+    }
+    // Pick the track (0-based) to start with.
+    pub fn set_track(&mut self, track: usize) {
+        self.track = track % self.header.song_count;
+    }
+    pub fn gameboy_mut(&mut self) -> &mut Gameboy {
+        &mut self.gameboy
+    }
+    // Ask for per-channel samples as well, which survive track changes.
+    pub fn record_channels(&mut self) {
+        self.record_channels = true;
+        self.gameboy.mmu.apu.enable_samples(true);
+    }
+    // Reset the machine and run the init routine for a track (0-based).
+    pub fn start_track(&mut self, track: usize) {
+        self.track = track % self.header.song_count;
+        let rom = Rom::from_bytes(self.rom.clone(), "").unwrap();
+        let logging = self.gameboy.mmu.apu.logging();
+        self.gameboy = Gameboy::new(rom, Model::Dmg);
+        self.gameboy.skip_boot_rom();
+        self.gameboy.mmu.apu.enable_samples(self.record_channels);
+        if logging {
+            self.gameboy.mmu.apu.start_log();
+        }
+
+        let mmu = &mut self.gameboy.mmu;
+        mmu.write(0x0000, 0x0A);
+        mmu.write(0xFF06, self.header.timer_modulo);
+        mmu.write(0xFF07, self.header.timer_control);
+        mmu.write(0xFF26, 0x80);
+        mmu.write(0xFF25, 0xFF);
+        mmu.write(0xFF24, 0x77);
+
+        self.gameboy.cpu.regs.sp = self.header.stack_pointer as usize;
+        self.gameboy.cpu.regs.a = self.track as u8;
+        let init = self.header.init_address as usize;
+        self.call(init);
+        self.until_play = 0;
+    }
+    pub fn next_track(&mut self) {
+        let track = self.track + 1;
+        self.start_track(track);
+    }
+    pub fn previous_track(&mut self) {
+        let track = self.track + self.header.song_count - 1;
+        self.start_track(track);
+    }
+    // Run a routine until it returns, giving the cycles it took.
+    fn call(&mut self, address: usize) -> u32 {
+        let gameboy = &mut self.gameboy;
+        gameboy.cpu.stack_push_u16(RETURN_ADDRESS as u16, &mut gameboy.mmu);
+        gameboy.cpu.regs.pc = address;
+        let mut cycles = 0;
+        while gameboy.cpu.regs.pc != RETURN_ADDRESS && cycles < CALL_LIMIT {
+            cycles += gameboy.step();
+        }
+        if gameboy.cpu.regs.pc != RETURN_ADDRESS {
+            println!("Routine at {:04X} did not return; skipping it.", address);
+            gameboy.cpu.regs.pc = RETURN_ADDRESS;
+        }
+        cycles
+    }
+    // Play one frame's worth of time, calling the play routine whenever
+    // it is due and letting the sound hardware run in between.
+    pub fn run_frame(&mut self) {
+        let mut remaining = CYCLES_PER_FRAME as i64;
+        while remaining > 0 {
+            if self.until_play <= 0 {
+                let play = self.header.play_address as usize;
+                let cycles = self.call(play) as i64;
+                self.until_play += self.header.play_period() as i64 - cycles;
+                remaining -= cycles;
+            } else {
+                let idle = remaining.min(self.until_play);
+                self.gameboy.mmu.step(idle as u32);
+                self.until_play -= idle;
+                remaining -= idle;
+            }
+        }
+    }
+    fn print_track(&self) {
+        println!(
+            "Track {}/{}: {} - {} ({})",
+            self.track + 1,
+            self.header.song_count,
+            self.header.title,
+            self.header.author,
+            self.header.copyright
+        );
+    }
+}
+
+// Export a track to a WAV file, a VGM log or both without opening any
+// windows.
+pub fn run_headless(
+    player: &mut GbsPlayer,
+    frames: Option<u64>,
+    mut recorder: Option<AudioRecorder>,
+    mut vgm: Option<VgmWriter>,
+) {
+    let track = player.track();
+    player.start_track(track);
+    player.print_track();
+    for _ in 0..frames.unwrap_or(EXPORT_FRAMES) {
+        player.run_frame();
+        let apu = &mut player.gameboy_mut().mmu.apu;
+        let samples = apu.take_samples();
+        let channel_samples = apu.take_channel_samples();
+        if let Some(mut current) = recorder.take() {
+            match current.write(&samples, &channel_samples) {
+                Ok(()) => recorder = Some(current),
+                Err(err) => eprintln!("Stopped recording audio: {}", err),
+            }
+        }
+        if let Some(mut writer) = vgm.take() {
+            let (writes, cycles) = apu.take_writes();
+            match writer.write(&writes, cycles) {
+                Ok(()) => vgm = Some(writer),
+                Err(err) => {
+                    eprintln!("Stopped logging sound registers: {}", err);
+                    apu.stop_log();
+                }
+            }
+        }
+    }
+    if let Some(current) = recorder {
+        current.finish();
+    }
+    if let Some(writer) = vgm {
+        writer.finish(&mut player.gameboy_mut().mmu.apu);
+    }
+}
+
+// Play through the speakers, with Right/Left changing track and Escape
+// quitting.
+pub fn run_window(player: &mut GbsPlayer, context: &sdl2::Sdl, mut audio: Option<Audio>) {
+    let window = context
+        .video()
+        .and_then(|video| video.window("GBS Player", 320, 64).build().map_err(|e| e.to_string()));
+    let mut window = match window {
+        Ok(window) => window,
+        Err(err) => {
+            eprintln!("Could not open a window: {}", err);
+            return;
+        }
+    };
+    let mut events = context.event_pump().unwrap();
+    let mut throttle = Throttle::new();
+    let track = player.track();
+    player.start_track(track);
+
+    let mut current = None;
+    loop {
+        if current != Some(player.track()) {
+            current = Some(player.track());
+            player.print_track();
+            let title = format!(
+                "{} - {}/{}",
+                player.header().title,
+                player.track() + 1,
+                player.header().song_count
+            );
+            let _ = window.set_title(&title);
+        }
+        for event in events.poll_iter() {
+            match event {
+                Event::Quit { .. } |
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => return,
+                Event::KeyDown { keycode: Some(Keycode::Right), .. } |
+                Event::KeyDown { keycode: Some(Keycode::N), .. } => player.next_track(),
+                Event::KeyDown { keycode: Some(Keycode::Left), .. } |
+                Event::KeyDown { keycode: Some(Keycode::P), .. } => player.previous_track(),
+                _ => {}
+            }
+        }
+        player.run_frame();
+        let samples = player.gameboy_mut().mmu.apu.take_samples();
+        match audio {
+            Some(ref mut audio) => {
+                audio.push(&samples);
+                audio.wait();
+            }
+            None => throttle.frame(),
+        }
+    }
+}
+
+
+#[test]
+fn test_gbs_header() {
+    let mut data = vec![0; HEADER_SIZE];
+    data[0..4].copy_from_slice(b"GBS\x01");
+    data[4] = 12;
+    data[5] = 3;
+    data[6..16].copy_from_slice(&[0x00, 0x04, 0x10, 0x04, 0x20, 0x04, 0xFE, 0xDF, 0xC0, 0x06]);
+    data[0x10..0x15].copy_from_slice(b"Tunes");
+    let header = GbsHeader::parse(&data).unwrap();
+    assert_eq!(12, header.song_count);
+    assert_eq!(3, header.first_song);
+    assert_eq!(0x0400, header.load_address);
+    assert_eq!(0x0420, header.play_address);
+    assert_eq!(0xDFFE, header.stack_pointer);
+    assert_eq!("Tunes", header.title);
+    // 64 cycle timer ticks, 64 of them per overflow
+    assert_eq!(64 * 64, header.play_period());
+
+    let rom = build_rom(&header, &[0xC9]);
+    assert_eq!(0x4000, rom.len());
+    assert_eq!(&[0xC3, 0x08, 0x04], &rom[0x08..0x0B]);
+    assert_eq!(0xC9, rom[0x400]);
+}
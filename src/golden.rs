@@ -0,0 +1,110 @@
+use std::io;
+use std::path::Path;
+
+use graphics::image::{load_png, save_png};
+
+
+// Compare the screen against a reference image, writing a diff image
+// next to the reference if they differ. With `bless` the reference is
+// replaced by the screen instead. Returns whether the screen matched.
+pub fn check(
+    framebuffer: &[u8],
+    size: (usize, usize),
+    reference: &Path,
+    bless: bool,
+) -> io::Result<bool> {
+    let (width, height) = (size.0 as u32, size.1 as u32);
+    if bless {
+        save_png(reference, width, height, framebuffer)?;
+        println!("Updated {}", reference.display());
+        return Ok(true);
+    }
+
+    let (ref_width, ref_height, expected) = load_png(reference)?;
+    if (ref_width, ref_height) != (width, height) {
+        println!(
+            "{} is {}x{}, but the screen is {}x{}",
+            reference.display(),
+            ref_width,
+            ref_height,
+            width,
+            height
+        );
+        return Ok(false);
+    }
+    let (changed, diff) = diff_image(framebuffer, &expected);
+    if changed == 0 {
+        println!("Screen matches {}", reference.display());
+        return Ok(true);
+    }
+    let diff_path = reference.with_extension("diff.png");
+    save_png(&diff_path, width, height, &diff)?;
+    println!(
+        "{} pixels differ from {}; wrote {}",
+        changed,
+        reference.display(),
+        diff_path.display()
+    );
+    Ok(false)
+}
+
+// Changed pixels are drawn in solid red over a faded copy of the
+// reference so they stand out.
+fn diff_image(actual: &[u8], expected: &[u8]) -> (usize, Vec<u8>) {
+    let mut changed = 0;
+    let mut diff = Vec::with_capacity(expected.len());
+    for (a, e) in actual.chunks(4).zip(expected.chunks(4)) {
+        if a[..3] != e[..3] {
+            changed += 1;
+            diff.extend_from_slice(&[0xFF, 0x00, 0x00, 0xFF]);
+        } else {
+            let luma = (e[0] as u32 * 3 + e[1] as u32 * 6 + e[2] as u32) / 10;
+            let faded = (0xC0 + luma / 4) as u8;
+            diff.extend_from_slice(&[faded, faded, faded, 0xFF]);
+        }
+    }
+    (changed, diff)
+}
+
+
+#[test]
+fn test_diff_image() {
+    let expected = [0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+    let actual = [0, 0, 0, 0xFF, 0x80, 0x80, 0x80, 0xFF];
+    let (changed, diff) = diff_image(&actual, &expected);
+    assert_eq!(1, changed);
+    assert_eq!(&[0xC0, 0xC0, 0xC0, 0xFF, 0xFF, 0x00, 0x00, 0xFF], &diff[..]);
+}
+
+#[test]
+fn test_golden_run() {
+    use std::path::PathBuf;
+    use gameboy::Gameboy;
+    use gameboy::model::Model;
+    use gameboy::rom::Rom;
+
+    // Wait for VBlank and turn the LCD off, fill tile 1 with its own
+    // addresses, lay tiles 0 and 1 across the map in alternate columns,
+    // then turn the LCD back on and spin.
+    let program = [
+        0xF0, 0x44, 0xFE, 0x90, 0x20, 0xFA,
+        0xAF, 0xE0, 0x40,
+        0x21, 0x10, 0x80, 0x06, 0x10,
+        0x7D, 0x22, 0x05, 0x20, 0xFB,
+        0x21, 0x00, 0x98,
+        0x7D, 0xE6, 0x01, 0x22, 0x7C, 0xFE, 0x9C, 0x20, 0xF7,
+        0x3E, 0xE4, 0xE0, 0x47, 0x3E, 0x91, 0xE0, 0x40,
+        0x18, 0xFE,
+    ];
+    let mut data = vec![0; 0x8000];
+    data[0x100..0x100 + program.len()].copy_from_slice(&program);
+    let mut gameboy = Gameboy::new(Rom::from_bytes(data, "golden").unwrap(), Model::Dmg);
+    gameboy.skip_boot_rom();
+    for _ in 0..5 {
+        gameboy.run_frame();
+    }
+
+    let reference = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/stripes.png");
+    let ppu = &gameboy.mmu.ppu;
+    assert!(check(ppu.framebuffer(), ppu.screen_size(), &reference, false).unwrap());
+}
@@ -1,67 +1,127 @@
-use sdl2::render::Canvas;
-use sdl2::pixels::PixelFormatEnum;
-use sdl2::video::Window;
+use sdl2::render::{Canvas, Texture};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::video::{FullscreenType, Window};
 use sdl2::rect::Rect;
 
-const DISPLAY_WIDTH_PIXELS: u32 = 160;
-const DISPLAY_HEIGHT_PIXELS: u32 = 144;
-const SCALE: u32 = 5;
 const TITLE: &'static str = "BitRomney GB";
 // const BACKGROUND: (u8, u8, u8) = (155, 188, 15);
 
+// Frames are handed over as R, G, B, A bytes. SDL names packed formats
+// by their layout in a native-endian u32, so the matching format
+// depends on the host's byte order.
+#[cfg(target_endian = "little")]
+const PIXEL_FORMAT: PixelFormatEnum = PixelFormatEnum::ABGR8888;
+#[cfg(target_endian = "big")]
+const PIXEL_FORMAT: PixelFormatEnum = PixelFormatEnum::RGBA8888;
+
+
+// How the frame is fitted to a window that isn't an exact multiple of
+// the screen size.
+#[derive(Clone, Copy, Debug)]
+pub enum Scaling {
+    // Largest whole-number multiple that fits
+    Integer,
+    // Fill as much of the window as possible at the original aspect ratio
+    Aspect,
+}
+
+impl Scaling {
+    pub fn from_str(name: &str) -> Option<Scaling> {
+        match name {
+            "integer" => Some(Scaling::Integer),
+            "aspect" => Some(Scaling::Aspect),
+            _ => None,
+        }
+    }
+}
 
 pub struct Display {
     canvas: Canvas<Window>,
+    // Owned for the lifetime of the canvas; SDL frees it along with
+    // the renderer.
+    texture: Texture,
     width: u32,
     height: u32,
-    scale: u32,
+    scaling: Scaling,
 }
 
 impl Display {
-    pub fn new(context: ::sdl2::Sdl) -> Display {
+    // Frames are `width` by `height`, which is larger than the Game Boy's
+    // screen for the SGB.
+    pub fn new(
+        context: ::sdl2::Sdl,
+        scale: u32,
+        scaling: Scaling,
+        width: u32,
+        height: u32,
+    ) -> Display {
         let window = context
             .video()
             .unwrap()
-            .window(
-                TITLE,
-                DISPLAY_WIDTH_PIXELS * SCALE,
-                DISPLAY_HEIGHT_PIXELS * SCALE,
-            )
+            .window(TITLE, width * scale, height * scale)
             .position_centered()
+            .resizable()
             .opengl()
             .build()
             .unwrap();
-        let canvas = window.into_canvas().build().unwrap();
+        let mut canvas = window.into_canvas().build().unwrap();
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        let texture = canvas
+            .create_texture_streaming(PIXEL_FORMAT, width, height)
+            .unwrap();
 
         Display {
             canvas: canvas,
-            width: DISPLAY_WIDTH_PIXELS,
-            height: DISPLAY_HEIGHT_PIXELS,
-            scale: SCALE,
+            texture: texture,
+            width: width,
+            height: height,
+            scaling: scaling,
         }
     }
 
-    pub fn draw_frame(&mut self, data: [u8; 23_040 * 4]) {
-        let texture_creator = self.canvas.texture_creator();
-        let mut texture = texture_creator
-            .create_texture_streaming(PixelFormatEnum::ARGB8888, self.width, self.height)
+    pub fn draw_frame(&mut self, data: &[u8]) {
+        self.texture
+            .update(None, &data, (self.width * 4) as usize)
             .unwrap();
+        self.present();
+    }
 
-        texture
-            .update(Rect::new(0, 0, self.width, self.height), &data, 1)
-            .unwrap();
-        self.canvas
-            .copy(
-                &texture,
-                None,
-                Some(Rect::new(
-                    0,
-                    0,
-                    self.width * self.scale,
-                    self.height * self.scale,
-                )),
-            )
-            .unwrap();
+    // Redraw the most recent frame, e.g. after the window was resized.
+    pub fn present(&mut self) {
+        let target = self.target_rect();
+        self.canvas.clear();
+        self.canvas.copy(&self.texture, None, Some(target)).unwrap();
         self.canvas.present()
     }
+
+    pub fn toggle_fullscreen(&mut self) {
+        let state = match self.canvas.window().fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        if let Err(err) = self.canvas.window_mut().set_fullscreen(state) {
+            println!("Could not toggle fullscreen: {}", err);
+        }
+        self.present();
+    }
+
+    fn target_rect(&self) -> Rect {
+        let (out_w, out_h) = self.canvas.output_size().unwrap_or((self.width, self.height));
+        let (w, h) = match self.scaling {
+            Scaling::Integer => {
+                let scale = (out_w / self.width).min(out_h / self.height).max(1);
+                (self.width * scale, self.height * scale)
+            }
+            Scaling::Aspect => {
+                if out_w * self.height > out_h * self.width {
+                    (out_h * self.width / self.height, out_h)
+                } else {
+                    (out_w, out_w * self.height / self.width)
+                }
+            }
+        };
+        let x = (out_w as i32 - w as i32) / 2;
+        let y = (out_h as i32 - h as i32) / 2;
+        Rect::new(x, y, w, h)
+    }
 }
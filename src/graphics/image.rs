@@ -0,0 +1,50 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use png;
+use png::HasParameters;
+
+
+// Write RGBA pixel data to a PNG file.
+pub fn save_png(path: &Path, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    Ok(())
+}
+
+// Write 8-bit grayscale pixel data to a binary PGM file.
+pub fn save_pgm(path: &Path, width: u32, height: u32, gray: &[u8]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P5\n{} {}\n255\n", width, height)?;
+    file.write_all(gray)?;
+    file.flush()
+}
+
+// Read a PNG file as RGBA pixel data, returning its width and height
+// alongside. 16-bit and palette images are reduced to 8-bit RGBA.
+pub fn load_png(path: &Path) -> io::Result<(u32, u32, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data)?;
+    let rgba = match info.color_type {
+        png::ColorType::RGBA => data,
+        png::ColorType::RGB => {
+            data.chunks(3).flat_map(|px| vec![px[0], px[1], px[2], 0xFF]).collect()
+        }
+        png::ColorType::Grayscale => data.iter().flat_map(|&v| vec![v, v, v, 0xFF]).collect(),
+        png::ColorType::GrayscaleAlpha => {
+            data.chunks(2).flat_map(|px| vec![px[0], px[0], px[0], px[1]]).collect()
+        }
+        png::ColorType::Indexed => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpanded palette image"))
+        }
+    };
+    Ok((info.width, info.height, rgba))
+}
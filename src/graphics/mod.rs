@@ -1,5 +1,6 @@
 pub mod display;
-use std::fmt;
+pub mod image;
+pub mod scope;
 
 pub struct Control {
     pub lcd_enable: bool, // Can only be done during V-Blank
@@ -43,42 +44,6 @@ impl Control {
     }
 }
 
-pub struct Tile {
-    pub lines: Vec<[u8; 8]>,
-}
-
-impl Tile {
-    pub fn new(slice: &[u8]) -> Self {
-        let mut vec: Vec<[u8; 8]> = Vec::new();
-        for bytes in slice.chunks(2) {
-            let mut line: [u8; 8] = [0; 8];
-            for i in 0..8 {
-                line[7 - i] = (bytes[0] >> i & 1) + (bytes[0] >> i & 1);
-            }
-            vec.push(line);
-        }
-        Tile { lines: vec }
-    }
-}
-
-impl fmt::Debug for Tile {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let lines = &self.lines;
-        let mut rv: Vec<String> = Vec::new();
-        for line in lines.into_iter() {
-            let l = line.into_iter()
-                .map(|x| match *x {
-                    0 => "_".to_string(),
-                    _ => format!("{}", x),
-                })
-                .collect::<Vec<_>>()
-                .join(" ");
-            rv.push(l);
-        }
-        write!(f, "{}", rv.into_iter().collect::<Vec<_>>().join("\n"))
-    }
-}
-
 pub enum StatMode {
     Hblank, // LCD Controller is in H-Blank period
     Vblank, // LCD Controller is in V-blank period
@@ -172,11 +137,61 @@ impl Shade {
             &Shade::Black => [0, 0, 0, 0xFF],
             &Shade::DarkGray => [80, 80, 80, 0xFF],
             &Shade::LightGray => [140, 140, 140, 0xFF],
-            &Shade::White => [15, 188, 155, 0xFF],
+            &Shade::White => [155, 188, 15, 0xFF],
         }
     }
 }
 
+/// The colours used to draw the four shades on screen.
+#[derive(Clone, Copy, Debug)]
+pub enum ColorScheme {
+    Green,
+    Gray,
+    Pocket,
+}
+
+impl ColorScheme {
+    pub fn from_str(name: &str) -> Option<ColorScheme> {
+        match name {
+            "green" => Some(ColorScheme::Green),
+            "gray" | "grey" => Some(ColorScheme::Gray),
+            "pocket" => Some(ColorScheme::Pocket),
+            _ => None,
+        }
+    }
+    pub fn to_rgba(&self, shade: &Shade) -> [u8; 4] {
+        match *self {
+            ColorScheme::Green => shade.to_rgba(),
+            ColorScheme::Gray => {
+                match *shade {
+                    Shade::Black => [0, 0, 0, 0xFF],
+                    Shade::DarkGray => [85, 85, 85, 0xFF],
+                    Shade::LightGray => [170, 170, 170, 0xFF],
+                    Shade::White => [255, 255, 255, 0xFF],
+                }
+            }
+            ColorScheme::Pocket => {
+                match *shade {
+                    Shade::Black => [31, 31, 31, 0xFF],
+                    Shade::DarkGray => [77, 83, 60, 0xFF],
+                    Shade::LightGray => [139, 149, 109, 0xFF],
+                    Shade::White => [196, 207, 161, 0xFF],
+                }
+            }
+        }
+    }
+}
+
+// Expand a CGB colour, 5 bits each of red, green and blue from the low
+// bits up, to RGBA bytes.
+pub fn rgb555_to_rgba(color: u16) -> [u8; 4] {
+    let channel = |shift: u16| {
+        let value = ((color >> shift) & 0x1F) as u8;
+        value << 3 | value >> 2
+    };
+    [channel(0), channel(5), channel(10), 0xFF]
+}
+
 pub struct Palette {
     color_3: Shade,
     color_2: Shade,
@@ -199,6 +214,10 @@ impl Palette {
         self.color_1 = Shade::from_u8((byte >> 2) & 0b11);
         self.color_0 = Shade::from_u8(byte & 0b11);
     }
+    // The shade colour number `color` (0-3) is drawn in.
+    pub fn shade(&self, color: u8) -> Shade {
+        Shade::from_u8((self.read_u8() >> (color * 2)) & 0b11)
+    }
     pub fn read_u8(&self) -> u8 {
         let c3 = self.color_3.to_u8();
         let c2 = self.color_2.to_u8();
@@ -0,0 +1,161 @@
+use sdl2::VideoSubsystem;
+use sdl2::pixels::Color;
+use sdl2::rect::{Point, Rect};
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+use gameboy::apu::ChannelInfo;
+
+
+const LANE_WIDTH: u32 = 480;
+const LANE_HEIGHT: u32 = 96;
+// Input samples per horizontal pixel, so a lane shows about 7 ms.
+const SAMPLES_PER_PIXEL: usize = 2;
+const TEXT_SCALE: i32 = 2;
+const NAMES: [&'static str; 4] = ["SQUARE 1", "SQUARE 2", "WAVE", "NOISE"];
+const COLORS: [(u8, u8, u8); 4] = [
+    (240, 200, 60),
+    (90, 200, 240),
+    (120, 230, 120),
+    (230, 110, 180),
+];
+
+
+// A window showing the recent output of each sound channel alongside
+// what its registers are set to play.
+pub struct Scope {
+    canvas: Canvas<Window>,
+}
+
+impl Scope {
+    pub fn new(video: &VideoSubsystem) -> Result<Scope, String> {
+        let window = video
+            .window("Channels", LANE_WIDTH, LANE_HEIGHT * 4)
+            .build()
+            .map_err(|err| err.to_string())?;
+        let canvas = window.into_canvas().build().map_err(|err| err.to_string())?;
+        Ok(Scope { canvas: canvas })
+    }
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+    // Draw one frame's worth of per-channel samples, four to a frame.
+    pub fn draw(&mut self, samples: &[f32], channels: &[ChannelInfo]) {
+        self.canvas.set_draw_color(Color::RGB(16, 16, 24));
+        self.canvas.clear();
+        for (i, info) in channels.iter().enumerate() {
+            let top = (LANE_HEIGHT * i as u32) as i32;
+            let middle = top + LANE_HEIGHT as i32 / 2;
+            self.canvas.set_draw_color(Color::RGB(48, 48, 64));
+            let _ = self.canvas.draw_line((0, middle), (LANE_WIDTH as i32, middle));
+            let _ = self.canvas.draw_line((0, top), (LANE_WIDTH as i32, top));
+
+            let (r, g, b) = COLORS[i];
+            let color = match info.enabled && !info.muted {
+                true => Color::RGB(r, g, b),
+                false => Color::RGB(r / 3, g / 3, b / 3),
+            };
+            self.canvas.set_draw_color(color);
+            let channel: Vec<f32> = samples.iter().skip(i).step_by(4).cloned().collect();
+            let start = trigger(&channel, LANE_WIDTH as usize * SAMPLES_PER_PIXEL);
+            let amplitude = (LANE_HEIGHT / 2 - 8) as f32;
+            let points: Vec<Point> = channel[start..]
+                .iter()
+                .step_by(SAMPLES_PER_PIXEL)
+                .take(LANE_WIDTH as usize)
+                .enumerate()
+                .map(|(x, level)| Point::new(x as i32, middle - (level * amplitude) as i32))
+                .collect();
+            if points.len() > 1 {
+                let _ = self.canvas.draw_lines(&points[..]);
+            }
+
+            self.draw_text(&describe(i, info), 4, top + 4);
+        }
+        self.canvas.present();
+    }
+    fn draw_text(&mut self, text: &str, x: i32, y: i32) {
+        let mut rects = Vec::new();
+        for (n, c) in text.chars().enumerate() {
+            let left = x + n as i32 * 4 * TEXT_SCALE;
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) != 0 {
+                        let px = left + col * TEXT_SCALE;
+                        let py = y + row as i32 * TEXT_SCALE;
+                        rects.push(Rect::new(px, py, TEXT_SCALE as u32, TEXT_SCALE as u32));
+                    }
+                }
+            }
+        }
+        let _ = self.canvas.fill_rects(&rects);
+    }
+}
+
+fn describe(channel: usize, info: &ChannelInfo) -> String {
+    let mut text = format!("{}  {:.1} HZ  VOL {}", NAMES[channel], info.frequency, info.volume);
+    if let Some(duty) = info.duty {
+        text.push_str(&format!("  DUTY {}%", duty));
+    }
+    if info.muted {
+        text.push_str("  MUTED");
+    } else if !info.enabled {
+        text.push_str("  OFF");
+    }
+    text
+}
+
+// Start at the first rising edge that leaves enough samples to fill the
+// lane, so a steady tone doesn't jitter from frame to frame.
+fn trigger(samples: &[f32], width: usize) -> usize {
+    let end = samples.len().saturating_sub(width);
+    (0..end)
+        .find(|&i| samples[i] < samples[i + 1])
+        .unwrap_or(0)
+}
+
+// Rows of a 3x5 pixel font, high bit on the left.
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; 5],
+    }
+}
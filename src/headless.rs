@@ -0,0 +1,94 @@
+use audio::vgm::VgmWriter;
+use audio::wav::AudioRecorder;
+use gameboy::Gameboy;
+use link::LocalLink;
+use movie::Session;
+use script::Script;
+
+
+// Run the emulator without a window or debugger. Without a frame
+// limit this runs until a movie being played or an input script ends,
+// or otherwise until the process is killed. A script's input is what
+// gets recorded when a movie is being made; only the first joypad is
+// driven then, as movies hold nothing else. Machines joined by a local
+// link cable or four player adapter run alongside without any input.
+pub fn run(
+    gameboy: &mut Gameboy,
+    frame_limit: Option<u64>,
+    mut movie: Option<Session>,
+    mut script: Option<Script>,
+    mut audio: Option<AudioRecorder>,
+    mut vgm: Option<VgmWriter>,
+    mut link: Option<LocalLink>,
+) {
+    loop {
+        if let Some(limit) = frame_limit {
+            if gameboy.frames() >= limit {
+                break;
+            }
+        }
+        let mut held = 0;
+        if let Some(mut current) = script.take() {
+            match current.frame(gameboy) {
+                Some(buttons) => {
+                    held = buttons;
+                    // Movies only hold the first joypad's input
+                    if movie.is_none() {
+                        for player in 1..4 {
+                            gameboy.set_player_buttons(player, current.player_buttons(player));
+                        }
+                    }
+                    script = Some(current);
+                }
+                None if frame_limit.is_none() => break,
+                None => {}
+            }
+        }
+        if let Some(mut session) = movie.take() {
+            if session.frame(gameboy, held) {
+                movie = Some(session);
+            } else {
+                session.finish(gameboy);
+                if frame_limit.is_none() {
+                    break;
+                }
+            }
+        } else {
+            gameboy.set_buttons(held);
+        }
+        match link {
+            Some(ref mut link) => link.run_frame(gameboy),
+            None => gameboy.run_frame(),
+        }
+        if let Some(mut recorder) = audio.take() {
+            let samples = gameboy.mmu.apu.take_samples();
+            let channel_samples = gameboy.mmu.apu.take_channel_samples();
+            match recorder.write(&samples, &channel_samples) {
+                Ok(()) => audio = Some(recorder),
+                Err(err) => {
+                    eprintln!("Stopped recording audio: {}", err);
+                    recorder.finish();
+                }
+            }
+        }
+        if let Some(mut writer) = vgm.take() {
+            let (writes, cycles) = gameboy.mmu.apu.take_writes();
+            match writer.write(&writes, cycles) {
+                Ok(()) => vgm = Some(writer),
+                Err(err) => {
+                    eprintln!("Stopped logging sound registers: {}", err);
+                    gameboy.mmu.apu.stop_log();
+                }
+            }
+        }
+    }
+    if let Some(session) = movie {
+        session.finish(gameboy);
+    }
+    if let Some(recorder) = audio {
+        recorder.finish();
+    }
+    if let Some(writer) = vgm {
+        writer.finish(&mut gameboy.mmu.apu);
+    }
+}
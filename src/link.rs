@@ -0,0 +1,288 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::rc::Rc;
+
+use gameboy::Gameboy;
+use gameboy::serial::{Connection, Peer};
+
+
+// Neither machine gets further ahead of the other than this many cycles,
+// the time an internally clocked byte takes.
+const SYNC_CYCLES: u32 = 4096;
+// Addresses starting with this name a Unix socket rather than a TCP one.
+const UNIX_PREFIX: &'static str = "unix:";
+
+// Messages are two bytes, a kind and a value.
+const TRANSFER: u8 = 1;
+const REPLY: u8 = 2;
+const SYNC: u8 = 3;
+
+
+// A link cable to another emulator process over a local socket. Each
+// side stops every SYNC_CYCLES until the other has caught up, and
+// answers transfers clocked by the other side while it waits.
+pub struct SocketPeer<S: Read + Write> {
+    stream: S,
+    connected: bool,
+    cycles: u32,
+    // SB as of the last poll, if waiting on an external clock
+    ready: Option<u8>,
+    received: Option<u8>,
+    // Syncs received from the other side less those sent to it
+    syncs: i64,
+}
+
+impl<S: Read + Write> SocketPeer<S> {
+    pub fn new(stream: S) -> SocketPeer<S> {
+        SocketPeer {
+            stream: stream,
+            connected: true,
+            cycles: 0,
+            ready: None,
+            received: None,
+            syncs: 0,
+        }
+    }
+    fn send(&mut self, kind: u8, value: u8) {
+        if self.connected {
+            if let Err(err) = self.stream.write_all(&[kind, value]) {
+                self.disconnect(err);
+            }
+        }
+    }
+    // Wait for and deal with the next message, giving it back.
+    fn handle(&mut self) -> Option<(u8, u8)> {
+        if !self.connected {
+            return None;
+        }
+        let mut message = [0; 2];
+        if let Err(err) = self.stream.read_exact(&mut message) {
+            self.disconnect(err);
+            return None;
+        }
+        match message[0] {
+            TRANSFER => {
+                let reply = match self.ready.take() {
+                    Some(byte) => {
+                        self.received = Some(message[1]);
+                        byte
+                    }
+                    None => 0xFF,
+                };
+                self.send(REPLY, reply);
+            }
+            SYNC => self.syncs += 1,
+            _ => {}
+        }
+        Some((message[0], message[1]))
+    }
+    fn disconnect(&mut self, err: io::Error) {
+        println!("Link cable disconnected: {}", err);
+        self.connected = false;
+    }
+}
+
+impl<S: Read + Write> Peer for SocketPeer<S> {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.ready = None;
+        self.send(TRANSFER, byte);
+        loop {
+            match self.handle() {
+                Some((REPLY, reply)) => return reply,
+                Some(_) => {}
+                None => return 0xFF,
+            }
+        }
+    }
+    fn poll(&mut self, cycles: u32, ready: Option<u8>) -> Option<u8> {
+        self.ready = ready;
+        self.cycles += cycles;
+        if self.cycles >= SYNC_CYCLES {
+            self.cycles -= SYNC_CYCLES;
+            self.send(SYNC, 0);
+            self.syncs -= 1;
+            while self.syncs < 0 && self.handle().is_some() {}
+        }
+        self.received.take()
+    }
+}
+
+// Wait for the other emulator to connect to ADDRESS, which is either
+// HOST:PORT or unix:PATH.
+pub fn listen(address: &str) -> io::Result<Connection> {
+    println!("Waiting for the other end of the link cable on {}", address);
+    let peer = accept(address, 1)?.remove(0);
+    println!("Link cable connected");
+    Ok(Connection::Peer(peer))
+}
+
+// Wait for `count` emulators to connect to ADDRESS.
+pub fn accept(address: &str, count: usize) -> io::Result<Vec<Box<Peer>>> {
+    let mut peers: Vec<Box<Peer>> = Vec::new();
+    match unix_path(address) {
+        Some(path) => {
+            // Clear out a socket left behind by an earlier run.
+            if let Ok(metadata) = fs::metadata(path) {
+                if metadata.file_type().is_socket() {
+                    fs::remove_file(path)?;
+                }
+            }
+            let listener = UnixListener::bind(path)?;
+            while peers.len() < count {
+                let (stream, _) = listener.accept()?;
+                peers.push(Box::new(SocketPeer::new(stream)));
+            }
+        }
+        None => {
+            let listener = TcpListener::bind(address)?;
+            while peers.len() < count {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                peers.push(Box::new(SocketPeer::new(stream)));
+            }
+        }
+    }
+    Ok(peers)
+}
+
+pub fn connect(address: &str) -> io::Result<Connection> {
+    let peer: Box<Peer> = match unix_path(address) {
+        Some(path) => Box::new(SocketPeer::new(UnixStream::connect(path)?)),
+        None => {
+            let stream = TcpStream::connect(address)?;
+            stream.set_nodelay(true)?;
+            Box::new(SocketPeer::new(stream))
+        }
+    };
+    println!("Link cable connected to {}", address);
+    Ok(Connection::Peer(peer))
+}
+
+fn unix_path(address: &str) -> Option<&str> {
+    match address.starts_with(UNIX_PREFIX) {
+        true => Some(&address[UNIX_PREFIX.len()..]),
+        false => None,
+    }
+}
+
+#[derive(Default)]
+struct Port {
+    ready: Option<u8>,
+    received: Option<u8>,
+}
+
+// One end of a cable between two machines in the same process.
+pub struct LocalPeer {
+    ports: Rc<RefCell<[Port; 2]>>,
+    side: usize,
+}
+
+impl Peer for LocalPeer {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut ports = self.ports.borrow_mut();
+        ports[self.side].ready = None;
+        let other = &mut ports[1 - self.side];
+        match other.ready.take() {
+            Some(reply) => {
+                other.received = Some(byte);
+                reply
+            }
+            None => 0xFF,
+        }
+    }
+    fn poll(&mut self, _: u32, ready: Option<u8>) -> Option<u8> {
+        let mut ports = self.ports.borrow_mut();
+        let port = &mut ports[self.side];
+        port.ready = ready;
+        port.received.take()
+    }
+}
+
+// Both ends of a cable for machines in the same process.
+pub fn cable() -> (LocalPeer, LocalPeer) {
+    let ports = Rc::new(RefCell::new([Port::default(), Port::default()]));
+    let first = LocalPeer {
+        ports: ports.clone(),
+        side: 0,
+    };
+    (first, LocalPeer { ports: ports, side: 1 })
+}
+
+// More machines in the same process, linked to the first and run in
+// step with it so runs are deterministic.
+pub struct LocalLink {
+    pub others: Vec<Gameboy>,
+    // Cycles each other machine has run beyond the first
+    leads: Vec<i64>,
+}
+
+impl LocalLink {
+    // The other machines should already be plugged in to something.
+    pub fn new(others: Vec<Gameboy>) -> LocalLink {
+        LocalLink {
+            leads: vec![0; others.len()],
+            others: others,
+        }
+    }
+    // Join a second machine to the first with a link cable.
+    pub fn pair(first: &mut Gameboy, mut other: Gameboy) -> LocalLink {
+        let (peer, other_peer) = cable();
+        first.mmu.serial.connect(Connection::Peer(Box::new(peer)));
+        other.mmu.serial.connect(Connection::Peer(Box::new(other_peer)));
+        LocalLink::new(vec![other])
+    }
+    // Run until the first machine finishes a frame, always stepping
+    // whichever machine is furthest behind.
+    pub fn run_frame(&mut self, first: &mut Gameboy) {
+        let frame = first.frames();
+        while first.frames() == frame {
+            let behind = self.leads.iter().enumerate().min_by_key(|&(_, &lead)| lead);
+            match behind {
+                Some((i, &lead)) if lead < 0 => self.leads[i] += self.others[i].step() as i64,
+                _ => {
+                    let cycles = first.step() as i64;
+                    for lead in &mut self.leads {
+                        *lead -= cycles;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_linked_transfer() {
+    use gameboy::model::Model;
+    use gameboy::rom::Rom;
+
+    // Put `byte` in SB, start a transfer with `control`, wait for it to
+    // finish and store what was received at C000.
+    fn machine(delay: u8, byte: u8, control: u8) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        let code = [
+            0x06, delay, 0x05, 0x20, 0xFD, // LD B,delay; DEC B; JR NZ
+            0x3E, byte, 0xE0, 0x01, // LD A,byte; LDH (01),A
+            0x3E, control, 0xE0, 0x02, // LD A,control; LDH (02),A
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, // LDH A,(02); BIT 7,A; JR NZ
+            0xF0, 0x01, 0xEA, 0x00, 0xC0, // LDH A,(01); LD (C000),A
+            0x18, 0xFE,
+        ];
+        rom[0x150..0x150 + code.len()].copy_from_slice(&code);
+        let mut gameboy = Gameboy::new(Rom::from_bytes(rom, "link").unwrap(), Model::Dmg);
+        gameboy.skip_boot_rom();
+        gameboy
+    }
+
+    // The master waits a little so the slave is ready for its byte.
+    let mut master = machine(0x20, 0x42, 0x81);
+    let mut link = LocalLink::pair(&mut master, machine(1, 0x99, 0x80));
+    link.run_frame(&mut master);
+    assert_eq!(0x99, master.mmu.read(0xC000));
+    assert_eq!(0x42, link.others[0].mmu.read(0xC000));
+}
@@ -1,54 +1,370 @@
 extern crate sdl2;
+extern crate clap;
+extern crate png;
 
+mod adapter;
+mod audio;
 mod bitty;
 mod gameboy;
+mod gbs;
+mod golden;
 mod graphics;
 mod debugger;
+mod headless;
+mod link;
+mod movie;
+mod options;
+mod printer;
+mod rewind;
+mod script;
+mod savestate;
+mod testrom;
+mod throttle;
 
 
+use adapter::Adapter;
+use audio::Audio;
+use audio::vgm::VgmWriter;
+use audio::wav::AudioRecorder;
 use debugger::Debugger;
 use gameboy::Gameboy;
+use gameboy::cheats::Cheats;
+use gameboy::model::Model;
 use gameboy::rom::Rom;
+use gameboy::serial::Connection;
+use gbs::GbsPlayer;
+use link::LocalLink;
+use movie::{Movie, Player, Recorder, Session, Start};
+use script::Script;
+use options::{BootRom, Options};
+use printer::Printer;
+use savestate::SaveSlots;
 
 #[macro_use]
 extern crate log;
 extern crate log4rs;
 
-use log::LogLevelFilter;
 use log4rs::append::file::FileAppender;
 use log4rs::encode::pattern::PatternEncoder;
 use log4rs::config::{Appender, Config, Root};
 use graphics::display::Display;
-use std::env;
+use graphics::scope::Scope;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
 
 fn main() {
+    let options = Options::from_args();
+
     let logfile = FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{l} - {m}\n")))
-        .build("log/output.log")
-        .unwrap();
+        .build(&options.log_file)
+        .unwrap_or_else(|err| {
+            fail(&format!("Could not open log file {}: {}", options.log_file.display(), err))
+        });
 
     let config = Config::builder()
         .appender(Appender::builder().build("logfile", Box::new(logfile)))
         .build(Root::builder().appender("logfile").build(
-            LogLevelFilter::Info,
+            options.log_level,
         ))
         .unwrap();
 
     log4rs::init_config(config).unwrap();
 
-    let context = ::sdl2::init().unwrap();
+    if options.test {
+        let junit = options.junit.as_ref().map(|path| path.as_path());
+        match testrom::run(&options.rom_path, options.test_timeout, junit) {
+            Ok(true) => return,
+            Ok(false) => process::exit(1),
+            Err(err) => fail(&format!("Could not run tests: {}", err)),
+        }
+    }
+
+    if options.is_gbs() {
+        play_gbs(&options);
+        return;
+    }
+
+    let mut gameboy = load_gameboy(&options.rom_path, &options);
+
+    let battery_path = options.battery_path();
+    if gameboy.mmu.has_battery() {
+        if let Ok(data) = read_file(&battery_path) {
+            gameboy.mmu.load_sram(&data);
+        }
+    }
+
+    if options.serial_stdout {
+        gameboy.mmu.serial.connect(Connection::Stdout);
+    }
+    let link = match (&options.link_listen, &options.link_connect) {
+        (&Some(ref address), _) => Some((address, link::listen(address))),
+        (_, &Some(ref address)) => Some((address, link::connect(address))),
+        _ => None,
+    };
+    match link {
+        Some((_, Ok(connection))) => {
+            gameboy.mmu.serial.connect(connection);
+        }
+        Some((address, Err(err))) => fail(&format!("Could not link to {}: {}", address, err)),
+        None => {}
+    }
+    if let Some(ref directory) = options.printer {
+        let printer = Printer::new(directory.clone(), options.print_format);
+        gameboy.mmu.serial.connect(Connection::Peer(Box::new(printer)));
+    }
+    let mut local_link = options.link_local.as_ref().map(|path| {
+        LocalLink::pair(&mut gameboy, load_gameboy(path, &options))
+    });
+    if options.uses_adapter() {
+        let mut adapter = Adapter::new();
+        let others: Vec<Gameboy> = options.adapter_local.iter().map(|path| {
+            let mut other = load_gameboy(path, &options);
+            adapter.plug_local(&mut other);
+            other
+        }).collect();
+        if let Some(ref address) = options.adapter_listen {
+            let count = options.adapter_remotes;
+            println!("Waiting for {} more players on {}", count, address);
+            match link::accept(address, count) {
+                Ok(peers) => for peer in peers {
+                    adapter.plug(peer);
+                },
+                Err(err) => fail(&format!("Could not listen on {}: {}", address, err)),
+            }
+            println!("All players connected");
+        }
+        gameboy.mmu.serial.connect(Connection::Peer(Box::new(adapter)));
+        if !others.is_empty() {
+            local_link = Some(LocalLink::new(others));
+        }
+    }
 
-    let filename = env::args().nth(1).unwrap();
-    let filepath = format!("./{}", filename);
-    let rom = Rom::new(&*filepath);
-    let mut gameboy = Gameboy::new(rom);
+    let movie = if let Some(ref path) = options.record_movie {
+        Some(Session::Recording(Recorder::start(&gameboy, Start::PowerOn, path)))
+    } else if let Some(ref path) = options.play_movie {
+        let player = Movie::load(path).and_then(|movie| Player::start(&mut gameboy, movie));
+        match player {
+            Ok(player) => Some(Session::Playing(player)),
+            Err(err) => fail(&format!("Could not play movie {}: {}", path.display(), err)),
+        }
+    } else {
+        None
+    };
+
+    let script = options.script.as_ref().map(|path| match Script::load(path) {
+        Ok(script) => script,
+        Err(err) => fail(&format!("Could not load script: {}", err)),
+    });
+
+    let audio_recorder = options.record_audio.as_ref().map(|path| {
+        let apu = &mut gameboy.mmu.apu;
+        match AudioRecorder::start(apu, path, options.audio_rate, options.audio_channels) {
+            Ok(recorder) => recorder,
+            Err(err) => fail(&format!("Could not record audio to {}: {}", path.display(), err)),
+        }
+    });
+
+    let vgm = options.record_vgm.as_ref().map(|path| {
+        match VgmWriter::start(&mut gameboy.mmu.apu, path) {
+            Ok(writer) => writer,
+            Err(err) => fail(&format!("Could not log to {}: {}", path.display(), err)),
+        }
+    });
+
+    if options.headless {
+        let limit = options.frame_limit;
+        headless::run(&mut gameboy, limit, movie, script, audio_recorder, vgm, local_link);
+        save_battery(&gameboy, &battery_path);
+        if let Some(ref reference) = options.golden {
+            let ppu = &gameboy.mmu.ppu;
+            match golden::check(ppu.framebuffer(), ppu.screen_size(), reference, options.bless) {
+                Ok(true) => {}
+                Ok(false) => process::exit(1),
+                Err(err) => {
+                    fail(&format!("Could not compare with {}: {}", reference.display(), err))
+                }
+            }
+        }
+        return;
+    }
+
+    let context = ::sdl2::init().unwrap();
 
     let event_pump = context.event_pump();
-    let mut display = Display::new(context);
+    let audio = match options.audio {
+        true => {
+            let rate = options.audio_rate;
+            match context.audio().and_then(|subsystem| Audio::new(&subsystem, rate)) {
+                Ok(audio) => Some(audio),
+                Err(err) => {
+                    eprintln!("Could not open audio device, continuing without sound: {}", err);
+                    None
+                }
+            }
+        }
+        false => None,
+    };
+    let scope = match options.scope {
+        true => {
+            match context.video().and_then(|video| Scope::new(&video)) {
+                Ok(scope) => Some(scope),
+                Err(err) => {
+                    eprintln!("Could not open the channel window: {}", err);
+                    None
+                }
+            }
+        }
+        false => None,
+    };
+    let (width, height) = gameboy.mmu.ppu.screen_size();
+    let display = Rc::new(RefCell::new(Display::new(
+        context,
+        options.scale,
+        options.scaling,
+        width as u32,
+        height as u32,
+    )));
+    let frame_display = display.clone();
     gameboy.mmu.ppu.set_on_refresh(Box::new(
-        move |arr| { display.draw_frame(arr); },
+        move |arr| { frame_display.borrow_mut().draw_frame(arr); },
     ));
 
     let mut debugger = Debugger::new(gameboy, event_pump.unwrap());
+    debugger.set_display(display);
+    if let Some(mut audio) = audio {
+        audio.set_volume(options.volume);
+        audio.set_sync(options.audio_sync);
+        debugger.set_audio(audio);
+    }
+    debugger.set_record_format(options.audio_rate, options.audio_channels);
+    if let Some(recorder) = audio_recorder {
+        debugger.set_audio_recorder(recorder);
+    }
+    if let Some(writer) = vgm {
+        debugger.set_vgm(writer);
+    }
+    if let Some(scope) = scope {
+        debugger.set_scope(scope);
+    }
+    debugger.set_save_slots(SaveSlots::new(&options.save_dir, &options.rom_path));
+    debugger.set_cheat_path(options.rom_path.with_extension("cht"));
+    debugger.set_frame_limit(options.frame_limit);
+    if let Some(session) = movie {
+        debugger.set_movie(session);
+    }
+    if !options.debug {
+        debugger.resume();
+    }
     debugger.run();
+    save_battery(debugger.gameboy(), &battery_path);
+}
+
+fn play_gbs(options: &Options) {
+    let mut player = GbsPlayer::load(&options.rom_path).unwrap_or_else(|err| {
+        fail(&format!("Could not load {}: {}", options.rom_path.display(), err))
+    });
+    if let Some(track) = options.track {
+        player.set_track(track - 1);
+    }
+
+    if options.headless {
+        if options.record_audio.is_none() && options.record_vgm.is_none() {
+            fail("Playing a .gbs file headless needs --record-audio or --record-vgm.");
+        }
+        if options.audio_channels {
+            player.record_channels();
+        }
+        let recorder = options.record_audio.as_ref().map(|path| {
+            let apu = &mut player.gameboy_mut().mmu.apu;
+            AudioRecorder::start(apu, path, options.audio_rate, options.audio_channels)
+                .unwrap_or_else(|err| {
+                    fail(&format!("Could not record audio to {}: {}", path.display(), err))
+                })
+        });
+        let vgm = options.record_vgm.as_ref().map(|path| {
+            VgmWriter::start(&mut player.gameboy_mut().mmu.apu, path).unwrap_or_else(|err| {
+                fail(&format!("Could not log to {}: {}", path.display(), err))
+            })
+        });
+        gbs::run_headless(&mut player, options.frame_limit, recorder, vgm);
+        return;
+    }
+
+    let context = ::sdl2::init().unwrap();
+    let rate = options.audio_rate;
+    let audio = match context.audio().and_then(|subsystem| Audio::new(&subsystem, rate)) {
+        Ok(mut audio) => {
+            audio.set_volume(options.volume);
+            Some(audio)
+        }
+        Err(err) => {
+            eprintln!("Could not open audio device: {}", err);
+            None
+        }
+    };
+    gbs::run_window(&mut player, &context, audio);
+}
+
+// Load a ROM into a new machine set up as the options ask.
+fn load_gameboy(path: &Path, options: &Options) -> Gameboy {
+    let rom_path = path.to_string_lossy().into_owned();
+    let rom = Rom::new(&rom_path).unwrap_or_else(|err| {
+        fail(&format!("Could not load ROM {}: {}", rom_path, err))
+    });
+    let model = options.model.unwrap_or_else(|| Model::for_rom(&rom));
+    let mut gameboy = Gameboy::new(rom, model);
+    gameboy.mmu.ppu.set_color_scheme(options.color_scheme);
+
+    match options.boot_rom {
+        // The builtin boot ROM is the DMG's, which can't start the CGB.
+        BootRom::Builtin if model.is_cgb() => gameboy.skip_boot_rom(),
+        BootRom::Builtin => {}
+        BootRom::Skip => gameboy.skip_boot_rom(),
+        BootRom::File(ref path) => {
+            let data = read_file(path).unwrap_or_else(|err| {
+                fail(&format!("Could not load boot ROM {}: {}", path.display(), err))
+            });
+            match (model.is_cgb(), data.len()) {
+                (false, 0x100) | (true, 0x900) => {}
+                (false, _) => fail("The boot ROM must be exactly 256 bytes."),
+                (true, _) => fail("A CGB boot ROM must be exactly 2304 bytes."),
+            }
+            gameboy.set_boot_rom(data);
+        }
+    }
+
+    let cheat_path = path.with_extension("cht");
+    if cheat_path.exists() {
+        gameboy.mmu.cheats = Cheats::load(&cheat_path).unwrap_or_else(|err| {
+            fail(&format!("Could not load cheats from {}", err))
+        });
+    }
+    gameboy
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn save_battery(gameboy: &Gameboy, path: &Path) {
+    if !gameboy.mmu.has_battery() {
+        return;
+    }
+    let result = File::create(path).and_then(|mut file| file.write_all(gameboy.mmu.sram()));
+    if let Err(err) = result {
+        eprintln!("Could not write save file {}: {}", path.display(), err);
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
 }
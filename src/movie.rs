@@ -0,0 +1,379 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use bitty::crc32;
+use gameboy::Gameboy;
+use gameboy::cheats::Cheats;
+use gameboy::state::{StateError, StateReader, StateWriter};
+
+
+// Movie files hold a header with the cheat codes in use, the machine
+// state the movie starts from, one byte of joypad state per frame, and a
+// hash of the machine state every HASH_INTERVAL frames for spotting
+// desyncs.
+const MAGIC: &'static [u8; 4] = b"GRMV";
+const VERSION: u32 = 3;
+const EMULATOR_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const HASH_INTERVAL: u32 = 60;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Start {
+    PowerOn,
+    SaveState,
+}
+
+pub struct Movie {
+    emulator_version: String,
+    rom_checksum: u32,
+    start: Start,
+    // Enabled cheat codes, which can't change during the movie
+    cheats: Vec<String>,
+    state: Vec<u8>,
+    inputs: Vec<u8>,
+    // (frame, hash of the machine state as that frame began)
+    hashes: Vec<(u32, u32)>,
+}
+
+impl Movie {
+    pub fn load(path: &Path) -> Result<Movie, StateError> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let mut movie = StateReader::new(&data);
+
+        if movie.read_bytes(4)? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = movie.read_u32()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let len = movie.read_u8()? as usize;
+        let emulator_version = String::from_utf8_lossy(movie.read_bytes(len)?).into_owned();
+        let rom_checksum = movie.read_u32()?;
+        let start = match movie.read_u8()? {
+            0 => Start::PowerOn,
+            1 => Start::SaveState,
+            _ => return Err(StateError::Invalid("movie start")),
+        };
+        let mut cheats = Vec::new();
+        for _ in 0..movie.read_u32()? {
+            let len = movie.read_u8()? as usize;
+            cheats.push(String::from_utf8_lossy(movie.read_bytes(len)?).into_owned());
+        }
+        let len = movie.read_u32()? as usize;
+        let state = movie.read_bytes(len)?.to_vec();
+        let len = movie.read_u32()? as usize;
+        let inputs = movie.read_bytes(len)?.to_vec();
+        let count = movie.read_u32()?;
+        let mut hashes = Vec::new();
+        for _ in 0..count {
+            let frame = movie.read_u32()?;
+            let hash = movie.read_u32()?;
+            hashes.push((frame, hash));
+        }
+
+        Ok(Movie {
+            emulator_version: emulator_version,
+            rom_checksum: rom_checksum,
+            start: start,
+            cheats: cheats,
+            state: state,
+            inputs: inputs,
+            hashes: hashes,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), StateError> {
+        let mut movie = StateWriter::new();
+        movie.write_bytes(MAGIC);
+        movie.write_u32(VERSION);
+        movie.write_u8(self.emulator_version.len() as u8);
+        movie.write_bytes(self.emulator_version.as_bytes());
+        movie.write_u32(self.rom_checksum);
+        movie.write_u8(match self.start {
+            Start::PowerOn => 0,
+            Start::SaveState => 1,
+        });
+        movie.write_u32(self.cheats.len() as u32);
+        for code in self.cheats.iter() {
+            movie.write_u8(code.len() as u8);
+            movie.write_bytes(code.as_bytes());
+        }
+        movie.write_u32(self.state.len() as u32);
+        movie.write_bytes(&self.state);
+        movie.write_u32(self.inputs.len() as u32);
+        movie.write_bytes(&self.inputs);
+        movie.write_u32(self.hashes.len() as u32);
+        for &(frame, hash) in self.hashes.iter() {
+            movie.write_u32(frame);
+            movie.write_u32(hash);
+        }
+        File::create(path)?.write_all(&movie.into_bytes())?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+}
+
+fn state_hash(gameboy: &Gameboy) -> u32 {
+    crc32(&gameboy.save_state())
+}
+
+
+pub struct Recorder {
+    movie: Movie,
+    path: PathBuf,
+    start_frame: u64,
+}
+
+impl Recorder {
+    // Begin recording from the machine's current state. `start` only
+    // records whether that state is power-on, for the reader's benefit.
+    pub fn start(gameboy: &Gameboy, start: Start, path: &Path) -> Recorder {
+        println!("Recording movie to {}", path.display());
+        Recorder {
+            movie: Movie {
+                emulator_version: EMULATOR_VERSION.to_string(),
+                rom_checksum: gameboy.mmu.rom_checksum(),
+                start: start,
+                cheats: gameboy
+                    .mmu
+                    .cheats
+                    .list()
+                    .iter()
+                    .filter(|x| x.enabled)
+                    .map(|x| x.text.clone())
+                    .collect(),
+                state: gameboy.save_state(),
+                inputs: Vec::new(),
+                hashes: Vec::new(),
+            },
+            path: path.to_path_buf(),
+            start_frame: gameboy.frames(),
+        }
+    }
+
+    // Call as each frame begins, with the buttons held for that frame,
+    // before they're given to the machine.
+    pub fn frame(&mut self, gameboy: &Gameboy, buttons: u8) {
+        let frame = (gameboy.frames() - self.start_frame) as u32;
+        if frame % HASH_INTERVAL == 0 {
+            self.movie.hashes.push((frame, state_hash(gameboy)));
+        }
+        self.movie.inputs.push(buttons);
+    }
+
+    pub fn finish(self) -> Result<PathBuf, StateError> {
+        self.movie.save(&self.path)?;
+        Ok(self.path)
+    }
+}
+
+
+pub struct Player {
+    movie: Movie,
+    start_frame: u64,
+    desynced: Option<u32>,
+    // The machine's own cheats, put back when the movie finishes
+    own_cheats: Cheats,
+}
+
+impl Player {
+    // Put the machine into the movie's starting state, with only the
+    // movie's cheats in use.
+    pub fn start(gameboy: &mut Gameboy, movie: Movie) -> Result<Player, StateError> {
+        if movie.rom_checksum != gameboy.mmu.rom_checksum() {
+            return Err(StateError::RomMismatch {
+                expected: gameboy.mmu.rom_checksum(),
+                found: movie.rom_checksum,
+            });
+        }
+        if movie.emulator_version != EMULATOR_VERSION {
+            println!(
+                "Movie was recorded with version {} (this is {}); it may desync.",
+                movie.emulator_version,
+                EMULATOR_VERSION
+            );
+        }
+        let mut cheats = Cheats::new();
+        for code in movie.cheats.iter() {
+            if cheats.add(code, "").is_none() {
+                return Err(StateError::Invalid("movie cheat code"));
+            }
+        }
+        gameboy.load_state(&movie.state)?;
+        println!("Playing movie ({} frames)", movie.len());
+        Ok(Player {
+            start_frame: gameboy.frames(),
+            movie: movie,
+            desynced: None,
+            own_cheats: mem::replace(&mut gameboy.mmu.cheats, cheats),
+        })
+    }
+
+    // Call as each frame begins. Returns the buttons to hold for the
+    // frame, or None once the movie has ended.
+    pub fn frame(&mut self, gameboy: &Gameboy) -> Option<u8> {
+        let frame = (gameboy.frames() - self.start_frame) as u32;
+        if self.desynced.is_none() {
+            let expected = self.movie.hashes.iter().find(|x| x.0 == frame);
+            if let Some(&(_, hash)) = expected {
+                if hash != state_hash(gameboy) {
+                    println!("Movie desynced at frame {}", frame);
+                    self.desynced = Some(frame);
+                }
+            }
+        }
+        self.movie.inputs.get(frame as usize).cloned()
+    }
+
+    // First frame whose state didn't match the recording.
+    pub fn desynced(&self) -> Option<u32> {
+        self.desynced
+    }
+}
+
+
+pub enum Session {
+    Recording(Recorder),
+    Playing(Player),
+}
+
+impl Session {
+    // Call as each frame begins, with the buttons the user is holding.
+    // Returns false once a movie being played has run out of input.
+    pub fn frame(&mut self, gameboy: &mut Gameboy, held: u8) -> bool {
+        match *self {
+            Session::Recording(ref mut recorder) => {
+                // Hash before the new buttons go in, as playback does
+                recorder.frame(gameboy, held);
+                gameboy.set_buttons(held);
+                true
+            }
+            Session::Playing(ref mut player) => {
+                match player.frame(gameboy) {
+                    Some(buttons) => {
+                        gameboy.set_buttons(buttons);
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+
+    // Playing a movie puts the machine's own cheats back.
+    pub fn finish(self, gameboy: &mut Gameboy) {
+        match self {
+            Session::Recording(recorder) => {
+                match recorder.finish() {
+                    Ok(path) => println!("Saved movie to {}", path.display()),
+                    Err(err) => println!("Could not save movie: {}", err),
+                }
+            }
+            Session::Playing(player) => {
+                match player.desynced() {
+                    Some(frame) => println!("Movie ended; first desync at frame {}", frame),
+                    None => println!("Movie ended in sync"),
+                }
+                gameboy.mmu.cheats = player.own_cheats;
+            }
+        }
+    }
+}
+
+
+#[test]
+fn test_record_and_play() {
+    use std::env;
+    use gameboy::joypad::Button;
+    use gameboy::model::Model;
+    use gameboy::rom::Rom;
+
+    // Select the direction keys, then keep adding P1 into B forever.
+    let mut data = vec![0; 0x8000];
+    let program = [0x3E, 0x20, 0xE0, 0x00, 0xF0, 0x00, 0x80, 0x47, 0x18, 0xFA];
+    data[0x100..0x100 + program.len()].copy_from_slice(&program);
+    let new_gameboy = || {
+        let rom = Rom::from_bytes(data.clone(), "movie").unwrap();
+        let mut gameboy = Gameboy::new(rom, Model::Dmg);
+        gameboy.skip_boot_rom();
+        gameboy
+    };
+
+    // The input changes on the frames that get hashed.
+    let mut gameboy = new_gameboy();
+    let path = env::temp_dir().join("bitromney_test.gmv");
+    let mut session = Session::Recording(Recorder::start(&gameboy, Start::PowerOn, &path));
+    for frame in 0..130 {
+        let held = if frame < 60 { Button::Right.mask() } else { Button::Down.mask() };
+        assert!(session.frame(&mut gameboy, held));
+        gameboy.run_frame();
+    }
+    let movie = match session {
+        Session::Recording(recorder) => recorder.movie,
+        Session::Playing(_) => unreachable!(),
+    };
+    assert_eq!(vec![0, 60, 120], movie.hashes.iter().map(|x| x.0).collect::<Vec<_>>());
+
+    let mut gameboy = new_gameboy();
+    let mut session = Session::Playing(Player::start(&mut gameboy, movie).unwrap());
+    while session.frame(&mut gameboy, 0) {
+        gameboy.run_frame();
+    }
+    match session {
+        Session::Playing(player) => assert_eq!(None, player.desynced()),
+        Session::Recording(_) => unreachable!(),
+    }
+}
+
+#[test]
+fn test_movie_cheats() {
+    use std::env;
+    use std::fs;
+    use gameboy::model::Model;
+    use gameboy::rom::Rom;
+
+    let new_gameboy = || {
+        let rom = Rom::from_bytes(vec![0; 0x8000], "movie").unwrap();
+        let mut gameboy = Gameboy::new(rom, Model::Dmg);
+        gameboy.skip_boot_rom();
+        gameboy
+    };
+
+    let mut gameboy = new_gameboy();
+    gameboy.mmu.cheats.add("010700C0", "").unwrap();
+    gameboy.mmu.cheats.add("010801C0", "").unwrap();
+    gameboy.mmu.cheats.set_enabled(1, false);
+    let path = env::temp_dir().join("bitromney_test_cheats.gmv");
+    let mut session = Session::Recording(Recorder::start(&gameboy, Start::PowerOn, &path));
+    for _ in 0..70 {
+        session.frame(&mut gameboy, 0);
+        gameboy.run_frame();
+    }
+    session.finish(&mut gameboy);
+    let movie = Movie::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(vec!["010700C0".to_string()], movie.cheats);
+
+    // Playback swaps the machine's own cheats out for the movie's.
+    let mut gameboy = new_gameboy();
+    gameboy.mmu.cheats.add("010902C0", "").unwrap();
+    let mut session = Session::Playing(Player::start(&mut gameboy, movie).unwrap());
+    assert_eq!("010700C0", gameboy.mmu.cheats.list()[0].text);
+    while session.frame(&mut gameboy, 0) {
+        gameboy.run_frame();
+    }
+    assert_eq!(0x07, gameboy.mmu.read(0xC000));
+    match session {
+        Session::Playing(ref player) => assert_eq!(None, player.desynced()),
+        Session::Recording(_) => unreachable!(),
+    }
+    session.finish(&mut gameboy);
+    assert_eq!("010902C0", gameboy.mmu.cheats.list()[0].text);
+}
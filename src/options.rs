@@ -0,0 +1,461 @@
+use std::path::PathBuf;
+
+use clap::{App, Arg};
+use log::LogLevelFilter;
+
+use fail;
+use gameboy::model::Model;
+use graphics::ColorScheme;
+use graphics::display::Scaling;
+use printer::Format;
+
+
+// Everything else that can be plugged into the link port.
+const LINK_OPTIONS: [&'static str; 4] =
+    ["link-listen", "link-connect", "link-local", "serial-stdout"];
+
+
+pub enum BootRom {
+    Builtin,
+    File(PathBuf),
+    Skip,
+}
+
+pub struct Options {
+    pub rom_path: PathBuf,
+    pub boot_rom: BootRom,
+    pub scale: u32,
+    pub scaling: Scaling,
+    pub color_scheme: ColorScheme,
+    // None picks the model from the cartridge header.
+    pub model: Option<Model>,
+    pub log_file: PathBuf,
+    pub log_level: LogLevelFilter,
+    pub debug: bool,
+    pub headless: bool,
+    pub frame_limit: Option<u64>,
+    pub save_dir: PathBuf,
+    pub record_movie: Option<PathBuf>,
+    pub play_movie: Option<PathBuf>,
+    pub script: Option<PathBuf>,
+    pub audio: bool,
+    pub audio_rate: u32,
+    pub audio_sync: bool,
+    pub volume: u32,
+    pub scope: bool,
+    pub record_audio: Option<PathBuf>,
+    pub audio_channels: bool,
+    pub record_vgm: Option<PathBuf>,
+    pub serial_stdout: bool,
+    pub link_listen: Option<String>,
+    pub link_connect: Option<String>,
+    pub link_local: Option<PathBuf>,
+    pub adapter_local: Vec<PathBuf>,
+    pub adapter_listen: Option<String>,
+    pub adapter_remotes: usize,
+    pub printer: Option<PathBuf>,
+    pub print_format: Format,
+    pub track: Option<usize>,
+    pub golden: Option<PathBuf>,
+    pub bless: bool,
+    pub test: bool,
+    pub junit: Option<PathBuf>,
+    pub test_timeout: u64,
+}
+
+impl Options {
+    pub fn from_args() -> Options {
+        let matches = App::new("gameroy")
+            .version(env!("CARGO_PKG_VERSION"))
+            .about("A Gameboy emulator and debugger")
+            .arg(
+                Arg::with_name("ROM")
+                    .help("Path to the cartridge ROM or a .gbs music file, or a directory of ROMs \
+                           with --test")
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("boot-rom")
+                    .long("boot-rom")
+                    .value_name("FILE")
+                    .help("Boot ROM to run instead of the built-in one, a CGB one for CGB games")
+                    .conflicts_with("skip-boot"),
+            )
+            .arg(Arg::with_name("skip-boot").long("skip-boot").help(
+                "Start directly at the cartridge entry point",
+            ))
+            .arg(
+                Arg::with_name("scale")
+                    .short("s")
+                    .long("scale")
+                    .value_name("N")
+                    .default_value("5")
+                    .help("Window scale factor"),
+            )
+            .arg(
+                Arg::with_name("scaling")
+                    .long("scaling")
+                    .value_name("MODE")
+                    .default_value("integer")
+                    .possible_values(&["integer", "aspect"])
+                    .help("How the screen is fitted to a resized window"),
+            )
+            .arg(
+                Arg::with_name("palette")
+                    .short("p")
+                    .long("palette")
+                    .value_name("NAME")
+                    .default_value("green")
+                    .possible_values(&["green", "gray", "pocket"])
+                    .help("Colours used for the four shades"),
+            )
+            .arg(
+                Arg::with_name("model")
+                    .short("m")
+                    .long("model")
+                    .value_name("MODEL")
+                    .default_value("auto")
+                    .possible_values(&["auto", "dmg", "mgb", "sgb", "cgb", "agb"])
+                    .help("Hardware to emulate; auto picks CGB or SGB by what the game supports"),
+            )
+            .arg(
+                Arg::with_name("log-file")
+                    .long("log-file")
+                    .value_name("FILE")
+                    .default_value("log/output.log")
+                    .help("File the instruction log is written to"),
+            )
+            .arg(
+                Arg::with_name("log-level")
+                    .long("log-level")
+                    .value_name("LEVEL")
+                    .default_value("info")
+                    .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                    .help("Most verbose level written to the log"),
+            )
+            .arg(
+                Arg::with_name("run")
+                    .short("r")
+                    .long("run")
+                    .help("Start running immediately instead of at the debugger prompt"),
+            )
+            .arg(
+                Arg::with_name("headless")
+                    .long("headless")
+                    .help("Run without a window or debugger"),
+            )
+            .arg(
+                Arg::with_name("frames")
+                    .short("f")
+                    .long("frames")
+                    .value_name("N")
+                    .help("Exit after emulating N frames"),
+            )
+            .arg(
+                Arg::with_name("save-dir")
+                    .long("save-dir")
+                    .value_name("DIR")
+                    .help("Directory for save files (defaults to the ROM's directory)"),
+            )
+            .arg(
+                Arg::with_name("record")
+                    .long("record")
+                    .value_name("FILE")
+                    .help("Record joypad input from power-on to a movie file")
+                    .conflicts_with("play"),
+            )
+            .arg(
+                Arg::with_name("play")
+                    .long("play")
+                    .value_name("FILE")
+                    .help("Play back a recorded movie file"),
+            )
+            .arg(
+                Arg::with_name("script")
+                    .long("script")
+                    .value_name("FILE")
+                    .help("Drive the joypad from an input script (headless only)")
+                    .requires("headless")
+                    .conflicts_with("play"),
+            )
+            .arg(
+                Arg::with_name("no-audio")
+                    .long("no-audio")
+                    .help("Don't play sound"),
+            )
+            .arg(
+                Arg::with_name("audio-rate")
+                    .long("audio-rate")
+                    .value_name("HZ")
+                    .possible_values(&["44100", "48000"])
+                    .default_value("48000")
+                    .help("Sample rate for sound output"),
+            )
+            .arg(
+                Arg::with_name("audio-sync")
+                    .long("audio-sync")
+                    .help("Pace emulation by the sound buffer instead of the clock"),
+            )
+            .arg(
+                Arg::with_name("volume")
+                    .long("volume")
+                    .value_name("PERCENT")
+                    .default_value("100")
+                    .help("Sound volume from 0 to 100"),
+            )
+            .arg(
+                Arg::with_name("scope")
+                    .long("scope")
+                    .help("Open a window showing each sound channel's waveform"),
+            )
+            .arg(
+                Arg::with_name("record-audio")
+                    .long("record-audio")
+                    .value_name("FILE")
+                    .help("Record sound to a 16-bit WAV file"),
+            )
+            .arg(
+                Arg::with_name("audio-channels")
+                    .long("audio-channels")
+                    .help("Also record each sound channel to FILE.ch1.wav to FILE.ch4.wav")
+                    .requires("record-audio"),
+            )
+            .arg(
+                Arg::with_name("record-vgm")
+                    .long("record-vgm")
+                    .value_name("FILE")
+                    .help("Log writes to the sound registers to a VGM file"),
+            )
+            .arg(
+                Arg::with_name("serial-stdout")
+                    .long("serial-stdout")
+                    .help("Print bytes sent over the link cable to stdout"),
+            )
+            .arg(
+                Arg::with_name("link-listen")
+                    .long("link-listen")
+                    .value_name("ADDRESS")
+                    .conflicts_with_all(&["link-connect", "serial-stdout"])
+                    .help("Wait for a link cable connection on HOST:PORT or unix:PATH"),
+            )
+            .arg(
+                Arg::with_name("link-connect")
+                    .long("link-connect")
+                    .value_name("ADDRESS")
+                    .conflicts_with("serial-stdout")
+                    .help("Connect a link cable to an instance started with --link-listen"),
+            )
+            .arg(
+                Arg::with_name("link-local")
+                    .long("link-local")
+                    .value_name("ROM")
+                    .conflicts_with_all(&["link-listen", "link-connect", "serial-stdout"])
+                    .requires("headless")
+                    .help("Link a second machine running ROM in the same process"),
+            )
+            .arg(
+                Arg::with_name("adapter-local")
+                    .long("adapter-local")
+                    .value_name("ROM")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .conflicts_with_all(&LINK_OPTIONS)
+                    .conflicts_with("printer")
+                    .requires("headless")
+                    .help("Plug a machine running ROM in the same process into a Four Player \
+                           Adapter; may be given up to three times"),
+            )
+            .arg(
+                Arg::with_name("adapter-listen")
+                    .long("adapter-listen")
+                    .value_name("ADDRESS")
+                    .conflicts_with_all(&LINK_OPTIONS)
+                    .conflicts_with("printer")
+                    .help("Plug players connecting with --link-connect into a Four Player Adapter"),
+            )
+            .arg(
+                Arg::with_name("adapter-remotes")
+                    .long("adapter-remotes")
+                    .value_name("N")
+                    .default_value("1")
+                    .help("Number of players to wait for with --adapter-listen"),
+            )
+            .arg(
+                Arg::with_name("printer")
+                    .long("printer")
+                    .value_name("DIR")
+                    .conflicts_with_all(&LINK_OPTIONS)
+                    .help("Attach a Game Boy Printer that saves each page to DIR"),
+            )
+            .arg(
+                Arg::with_name("print-format")
+                    .long("print-format")
+                    .value_name("FORMAT")
+                    .possible_values(&["png", "pgm"])
+                    .default_value("png")
+                    .help("Image format for printed pages"),
+            )
+            .arg(
+                Arg::with_name("track")
+                    .long("track")
+                    .value_name("N")
+                    .help("Track to start a .gbs file on, counting from 1"),
+            )
+            .arg(
+                Arg::with_name("golden")
+                    .long("golden")
+                    .value_name("PNG")
+                    .help("Compare the final screen of a headless run with a reference image")
+                    .requires_all(&["headless", "frames"]),
+            )
+            .arg(
+                Arg::with_name("bless")
+                    .long("bless")
+                    .help("Overwrite the --golden reference with the final screen")
+                    .requires("golden"),
+            )
+            .arg(
+                Arg::with_name("test")
+                    .long("test")
+                    .help("Run Blargg or Mooneye test ROMs headlessly and report the results"),
+            )
+            .arg(
+                Arg::with_name("junit")
+                    .long("junit")
+                    .value_name("FILE")
+                    .help("Write test results as JUnit XML")
+                    .requires("test"),
+            )
+            .arg(
+                Arg::with_name("timeout")
+                    .long("timeout")
+                    .value_name("SECS")
+                    .default_value("30")
+                    .help("Emulated seconds each test ROM may run before failing"),
+            )
+            .get_matches();
+
+        let rom_path = PathBuf::from(matches.value_of("ROM").unwrap());
+
+        let boot_rom = if matches.is_present("skip-boot") {
+            BootRom::Skip
+        } else {
+            match matches.value_of("boot-rom") {
+                Some(path) => BootRom::File(PathBuf::from(path)),
+                None => BootRom::Builtin,
+            }
+        };
+
+        let scale = match matches.value_of("scale").unwrap().parse::<u32>() {
+            Ok(val) if val > 0 => val,
+            _ => fail("--scale must be a positive integer."),
+        };
+
+        let frame_limit = match matches.value_of("frames") {
+            Some(val) => {
+                match val.parse::<u64>() {
+                    Ok(val) if val > 0 => Some(val),
+                    _ => fail("--frames must be a positive integer."),
+                }
+            }
+            None => None,
+        };
+
+        let volume = match matches.value_of("volume").unwrap().parse::<u32>() {
+            Ok(val) if val <= 100 => val,
+            _ => fail("--volume must be a percentage from 0 to 100."),
+        };
+
+        let track = match matches.value_of("track").map(|val| val.parse::<usize>()) {
+            Some(Ok(val)) if val > 0 => Some(val),
+            Some(_) => fail("--track must be a positive integer."),
+            None => None,
+        };
+
+        let test_timeout = match matches.value_of("timeout").unwrap().parse::<u64>() {
+            Ok(val) if val > 0 => val,
+            _ => fail("--timeout must be a positive integer."),
+        };
+
+        let adapter_local: Vec<PathBuf> = match matches.values_of("adapter-local") {
+            Some(values) => values.map(PathBuf::from).collect(),
+            None => Vec::new(),
+        };
+        let adapter_remotes = match matches.value_of("adapter-remotes").unwrap().parse::<usize>() {
+            Ok(val) if val > 0 => val,
+            _ => fail("--adapter-remotes must be a positive integer."),
+        };
+        let remotes = if matches.is_present("adapter-listen") { adapter_remotes } else { 0 };
+        if adapter_local.len() + remotes > 3 {
+            fail("The Four Player Adapter only has room for three more players.");
+        }
+
+        let save_dir = match matches.value_of("save-dir") {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                match rom_path.parent() {
+                    Some(dir) => dir.to_path_buf(),
+                    None => PathBuf::from("."),
+                }
+            }
+        };
+
+        Options {
+            boot_rom: boot_rom,
+            scale: scale,
+            scaling: Scaling::from_str(matches.value_of("scaling").unwrap()).unwrap(),
+            color_scheme: ColorScheme::from_str(matches.value_of("palette").unwrap()).unwrap(),
+            model: Model::from_str(matches.value_of("model").unwrap()),
+            log_file: PathBuf::from(matches.value_of("log-file").unwrap()),
+            log_level: matches.value_of("log-level").unwrap().parse().unwrap(),
+            debug: !matches.is_present("run"),
+            headless: matches.is_present("headless"),
+            frame_limit: frame_limit,
+            save_dir: save_dir,
+            record_movie: matches.value_of("record").map(PathBuf::from),
+            play_movie: matches.value_of("play").map(PathBuf::from),
+            script: matches.value_of("script").map(PathBuf::from),
+            audio: !matches.is_present("no-audio"),
+            audio_rate: matches.value_of("audio-rate").unwrap().parse().unwrap(),
+            audio_sync: matches.is_present("audio-sync"),
+            volume: volume,
+            scope: matches.is_present("scope"),
+            record_audio: matches.value_of("record-audio").map(PathBuf::from),
+            audio_channels: matches.is_present("audio-channels"),
+            record_vgm: matches.value_of("record-vgm").map(PathBuf::from),
+            serial_stdout: matches.is_present("serial-stdout"),
+            link_listen: matches.value_of("link-listen").map(String::from),
+            link_connect: matches.value_of("link-connect").map(String::from),
+            link_local: matches.value_of("link-local").map(PathBuf::from),
+            adapter_local: adapter_local,
+            adapter_listen: matches.value_of("adapter-listen").map(String::from),
+            adapter_remotes: adapter_remotes,
+            printer: matches.value_of("printer").map(PathBuf::from),
+            print_format: Format::from_str(matches.value_of("print-format").unwrap()).unwrap(),
+            track: track,
+            golden: matches.value_of("golden").map(PathBuf::from),
+            bless: matches.is_present("bless"),
+            test: matches.is_present("test"),
+            junit: matches.value_of("junit").map(PathBuf::from),
+            test_timeout: test_timeout,
+            rom_path: rom_path,
+        }
+    }
+
+    pub fn uses_adapter(&self) -> bool {
+        !self.adapter_local.is_empty() || self.adapter_listen.is_some()
+    }
+
+    pub fn is_gbs(&self) -> bool {
+        match self.rom_path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => ext.eq_ignore_ascii_case("gbs"),
+            None => false,
+        }
+    }
+
+    // Where battery-backed cartridge RAM is persisted between runs.
+    pub fn battery_path(&self) -> PathBuf {
+        let stem = self.rom_path.file_stem().unwrap_or_default();
+        self.save_dir.join(format!("{}.sav", stem.to_string_lossy()))
+    }
+}
@@ -0,0 +1,346 @@
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+
+use gameboy::CLOCK_SPEED;
+use gameboy::serial::Peer;
+use graphics::image::{save_pgm, save_png};
+
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+// Replied in place of the status byte's predecessor to say a printer
+// is attached.
+const DEVICE_ID: u8 = 0x81;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const BREAK: u8 = 0x08;
+const STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM: u8 = 0x01;
+const STATUS_BUSY: u8 = 0x02;
+const STATUS_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = WIDTH / 8;
+// The printer holds 8KB of tiles, nine 160x16 bands.
+const BUFFER_SIZE: usize = 9 * 2 * TILES_PER_ROW * 16;
+// Margins count line feeds, drawn here as a tile row each.
+const MARGIN_LINES: usize = 8;
+// Printing a 16 pixel band keeps the printer busy this long.
+const BAND_CYCLES: u32 = CLOCK_SPEED / 8;
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Png,
+    Pgm,
+}
+
+impl Format {
+    pub fn from_str(name: &str) -> Option<Format> {
+        match name {
+            "png" => Some(Format::Png),
+            "pgm" => Some(Format::Pgm),
+            _ => None,
+        }
+    }
+    fn extension(&self) -> &'static str {
+        match *self {
+            Format::Png => "png",
+            Format::Pgm => "pgm",
+        }
+    }
+}
+
+// Where the printer is in receiving a packet: two magic bytes, a
+// command, compression flag and length, the data, a checksum, then two
+// bytes clocked out to read back the device ID and status.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Magic,
+    Header,
+    Data,
+    Checksum,
+    DeviceId,
+    Status,
+}
+
+// A Game Boy Printer on the end of the link cable. Each page of paper
+// is saved as an image once the printer feeds past it, or when the
+// printer is unplugged.
+pub struct Printer {
+    directory: PathBuf,
+    format: Format,
+    pages: usize,
+
+    stage: Stage,
+    packet: Vec<u8>,
+    status: u8,
+    busy_cycles: u32,
+
+    // Tile data waiting to be printed
+    buffer: Vec<u8>,
+    // Shades of the page printed so far
+    page: Vec<u8>,
+}
+
+impl Printer {
+    pub fn new(directory: PathBuf, format: Format) -> Printer {
+        Printer {
+            directory: directory,
+            format: format,
+            pages: 0,
+            stage: Stage::Magic,
+            packet: Vec::new(),
+            status: 0,
+            busy_cycles: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+        }
+    }
+    // Take a byte of a packet, returning the reply clocked back.
+    fn receive(&mut self, byte: u8) -> u8 {
+        match self.stage {
+            Stage::Magic => {
+                self.packet.push(byte);
+                if self.packet[..] == MAGIC[..self.packet.len()] {
+                    if self.packet.len() == MAGIC.len() {
+                        self.packet.clear();
+                        self.stage = Stage::Header;
+                    }
+                } else {
+                    self.packet.clear();
+                }
+            }
+            Stage::Header => {
+                self.packet.push(byte);
+                if self.packet.len() == 4 {
+                    self.stage = match self.data_length() {
+                        0 => Stage::Checksum,
+                        _ => Stage::Data,
+                    };
+                }
+            }
+            Stage::Data => {
+                self.packet.push(byte);
+                if self.packet.len() == 4 + self.data_length() {
+                    self.stage = Stage::Checksum;
+                }
+            }
+            Stage::Checksum => {
+                self.packet.push(byte);
+                if self.packet.len() == 4 + self.data_length() + 2 {
+                    self.process_packet();
+                    self.stage = Stage::DeviceId;
+                }
+            }
+            Stage::DeviceId => {
+                self.stage = Stage::Status;
+                return DEVICE_ID;
+            }
+            Stage::Status => {
+                self.stage = Stage::Magic;
+                self.packet.clear();
+                return self.status;
+            }
+        }
+        0x00
+    }
+    fn data_length(&self) -> usize {
+        self.packet[2] as usize | (self.packet[3] as usize) << 8
+    }
+    fn process_packet(&mut self) {
+        let length = self.data_length();
+        let (body, checksum) = self.packet.split_at(4 + length);
+        let expected = checksum[0] as u16 | (checksum[1] as u16) << 8;
+        let sum = body.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        if sum != expected {
+            self.status |= STATUS_CHECKSUM;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM;
+
+        let (command, compressed) = (body[0], body[1] & 1 != 0);
+        let data = body[4..].to_vec();
+        match command {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            DATA if length > 0 => {
+                let data = if compressed { decompress(&data) } else { data };
+                let room = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend(data.iter().take(room));
+                self.status |= STATUS_UNPROCESSED;
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_FULL;
+                }
+            }
+            DATA => self.status |= STATUS_FULL,
+            PRINT if data.len() >= 4 => {
+                let buffer = mem::replace(&mut self.buffer, Vec::new());
+                self.print(&buffer, data[0], data[1], data[2]);
+            }
+            BREAK => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_cycles = 0;
+            }
+            // A status request only wants the reply.
+            STATUS => {}
+            _ => {}
+        }
+    }
+    // Print the tiles with the margins (upper nibble before, lower after)
+    // and palette given. Zero sheets just feeds the paper.
+    fn print(&mut self, tiles: &[u8], sheets: u8, margins: u8, palette: u8) {
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        self.feed((margins >> 4) as usize * MARGIN_LINES);
+        if sheets > 0 {
+            let rows = tiles.len() / (TILES_PER_ROW * 16);
+            for y in 0..rows * 8 {
+                for x in 0..WIDTH {
+                    let tile = (y / 8) * TILES_PER_ROW + x / 8;
+                    let offset = tile * 16 + (y % 8) * 2;
+                    let bit = 7 - x % 8;
+                    let color = (tiles[offset] >> bit) & 1 | ((tiles[offset + 1] >> bit) & 1) << 1;
+                    let shade = (palette >> (color * 2)) & 0x03;
+                    self.page.push(SHADES[shade as usize]);
+                }
+            }
+            self.busy_cycles = (rows as u32 + 1) / 2 * BAND_CYCLES;
+        }
+        let after = (margins & 0x0F) as usize;
+        self.feed(after * MARGIN_LINES);
+        self.status &= !STATUS_UNPROCESSED & !STATUS_FULL;
+        if self.busy_cycles > 0 {
+            self.status |= STATUS_BUSY;
+        }
+        if after > 0 {
+            self.save_page();
+        }
+    }
+    fn feed(&mut self, lines: usize) {
+        let length = self.page.len() + lines * WIDTH;
+        self.page.resize(length, SHADES[0]);
+    }
+    fn save_page(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+        let page = mem::replace(&mut self.page, Vec::new());
+        self.pages += 1;
+        let name = format!("print_{:04}.{}", self.pages, self.format.extension());
+        let path = self.directory.join(name);
+        let height = (page.len() / WIDTH) as u32;
+        let result = fs::create_dir_all(&self.directory).and_then(|_| match self.format {
+            Format::Png => {
+                let rgba: Vec<u8> = page.iter().flat_map(|&v| vec![v, v, v, 0xFF]).collect();
+                save_png(&path, WIDTH as u32, height, &rgba)
+            }
+            Format::Pgm => save_pgm(&path, WIDTH as u32, height, &page),
+        });
+        match result {
+            Ok(()) => println!("Printed {}", path.display()),
+            Err(err) => eprintln!("Could not save print to {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl Peer for Printer {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.receive(byte)
+    }
+    // The printer never clocks a transfer itself; time only matters for
+    // how long it stays busy.
+    fn poll(&mut self, cycles: u32, _: Option<u8>) -> Option<u8> {
+        if self.busy_cycles > 0 {
+            self.busy_cycles = self.busy_cycles.saturating_sub(cycles);
+            if self.busy_cycles == 0 {
+                self.status &= !STATUS_BUSY;
+            }
+        }
+        None
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.save_page();
+    }
+}
+
+// Expand run-length encoded data. A control byte with the high bit set
+// repeats the next byte (control & 0x7F) + 2 times; otherwise the next
+// control + 1 bytes are copied as they are.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            if let Some(&byte) = data.get(i) {
+                output.extend(vec![byte; (control & 0x7F) as usize + 2]);
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    output
+}
+
+
+#[test]
+fn test_printer_packets() {
+    use std::env;
+
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let mut packet = vec![command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        packet.extend_from_slice(data);
+        let sum = packet.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        packet.extend_from_slice(&[sum as u8, (sum >> 8) as u8]);
+        for &byte in MAGIC.iter().chain(packet.iter()) {
+            assert_eq!(0x00, printer.transfer(byte));
+        }
+        (printer.transfer(0), printer.transfer(0))
+    }
+
+    let directory = env::temp_dir().join("bitromney_test_printer");
+    let mut printer = Printer::new(directory.clone(), Format::Pgm);
+    assert_eq!((DEVICE_ID, 0x00), send(&mut printer, INIT, false, &[]));
+    // A band of tiles whose rows are all color 1, then one all color 3,
+    // using both kinds of run.
+    let band: Vec<u8> = [0x01, 0xFF, 0x00].iter().cycle().take(3 * 320).cloned().collect();
+    assert_eq!(0x08, send(&mut printer, DATA, true, &band).1);
+    let band = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFA, 0xFF];
+    assert_eq!(0x08, send(&mut printer, DATA, true, &band).1);
+    assert_eq!(0x0C, send(&mut printer, DATA, false, &[]).1);
+
+    // A status request with a bad checksum
+    for &byte in &[0x88, 0x33, STATUS, 0, 0, 0, 0, 0, 0] {
+        printer.transfer(byte);
+    }
+    assert_eq!(0x0D, printer.transfer(0));
+
+    assert_eq!((DEVICE_ID, STATUS_BUSY), send(&mut printer, PRINT, false, &[1, 0x01, 0xE4, 0x40]));
+    assert!(printer.page.is_empty());
+    printer.poll(2 * BAND_CYCLES, None);
+    assert_eq!(0x00, send(&mut printer, STATUS, false, &[]).1);
+
+    let page = fs::read(directory.join("print_0001.pgm")).unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+    let header = format!("P5\n160 {}\n255\n", 32 + MARGIN_LINES);
+    assert_eq!(header.as_bytes(), &page[..header.len()]);
+    let pixels = &page[header.len()..];
+    assert_eq!(0xAA, pixels[0]);
+    assert_eq!(0x00, pixels[160 * 16]);
+    assert_eq!(0xFF, pixels[160 * 32]);
+}
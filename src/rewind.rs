@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+
+use gameboy::Gameboy;
+
+
+// A bounded history of machine snapshots. The newest snapshot is kept
+// whole; each older one is stored as the XOR against its successor,
+// run-length encoded, so mostly-unchanged memory costs next to nothing.
+// The buttons held on each frame are kept alongside, so going back can
+// replay the same input.
+pub struct Rewind {
+    interval: u64,
+    capacity: usize,
+    newest: Option<(u64, Vec<u8>)>,
+    // Oldest first; each entry turns the snapshot after it into itself.
+    older: VecDeque<(u64, Vec<u8>)>,
+    // Frame number and the buttons held while it ran, oldest first
+    inputs: VecDeque<(u64, u8)>,
+}
+
+impl Rewind {
+    // Snapshot every `interval` frames, keeping up to `capacity` of them.
+    pub fn new(interval: u64, capacity: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            newest: None,
+            older: VecDeque::new(),
+            inputs: VecDeque::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.older.clear();
+        self.inputs.clear();
+    }
+
+    // Call once per emulated frame.
+    pub fn record(&mut self, gameboy: &Gameboy) {
+        let frame = gameboy.frames();
+        if frame > 0 {
+            self.inputs.push_back((frame - 1, gameboy.buttons()));
+        }
+        if frame % self.interval != 0 {
+            return;
+        }
+        let state = gameboy.save_state();
+        if let Some((prev_frame, prev)) = self.newest.take() {
+            if prev.len() == state.len() && prev_frame < frame {
+                self.older.push_back((prev_frame, compress(&xor(&prev, &state))));
+            } else {
+                self.older.clear();
+            }
+        }
+        self.newest = Some((frame, state));
+        while self.older.len() + 1 > self.capacity {
+            self.older.pop_front();
+        }
+        let oldest = self.older.front().map_or(frame, |x| x.0);
+        while self.inputs.front().map_or(false, |x| x.0 < oldest) {
+            self.inputs.pop_front();
+        }
+    }
+
+    // Move the machine back one frame, re-simulating forward from the
+    // nearest snapshot before it. Returns false once the history runs
+    // out.
+    pub fn step_back(&mut self, gameboy: &mut Gameboy) -> bool {
+        let target = match gameboy.frames().checked_sub(1) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        loop {
+            match self.newest {
+                Some((frame, _)) if frame <= target => break,
+                Some(_) => {
+                    if !self.pop() {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+        // The sound log only wants where rewinding ends up
+        gameboy.mmu.apu.pause_log(true);
+        let on_refresh = gameboy.mmu.ppu.take_on_refresh();
+        if let Some((_, ref state)) = self.newest {
+            gameboy.load_state(state).unwrap();
+        }
+        while gameboy.frames() < target {
+            let frame = gameboy.frames();
+            if let Some(&(_, buttons)) = self.inputs.iter().find(|x| x.0 == frame) {
+                gameboy.set_buttons(buttons);
+            }
+            gameboy.run_frame();
+        }
+        // Frames from here on are recorded again as they're played
+        while self.inputs.back().map_or(false, |x| x.0 >= target) {
+            self.inputs.pop_back();
+        }
+        gameboy.mmu.apu.pause_log(false);
+        if let Some(callback) = on_refresh {
+            gameboy.mmu.ppu.set_on_refresh(callback);
+        }
+        gameboy.mmu.ppu.refresh();
+        true
+    }
+
+    // Replace the newest snapshot with the one before it.
+    fn pop(&mut self) -> bool {
+        match (self.older.pop_back(), self.newest.take()) {
+            (Some((frame, delta)), Some((_, state))) => {
+                self.newest = Some((frame, xor(&state, &decompress(&delta))));
+                true
+            }
+            (_, newest) => {
+                self.newest = newest;
+                false
+            }
+        }
+    }
+}
+
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+// Encode as alternating runs: a count of zero bytes, then a count of
+// literal bytes followed by the bytes themselves. Counts are LEB128.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..].iter().take_while(|x| **x == 0).count();
+        i += zeros;
+        let literals = data[i..].iter().take_while(|x| **x != 0).count();
+        write_count(&mut out, zeros);
+        write_count(&mut out, literals);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = read_count(data, &mut i);
+        let literals = read_count(data, &mut i);
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out
+}
+
+fn write_count(out: &mut Vec<u8>, mut count: usize) {
+    loop {
+        let byte = (count & 0x7F) as u8;
+        count >>= 7;
+        if count == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_count(data: &[u8], pos: &mut usize) -> usize {
+    let mut count = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        count |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return count;
+        }
+        shift += 7;
+    }
+}
+
+
+#[test]
+fn test_compress_round_trip() {
+    let mut data = vec![0; 1000];
+    data[3] = 7;
+    data[4] = 9;
+    data[500] = 1;
+    data[999] = 0xFF;
+    let compressed = compress(&data);
+    assert!(compressed.len() < 20);
+    assert_eq!(data, decompress(&compressed));
+    assert_eq!(Vec::<u8>::new(), decompress(&compress(&[])));
+}
+
+#[test]
+fn test_step_back_replays_input() {
+    use gameboy::model::Model;
+    use gameboy::rom::Rom;
+    use gameboy::joypad::Button;
+
+    // Select the direction keys, then keep adding P1 into B forever.
+    let mut data = vec![0; 0x8000];
+    let program = [0x3E, 0x20, 0xE0, 0x00, 0xF0, 0x00, 0x80, 0x47, 0x18, 0xFA];
+    data[0x100..0x100 + program.len()].copy_from_slice(&program);
+    let mut gameboy = Gameboy::new(Rom::from_bytes(data, "rewind").unwrap(), Model::Dmg);
+    gameboy.skip_boot_rom();
+
+    let mut rewind = Rewind::new(4, 8);
+    let mut states = vec![gameboy.save_state()];
+    for frame in 0..20 {
+        let buttons = match frame % 3 {
+            0 => Button::Right.mask(),
+            1 => Button::Down.mask() | Button::Left.mask(),
+            _ => 0,
+        };
+        gameboy.set_buttons(buttons);
+        gameboy.run_frame();
+        rewind.record(&gameboy);
+        states.push(gameboy.save_state());
+    }
+    for frame in (5..20).rev() {
+        assert!(rewind.step_back(&mut gameboy));
+        assert_eq!(frame, gameboy.frames());
+        assert!(states[frame as usize] == gameboy.save_state(), "frame {}", frame);
+    }
+}
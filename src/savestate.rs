@@ -0,0 +1,56 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use gameboy::{bess, state, Gameboy};
+use gameboy::state::StateError;
+
+
+pub const SLOT_COUNT: usize = 10;
+
+// Save state files for one ROM, either in numbered slots alongside the
+// battery save or at an arbitrary path.
+pub struct SaveSlots {
+    dir: PathBuf,
+    stem: String,
+}
+
+impl SaveSlots {
+    pub fn new(dir: &Path, rom_path: &Path) -> SaveSlots {
+        let stem = rom_path.file_stem().unwrap_or_default();
+        SaveSlots {
+            dir: dir.to_path_buf(),
+            stem: stem.to_string_lossy().into_owned(),
+        }
+    }
+    pub fn slot_path(&self, slot: usize) -> PathBuf {
+        self.dir.join(format!("{}.ss{}", self.stem, slot))
+    }
+    // A single digit names a slot, anything else is taken as a path.
+    pub fn resolve(&self, target: &str) -> PathBuf {
+        match target.parse::<usize>() {
+            Ok(slot) if slot < SLOT_COUNT => self.slot_path(slot),
+            _ => PathBuf::from(target),
+        }
+    }
+}
+
+// States are written in the native format with a BESS section
+// appended, so other emulators can load them too.
+pub fn save(gameboy: &Gameboy, path: &Path) -> Result<(), StateError> {
+    let data = bess::export(gameboy, gameboy.save_state());
+    let mut file = File::create(path)?;
+    file.write_all(&data)?;
+    Ok(())
+}
+
+// Load either a native state or one exported by another emulator.
+pub fn load(gameboy: &mut Gameboy, path: &Path) -> Result<(), StateError> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    if !data.starts_with(state::MAGIC) && bess::has_footer(&data) {
+        bess::import(gameboy, &data)
+    } else {
+        gameboy.load_state(&data)
+    }
+}
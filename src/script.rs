@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use gameboy::Gameboy;
+use gameboy::joypad::Button;
+use graphics::image::save_png;
+
+
+// One line of an input script. Buttons are bitmasks so several can be
+// given at once, e.g. `press A+B 2`.
+#[derive(Clone, Debug, PartialEq)]
+enum Action {
+    // Run frames with the held buttons unchanged
+    Wait(u32),
+    // Hold buttons for some frames, then let go of them
+    Press(u8, u32),
+    // Keep holding buttons until released, running some frames first
+    Hold(u8, u32),
+    Release(u8),
+    // Direct the actions after this at a joypad, counting from 0
+    Player(usize),
+    Screenshot(PathBuf),
+}
+
+// A plain-text list of joypad actions for driving a ROM without a
+// person at the keyboard, one per line:
+//
+//     # comments and blank lines are ignored
+//     wait 120
+//     press A 5
+//     hold RIGHT 30
+//     release RIGHT
+//     screenshot title.png
+//
+// `player 2` to `player 4` send the actions that follow to the SGB's
+// other joypads, and `player 1` goes back to the first.
+pub struct Script {
+    actions: Vec<(usize, Action)>,
+    next: usize,
+    frames_left: u32,
+    player: usize,
+    held: [u8; 4],
+    pressing: [u8; 4],
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Script, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Script::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Script, String> {
+        let mut actions = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let action = parse_line(line).map_err(|err| format!("line {}: {}", i + 1, err))?;
+            actions.push((i + 1, action));
+        }
+        Ok(Script {
+            actions: actions,
+            next: 0,
+            frames_left: 0,
+            player: 0,
+            held: [0; 4],
+            pressing: [0; 4],
+        })
+    }
+
+    // Call as each frame begins. Carries out actions up to the next one
+    // that takes time and returns the buttons the first joypad holds for
+    // this frame, or None once the script has finished.
+    pub fn frame(&mut self, gameboy: &Gameboy) -> Option<u8> {
+        while self.frames_left == 0 {
+            for (held, pressing) in self.held.iter_mut().zip(self.pressing.iter()) {
+                *held &= !pressing;
+            }
+            self.pressing = [0; 4];
+            let (line, action) = match self.actions.get(self.next) {
+                Some(entry) => entry.clone(),
+                None => return None,
+            };
+            self.next += 1;
+            match action {
+                Action::Wait(frames) => self.frames_left = frames,
+                Action::Press(buttons, frames) => {
+                    self.held[self.player] |= buttons;
+                    self.pressing[self.player] = buttons;
+                    self.frames_left = frames;
+                }
+                Action::Hold(buttons, frames) => {
+                    self.held[self.player] |= buttons;
+                    self.frames_left = frames;
+                }
+                Action::Release(buttons) => self.held[self.player] &= !buttons,
+                Action::Player(player) => self.player = player,
+                Action::Screenshot(path) => {
+                    let framebuffer = gameboy.mmu.ppu.framebuffer();
+                    let (width, height) = gameboy.mmu.ppu.screen_size();
+                    if let Err(err) = save_png(&path, width as u32, height as u32, framebuffer) {
+                        println!("line {}: could not save {}: {}", line, path.display(), err);
+                    }
+                }
+            }
+        }
+        self.frames_left -= 1;
+        Some(self.held[0])
+    }
+
+    // Buttons held on joypad `player` this frame.
+    pub fn player_buttons(&self, player: usize) -> u8 {
+        self.held[player]
+    }
+}
+
+fn parse_line(line: &str) -> Result<Action, String> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let arg = |idx: usize| parts.get(idx).cloned();
+    match parts[0] {
+        "wait" => Ok(Action::Wait(parse_frames(arg(1), None)?)),
+        "press" => Ok(Action::Press(parse_buttons(arg(1))?, parse_frames(arg(2), Some(1))?)),
+        "hold" => Ok(Action::Hold(parse_buttons(arg(1))?, parse_frames(arg(2), Some(0))?)),
+        "release" => {
+            match arg(1) {
+                Some("all") => Ok(Action::Release(0xFF)),
+                other => Ok(Action::Release(parse_buttons(other)?)),
+            }
+        }
+        "player" => {
+            match arg(1).and_then(|x| x.parse::<usize>().ok()) {
+                Some(player @ 1...4) => Ok(Action::Player(player - 1)),
+                _ => Err("player needs a joypad number from 1 to 4".to_string()),
+            }
+        }
+        "screenshot" => {
+            match arg(1) {
+                Some(path) => Ok(Action::Screenshot(PathBuf::from(path))),
+                None => Err("screenshot needs a file name".to_string()),
+            }
+        }
+        other => Err(format!("unknown command '{}'", other)),
+    }
+}
+
+fn parse_buttons(arg: Option<&str>) -> Result<u8, String> {
+    let arg = arg.ok_or("missing button name")?;
+    let mut mask = 0;
+    for name in arg.split('+') {
+        match Button::from_str(name) {
+            Some(button) => mask |= button.mask(),
+            None => return Err(format!("unknown button '{}'", name)),
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_frames(arg: Option<&str>, default: Option<u32>) -> Result<u32, String> {
+    match (arg, default) {
+        (Some(val), _) => val.parse().map_err(|_| format!("'{}' is not a frame count", val)),
+        (None, Some(val)) => Ok(val),
+        (None, None) => Err("missing frame count".to_string()),
+    }
+}
+
+
+#[test]
+fn test_parse_script() {
+    use gameboy::model::Model;
+    use gameboy::rom::Rom;
+
+    let script = Script::parse("# title screen\nwait 120\npress A+B\n\nhold right 30 # walk\n")
+        .unwrap();
+    let actions: Vec<Action> = script.actions.into_iter().map(|x| x.1).collect();
+    assert_eq!(
+        vec![
+            Action::Wait(120),
+            Action::Press(Button::A.mask() | Button::B.mask(), 1),
+            Action::Hold(Button::Right.mask(), 30),
+        ],
+        actions
+    );
+    assert!(Script::parse("press C").is_err());
+    assert!(Script::parse("player 5").is_err());
+
+    let mut script = Script::parse("hold A\nplayer 2\npress B 2\nplayer 1\nwait 1").unwrap();
+    let gameboy = Gameboy::new(Rom::from_bytes(vec![0; 0x8000], "script").unwrap(), Model::Dmg);
+    let mut frames = Vec::new();
+    while let Some(buttons) = script.frame(&gameboy) {
+        frames.push((buttons, script.player_buttons(1)));
+    }
+    let (a, b) = (Button::A.mask(), Button::B.mask());
+    assert_eq!(vec![(a, b), (a, b), (a, 0)], frames);
+    assert!(Script::parse("wait").is_err());
+}
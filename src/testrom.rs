@@ -0,0 +1,275 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use gameboy::{Gameboy, CLOCK_SPEED, CYCLES_PER_FRAME};
+use gameboy::model::Model;
+use gameboy::rom::Rom;
+use gameboy::serial::Connection;
+
+
+// `LD B,B`, which Mooneye's tests execute once they have finished.
+const MOONEYE_BREAKPOINT: u8 = 0x40;
+// Blargg's tests mark cartridge RAM with this after the status byte.
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Passed,
+    Failed(String),
+    TimedOut,
+}
+
+struct TestResult {
+    name: String,
+    outcome: Outcome,
+    seconds: f64,
+    output: String,
+}
+
+impl TestResult {
+    fn passed(&self) -> bool {
+        self.outcome == Outcome::Passed
+    }
+}
+
+// Run a test ROM, or every ROM in a directory, print a summary and
+// optionally write JUnit XML. Returns whether every test passed.
+pub fn run(path: &Path, timeout: u64, junit: Option<&Path>) -> io::Result<bool> {
+    let mut roms = Vec::new();
+    collect_roms(path, &mut roms)?;
+    if roms.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no ROMs found"));
+    }
+    roms.sort();
+
+    let frame_limit = timeout * CLOCK_SPEED as u64 / CYCLES_PER_FRAME as u64;
+    let mut results = Vec::new();
+    for rom in &roms {
+        let name = match rom.strip_prefix(path) {
+            Ok(rel) if rel != Path::new("") => rel.display().to_string(),
+            _ => rom.display().to_string(),
+        };
+        let result = run_rom(rom, name, frame_limit);
+        match result.outcome {
+            Outcome::Passed => println!("PASS    {}", result.name),
+            Outcome::Failed(ref reason) => println!("FAIL    {} ({})", result.name, reason),
+            Outcome::TimedOut => println!("TIMEOUT {}", result.name),
+        }
+        results.push(result);
+    }
+
+    let passed = results.iter().filter(|r| r.passed()).count();
+    println!("\n{} passed, {} failed", passed, results.len() - passed);
+    if let Some(junit) = junit {
+        write_junit(junit, &results)?;
+    }
+    Ok(passed == results.len())
+}
+
+fn collect_roms(path: &Path, roms: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        roms.push(path.to_path_buf());
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let is_rom = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gb") | Some("gbc") => true,
+            _ => false,
+        };
+        if path.is_dir() {
+            collect_roms(&path, roms)?;
+        } else if is_rom {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn run_rom(path: &Path, name: String, frame_limit: u64) -> TestResult {
+    let started = Instant::now();
+    let (outcome, output) = match Rom::new(&path.to_string_lossy()) {
+        Ok(rom) => {
+            let model = Model::for_rom(&rom);
+            let mut gameboy = Gameboy::new(rom, model);
+            gameboy.skip_boot_rom();
+            gameboy.mmu.serial.connect(Connection::Buffer(Vec::new()));
+            // A ROM that crashes the emulator fails without stopping the
+            // rest of the run.
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                run_until_done(&mut gameboy, frame_limit)
+            })).unwrap_or_else(|err| {
+                let message = match err.downcast_ref::<&str>() {
+                    Some(message) => message.to_string(),
+                    None => err.downcast_ref::<String>().cloned().unwrap_or_default(),
+                };
+                Outcome::Failed(format!("emulator panicked: {}", message))
+            });
+            let output = String::from_utf8_lossy(gameboy.mmu.serial.output()).into_owned();
+            (outcome, output)
+        }
+        Err(err) => (Outcome::Failed(format!("could not load ROM: {}", err)), String::new()),
+    };
+    let elapsed = started.elapsed();
+    TestResult {
+        name: name,
+        outcome: outcome,
+        seconds: elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9,
+        output: output,
+    }
+}
+
+fn run_until_done(gameboy: &mut Gameboy, frame_limit: u64) -> Outcome {
+    while gameboy.frames() < frame_limit {
+        let frame = gameboy.frames();
+        while gameboy.frames() == frame {
+            let pc = gameboy.cpu.regs.pc;
+            if gameboy.mmu.read(pc) == MOONEYE_BREAKPOINT {
+                if let Some(outcome) = mooneye_result(gameboy) {
+                    return outcome;
+                }
+            }
+            gameboy.step();
+        }
+        let serial = String::from_utf8_lossy(gameboy.mmu.serial.output()).into_owned();
+        if let Some(outcome) = blargg_serial_result(&serial) {
+            return outcome;
+        }
+        if let Some(outcome) = blargg_memory_result(gameboy.mmu.sram()) {
+            return outcome;
+        }
+    }
+    Outcome::TimedOut
+}
+
+// Mooneye's tests load the Fibonacci numbers into the registers to
+// report success, or 0x42 into every one of them on failure.
+fn mooneye_result(gameboy: &Gameboy) -> Option<Outcome> {
+    let regs = &gameboy.cpu.regs;
+    let values = [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l];
+    if values == [3, 5, 8, 13, 21, 34] {
+        Some(Outcome::Passed)
+    } else if values == [0x42; 6] {
+        Some(Outcome::Failed("registers hold the failure signature".to_string()))
+    } else {
+        None
+    }
+}
+
+fn blargg_serial_result(serial: &str) -> Option<Outcome> {
+    if serial.contains("Passed") {
+        Some(Outcome::Passed)
+    } else if serial.contains("Failed") {
+        Some(Outcome::Failed(last_line(serial)))
+    } else {
+        None
+    }
+}
+
+// Newer Blargg tests also report through cartridge RAM: a status byte
+// at 0xA000 that stays at 0x80 while running, the signature, then the
+// text of the result from 0xA004.
+fn blargg_memory_result(sram: &[u8]) -> Option<Outcome> {
+    if sram.len() < 4 || sram[1..4] != BLARGG_SIGNATURE || sram[0] == BLARGG_RUNNING {
+        return None;
+    }
+    let text: Vec<u8> = sram[4..].iter().cloned().take_while(|&b| b != 0).collect();
+    let text = String::from_utf8_lossy(&text).into_owned();
+    match sram[0] {
+        0x00 => Some(Outcome::Passed),
+        code => Some(Outcome::Failed(format!("status {}: {}", code, last_line(&text)))),
+    }
+}
+
+fn last_line(text: &str) -> String {
+    let line = text.lines().rev().find(|line| !line.trim().is_empty());
+    line.unwrap_or("").trim().to_string()
+}
+
+fn write_junit(path: &Path, results: &[TestResult]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    let failures = results.iter().filter(|r| !r.passed()).count();
+    let total: f64 = results.iter().map(|r| r.seconds).sum();
+    writeln!(file, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        file,
+        r#"<testsuite name="gameroy" tests="{}" failures="{}" time="{:.3}">"#,
+        results.len(),
+        failures,
+        total
+    )?;
+    for result in results {
+        write!(
+            file,
+            r#"  <testcase name="{}" time="{:.3}">"#,
+            escape_xml(&result.name),
+            result.seconds
+        )?;
+        match result.outcome {
+            Outcome::Passed => {}
+            Outcome::Failed(ref reason) => {
+                write!(file, r#"<failure message="{}"/>"#, escape_xml(reason))?
+            }
+            Outcome::TimedOut => write!(file, r#"<failure message="timed out"/>"#)?,
+        }
+        if !result.output.is_empty() {
+            write!(file, "<system-out>{}</system-out>", escape_xml(&result.output))?;
+        }
+        writeln!(file, "</testcase>")?;
+    }
+    writeln!(file, "</testsuite>")
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            // XML 1.0 can't carry most control characters at all.
+            c if c < ' ' && c != '\n' && c != '\t' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+
+#[test]
+fn test_blargg_memory_result() {
+    let mut sram = vec![0x80, 0xDE, 0xB0, 0x61];
+    sram.extend_from_slice(b"cpu_instrs\n\nFailed #3\n\0junk");
+    assert_eq!(None, blargg_memory_result(&sram));
+    sram[0] = 0x03;
+    assert_eq!(
+        Some(Outcome::Failed("status 3: Failed #3".to_string())),
+        blargg_memory_result(&sram)
+    );
+    sram[0] = 0x00;
+    assert_eq!(Some(Outcome::Passed), blargg_memory_result(&sram));
+}
+
+
+#[test]
+fn test_emulator_panic() {
+    use std::env;
+
+    // LD A,(FF7F) reads an unmapped address.
+    let mut data = vec![0; 0x8000];
+    data[0x100..0x103].copy_from_slice(&[0xFA, 0x7F, 0xFF]);
+    let path = env::temp_dir().join("bitromney_test_panic.gb");
+    File::create(&path).unwrap().write_all(&data).unwrap();
+    let result = run_rom(&path, "panic".to_string(), 10);
+    fs::remove_file(&path).unwrap();
+    let message = "emulator panicked: FF7F is an unused address.";
+    assert_eq!(Outcome::Failed(message.to_string()), result.outcome);
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use gameboy::{CLOCK_SPEED, CYCLES_PER_FRAME};
+
+
+// If emulation falls this far behind the wall clock (a slow host, or
+// time spent sitting at the debugger prompt) stop trying to catch up.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Scaled(f64),
+    Uncapped,
+}
+
+impl Speed {
+    pub fn normal() -> Speed {
+        Speed::Scaled(1.0)
+    }
+    pub fn from_str(string: &str) -> Option<Speed> {
+        match string {
+            "max" | "uncapped" | "unlimited" => Some(Speed::Uncapped),
+            _ => {
+                match string.trim_end_matches('x').parse::<f64>() {
+                    Ok(val) if val > 0.0 => Some(Speed::Scaled(val)),
+                    _ => None,
+                }
+            }
+        }
+    }
+    // For ordering speeds, with uncapped faster than any multiplier.
+    fn multiplier(&self) -> f64 {
+        match *self {
+            Speed::Scaled(val) => val,
+            Speed::Uncapped => f64::INFINITY,
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Speed::Scaled(val) => write!(f, "{}x", val),
+            Speed::Uncapped => write!(f, "uncapped"),
+        }
+    }
+}
+
+// Speeds the fast-forward and slow-motion hotkeys step through.
+const SPEEDS: [Speed; 6] = [
+    Speed::Scaled(0.25),
+    Speed::Scaled(0.5),
+    Speed::Scaled(1.0),
+    Speed::Scaled(2.0),
+    Speed::Scaled(4.0),
+    Speed::Uncapped,
+];
+
+// Keeps emulated time in step with the wall clock by sleeping at the
+// end of each frame until that frame is due.
+pub struct Throttle {
+    speed: Speed,
+    reference: Instant,
+    frames: u32,
+}
+
+impl Throttle {
+    pub fn new() -> Throttle {
+        Throttle {
+            speed: Speed::normal(),
+            reference: Instant::now(),
+            frames: 0,
+        }
+    }
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.reset();
+    }
+    // Step to the nearest preset above or below the current speed, which
+    // need not be a preset itself.
+    pub fn faster(&mut self) {
+        let current = self.speed.multiplier();
+        if let Some(&speed) = SPEEDS.iter().find(|x| x.multiplier() > current) {
+            self.set_speed(speed);
+        }
+    }
+    pub fn slower(&mut self) {
+        let current = self.speed.multiplier();
+        if let Some(&speed) = SPEEDS.iter().rev().find(|x| x.multiplier() < current) {
+            self.set_speed(speed);
+        }
+    }
+    // Forget the timing history, e.g. after emulation has been paused.
+    pub fn reset(&mut self) {
+        self.reference = Instant::now();
+        self.frames = 0;
+    }
+    pub fn frame(&mut self) {
+        let scale = match self.speed {
+            Speed::Scaled(val) => val,
+            Speed::Uncapped => return,
+        };
+        self.frames += 1;
+        let seconds = self.frames as f64 * CYCLES_PER_FRAME as f64 / CLOCK_SPEED as f64 / scale;
+        let due = Duration::from_secs_f64(seconds);
+        let elapsed = self.reference.elapsed();
+        if due > elapsed {
+            sleep(due - elapsed);
+        } else if elapsed - due > MAX_LAG {
+            self.reset();
+        }
+    }
+}
+
+
+#[test]
+fn test_speed_steps() {
+    let mut throttle = Throttle::new();
+    throttle.set_speed(Speed::Scaled(1.5));
+    throttle.faster();
+    assert_eq!(Speed::Scaled(2.0), throttle.speed());
+    throttle.set_speed(Speed::Scaled(1.5));
+    throttle.slower();
+    assert_eq!(Speed::Scaled(1.0), throttle.speed());
+    throttle.slower();
+    assert_eq!(Speed::Scaled(0.5), throttle.speed());
+    throttle.set_speed(Speed::Scaled(8.0));
+    throttle.faster();
+    assert_eq!(Speed::Uncapped, throttle.speed());
+    throttle.faster();
+    assert_eq!(Speed::Uncapped, throttle.speed());
+    throttle.set_speed(Speed::Scaled(0.1));
+    throttle.slower();
+    assert_eq!(Speed::Scaled(0.1), throttle.speed());
+}